@@ -48,6 +48,12 @@ quick_error!{
             description(tr!("No such bundle"))
             display("{}", tr_format!("Bundle db error: no such bundle: {:?}", bundle))
         }
+        Dictionary(err: DictionaryError) {
+            from()
+            cause(err)
+            description(tr!("Failed to read/write dictionary"))
+            display("{}", tr_format!("Bundle db error: failed to read/write dictionary\n\tcaused by: {}", err))
+        }
         Remove(err: io::Error, bundle: BundleId) {
             cause(err)
             description(tr!("Failed to remove bundle"))
@@ -122,6 +128,7 @@ pub struct BundleDb {
     crypto: Arc<Mutex<Crypto>>,
     local_bundles: HashMap<BundleId, StoredBundle>,
     remote_bundles: HashMap<BundleId, StoredBundle>,
+    dictionaries: HashMap<Hash, Arc<Vec<u8>>>,
     bundle_cache: LruCache<BundleId, (BundleReader, Vec<u8>)>
 }
 
@@ -134,6 +141,7 @@ impl BundleDb {
             uploader: None,
             local_bundles: HashMap::new(),
             remote_bundles: HashMap::new(),
+            dictionaries: HashMap::new(),
             bundle_cache: LruCache::new(5, 10)
         }
     }
@@ -189,6 +197,47 @@ impl BundleDb {
         Ok((new, gone))
     }
 
+    /// Copies new dictionaries to the local cache and loads all readable dictionaries
+    ///
+    /// The local copies are needed to read the cached meta bundles when the remote storage is
+    /// offline.
+    fn load_dictionaries(&mut self, online: bool) -> Result<(), BundleDbError> {
+        let local_path = self.layout.local_dictionaries_path();
+        try!(fs::create_dir_all(&local_path).context(&local_path as &Path));
+        let remote_path = self.layout.remote_dictionaries_path();
+        if online && remote_path.exists() {
+            for entry in try!(fs::read_dir(&remote_path).context(&remote_path as &Path)) {
+                let entry = try!(entry.context(&remote_path as &Path));
+                if !Dictionary::is_dictionary_file(&entry.path()) {
+                    continue;
+                }
+                let dst_path = local_path.join(entry.file_name());
+                if !dst_path.exists() {
+                    try!(fs::copy(entry.path(), &dst_path).context(&dst_path as &Path));
+                }
+            }
+        }
+        for entry in try!(fs::read_dir(&local_path).context(&local_path as &Path)) {
+            let path = try!(entry.context(&local_path as &Path)).path();
+            if !Dictionary::is_dictionary_file(&path) {
+                continue;
+            }
+            match Dictionary::load(&path, &self.crypto) {
+                Ok(dictionary) => {
+                    // The file name is the id that bundles refer to
+                    if path != self.layout.local_dictionary_path(&dictionary.id) {
+                        tr_warn!("Dictionary {:?} does not match its name, ignoring it", path);
+                        continue;
+                    }
+                    self.dictionaries.insert(dictionary.id, Arc::new(dictionary.data));
+                }
+                // Without the secret key, encrypted dictionaries can not be used
+                Err(err) => tr_debug!("Failed to load dictionary {:?}: {}", path, err),
+            }
+        }
+        Ok(())
+    }
+
     pub fn flush(&mut self) -> Result<(), BundleDbError> {
         self.finish_uploads().and_then(|()| self.save_cache())
     }
@@ -245,6 +294,7 @@ impl BundleDb {
     ) -> Result<(Self, Vec<BundleInfo>, Vec<BundleInfo>), BundleDbError> {
         let mut self_ = Self::new(layout, crypto);
         let (new, gone) = try!(self_.load_bundle_list(online));
+        try!(self_.load_dictionaries(online));
         try!(self_.update_cache());
         let new = new.into_iter().map(|s| s.info).collect();
         let gone = gone.into_iter().map(|s| s.info).collect();
@@ -264,6 +314,10 @@ impl BundleDb {
             &layout.temp_bundles_path() as
                 &Path
         ));
+        try!(fs::create_dir_all(layout.remote_dictionaries_path()).context(
+            &layout.remote_dictionaries_path() as
+                &Path
+        ));
         try!(StoredBundle::save_list_to(
             &[],
             layout.local_bundle_cache_path()
@@ -282,13 +336,15 @@ impl BundleDb {
         hash_method: HashMethod,
         compression: Option<Compression>,
         encryption: Option<Encryption>,
+        padding: Option<usize>,
     ) -> Result<BundleWriter, BundleDbError> {
         Ok(try!(BundleWriter::new(
             mode,
             hash_method,
             compression,
             encryption,
-            self.crypto.clone()
+            self.crypto.clone(),
+            padding
         )))
     }
 
@@ -303,17 +359,81 @@ impl BundleDb {
         }
     }
 
+    /// Stores a new dictionary in the remote storage and the local cache and returns its id
+    pub fn add_dictionary(
+        &mut self,
+        data: Vec<u8>,
+        encryption: Option<&Encryption>,
+    ) -> Result<Hash, BundleDbError> {
+        let dictionary = Dictionary::new(data);
+        let remote_path = self.layout.remote_dictionaries_path();
+        try!(fs::create_dir_all(&remote_path).context(&remote_path as &Path));
+        let src_path = self.layout.remote_dictionary_path(&dictionary.id);
+        try!(dictionary.save(&src_path, encryption, &self.crypto));
+        let dst_path = self.layout.local_dictionary_path(&dictionary.id);
+        try!(dictionary.save(&dst_path, encryption, &self.crypto));
+        self.dictionaries.insert(dictionary.id, Arc::new(dictionary.data));
+        Ok(dictionary.id)
+    }
+
+    /// Encrypts all dictionaries that use another encryption again with `encryption`
+    ///
+    /// Returns the number of dictionaries that still use another encryption because they could
+    /// not be read.
+    pub fn reencrypt_dictionaries(
+        &mut self,
+        encryption: Option<&Encryption>,
+    ) -> Result<usize, BundleDbError> {
+        let remote_path = self.layout.remote_dictionaries_path();
+        if !remote_path.exists() {
+            return Ok(0);
+        }
+        let mut failed = 0;
+        for entry in try!(fs::read_dir(&remote_path).context(&remote_path as &Path)) {
+            let path = try!(entry.context(&remote_path as &Path)).path();
+            if !Dictionary::is_dictionary_file(&path) {
+                continue;
+            }
+            if try!(Dictionary::get_encryption_from(&path)).as_ref() == encryption {
+                continue;
+            }
+            let dictionary = match Dictionary::load(&path, &self.crypto) {
+                Ok(dictionary) => dictionary,
+                Err(err) => {
+                    tr_warn!("Failed to read dictionary {:?}: {}", path, err);
+                    failed += 1;
+                    continue;
+                }
+            };
+            try!(dictionary.save(&path, encryption, &self.crypto));
+            let dst_path = self.layout.local_dictionary_path(&dictionary.id);
+            try!(dictionary.save(&dst_path, encryption, &self.crypto));
+        }
+        Ok(failed)
+    }
+
     #[inline]
+    pub fn get_dictionary(&self, id: &Hash) -> Option<Arc<Vec<u8>>> {
+        self.dictionaries.get(id).cloned()
+    }
+
     fn get_bundle(&self, stored: &StoredBundle) -> Result<BundleReader, BundleDbError> {
         let base_path = self.layout.base_path();
-        Ok(try!(BundleReader::load(
+        let mut bundle = try!(BundleReader::load(
             base_path.join(&stored.path),
             self.crypto.clone()
-        )))
+        ));
+        if let Some(dictionary) = bundle.info.dictionary.and_then(|id| self.get_dictionary(&id)) {
+            bundle.set_dictionary(dictionary);
+        }
+        Ok(bundle)
     }
 
     pub fn get_chunk(&mut self, bundle_id: &BundleId, id: usize) -> Result<Vec<u8>, BundleDbError> {
         if let Some(&mut (ref mut bundle, ref data)) = self.bundle_cache.get_mut(bundle_id) {
+            if bundle.has_chunk_groups() {
+                return Ok(try!(bundle.load_chunk(id)));
+            }
             let (pos, len) = try!(bundle.get_chunk_position(id));
             let mut chunk = Vec::with_capacity(len);
             chunk.extend_from_slice(&data[pos..pos + len]);
@@ -322,6 +442,12 @@ impl BundleDb {
         let mut bundle = try!(self.get_stored_bundle(bundle_id).and_then(
             |s| self.get_bundle(s)
         ));
+        if bundle.has_chunk_groups() {
+            // Only the group of the chunk is decoded, the reader keeps the last group
+            let chunk = try!(bundle.load_chunk(id));
+            self.bundle_cache.put(bundle_id.clone(), (bundle, vec![]));
+            return Ok(chunk);
+        }
         let (pos, len) = try!(bundle.get_chunk_position(id));
         let mut chunk = Vec::with_capacity(len);
         let data = try!(bundle.load_contents());
@@ -484,7 +610,7 @@ impl BundleDb {
                 return self.evacuate_broken_bundle(stored);
             }
         };
-        let data = match bundle.load_contents() {
+        let data = match bundle.load_damaged_contents() {
             Ok(data) => data,
             Err(err) => {
                 tr_warn!(
@@ -498,19 +624,41 @@ impl BundleDb {
         tr_warn!("Problem detected: bundle data was truncated: {}", id);
         tr_info!("Copying readable data into new bundle");
         let info = stored.info.clone();
+        let chunk_groups = bundle.has_chunk_groups();
         let mut new_bundle = try!(self.create_bundle(
             info.mode,
             info.hash_method,
             info.compression,
-            info.encryption
+            info.encryption,
+            info.padding
         ));
+        if info.incompressible {
+            new_bundle.set_incompressible();
+        }
+        if chunk_groups {
+            new_bundle.set_chunk_groups();
+        }
+        if let Some(id) = info.dictionary {
+            if let Some(dictionary) = self.get_dictionary(&id) {
+                try!(new_bundle.set_dictionary(id, &dictionary));
+            }
+        }
+        let hash_key = if info.hash_method.is_keyed() {
+            try!(self.crypto.lock().unwrap().get_hash_key().map_err(BundleWriterError::Encryption))
+        } else {
+            vec![]
+        };
         let mut pos = 0;
         for (hash, mut len) in chunks.into_inner() {
             if pos >= data.len() {
                 break;
             }
             len = min(len, (data.len() - pos) as u32);
-            try!(new_bundle.add(&data[pos..pos + len as usize], hash));
+            let chunk = &data[pos..pos + len as usize];
+            // The contents are not covered by the digest anymore
+            if info.hash_method.matches(&hash_key, chunk, &hash) {
+                try!(new_bundle.add(chunk, hash));
+            }
             pos += len as usize;
         }
         let bundle = try!(self.add_bundle(new_bundle));
@@ -527,6 +675,7 @@ impl BundleDb {
         let bundles = self.list_bundles();
         let bundles_meta: Vec<_> = bundles.iter().filter(|b| b.mode == BundleMode::Meta).collect();
         let bundles_data: Vec<_> = bundles.iter().filter(|b| b.mode == BundleMode::Data).collect();
+        let bundles_incompressible: Vec<_> = bundles.iter().filter(|b| b.incompressible).collect();
         let mut hash_methods = HashMap::new();
         let mut compressions = HashMap::new();
         let mut encryptions = HashMap::new();
@@ -545,7 +694,10 @@ impl BundleDb {
             chunk_count_meta: ValueStats::from_iter(|| bundles_meta.iter().map(|b| b.chunk_count as f32)),
             raw_size_data: ValueStats::from_iter(|| bundles_data.iter().map(|b| b.raw_size as f32)),
             encoded_size_data: ValueStats::from_iter(|| bundles_data.iter().map(|b| b.encoded_size as f32)),
-            chunk_count_data: ValueStats::from_iter(|| bundles_data.iter().map(|b| b.chunk_count as f32))
+            chunk_count_data: ValueStats::from_iter(|| bundles_data.iter().map(|b| b.chunk_count as f32)),
+            raw_size_incompressible: ValueStats::from_iter(|| bundles_incompressible.iter().map(|b| b.raw_size as f32)),
+            encoded_size_incompressible: ValueStats::from_iter(|| bundles_incompressible.iter().map(|b| b.encoded_size as f32)),
+            chunk_count_incompressible: ValueStats::from_iter(|| bundles_incompressible.iter().map(|b| b.chunk_count as f32))
         }
     }
 }
@@ -0,0 +1,176 @@
+use prelude::*;
+
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write, Read};
+use std::sync::{Arc, Mutex};
+
+
+pub static DICTIONARY_FILE_STRING: [u8; 7] = *b"zvault\x05";
+pub static DICTIONARY_FILE_VERSION: u8 = 1;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum DictionaryError {
+        Read(err: io::Error, path: PathBuf) {
+            cause(err)
+            context(path: &'a Path, err: io::Error) -> (err, path.to_path_buf())
+            description(tr!("Failed to read dictionary"))
+            display("{}", tr_format!("Dictionary error: failed to read dictionary {:?}\n\tcaused by: {}", path, err))
+        }
+        Write(err: io::Error, path: PathBuf) {
+            cause(err)
+            description(tr!("Failed to write dictionary"))
+            display("{}", tr_format!("Dictionary error: failed to write dictionary {:?}\n\tcaused by: {}", path, err))
+        }
+        WrongHeader(path: PathBuf) {
+            description(tr!("Wrong header"))
+            display("{}", tr_format!("Dictionary error: wrong header on dictionary {:?}", path))
+        }
+        UnsupportedVersion(path: PathBuf, version: u8) {
+            description(tr!("Wrong version"))
+            display("{}", tr_format!("Dictionary error: unsupported version on dictionary {:?}: {}", path, version))
+        }
+        Decode(err: msgpack::DecodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to decode dictionary header"))
+            display("{}", tr_format!("Dictionary error: failed to decode dictionary header\n\tcaused by: {}", err))
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to encode dictionary header"))
+            display("{}", tr_format!("Dictionary error: failed to encode dictionary header\n\tcaused by: {}", err))
+        }
+        Encryption(err: EncryptionError) {
+            from()
+            cause(err)
+            description(tr!("Failed to encrypt or decrypt dictionary"))
+            display("{}", tr_format!("Dictionary error: failed to encrypt or decrypt dictionary\n\tcaused by: {}", err))
+        }
+    }
+}
+
+
+#[derive(Default)]
+struct DictionaryHeader {
+    encryption: Option<Encryption>
+}
+serde_impl!(DictionaryHeader(u8?) {
+    encryption: Option<Encryption> => 0
+});
+
+
+/// A compression dictionary, identified by the hash of its contents
+///
+/// Dictionaries are never modified, a retrained dictionary gets a new id so that bundles
+/// compressed with older versions stay readable.
+pub struct Dictionary {
+    pub id: Hash,
+    pub data: Vec<u8>
+}
+
+impl Dictionary {
+    #[inline]
+    pub fn new(data: Vec<u8>) -> Self {
+        Dictionary {
+            id: HashMethod::Blake2.hash(&data),
+            data
+        }
+    }
+
+    fn read_header(path: &Path) -> Result<(BufReader<File>, DictionaryHeader), DictionaryError> {
+        let mut file = BufReader::new(try!(File::open(path).context(path)));
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header).context(path));
+        if header[..DICTIONARY_FILE_STRING.len()] != DICTIONARY_FILE_STRING {
+            return Err(DictionaryError::WrongHeader(path.to_path_buf()));
+        }
+        let version = header[DICTIONARY_FILE_STRING.len()];
+        if version != DICTIONARY_FILE_VERSION {
+            return Err(DictionaryError::UnsupportedVersion(path.to_path_buf(), version));
+        }
+        let header: DictionaryHeader = try!(msgpack::decode_from_stream(&mut file));
+        Ok((file, header))
+    }
+
+    pub fn get_encryption_from<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<Option<Encryption>, DictionaryError> {
+        let (_file, header) = try!(Self::read_header(path.as_ref()));
+        Ok(header.encryption)
+    }
+
+    /// Whether the path is a complete dictionary file and not a leftover temporary file
+    #[inline]
+    pub fn is_dictionary_file(path: &Path) -> bool {
+        path.extension().map_or(false, |ext| ext == "dict")
+    }
+
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        crypto: &Arc<Mutex<Crypto>>,
+    ) -> Result<Self, DictionaryError> {
+        let path = path.as_ref();
+        let (mut file, header) = try!(Self::read_header(path));
+        let mut data = Vec::new();
+        try!(file.read_to_end(&mut data).context(path));
+        if let Some(ref encryption) = header.encryption {
+            data = try!(crypto.lock().unwrap().decrypt(encryption, &data));
+        }
+        Ok(Dictionary::new(data))
+    }
+
+    /// Saves the dictionary, encrypting it if `encryption` is set
+    ///
+    /// Dictionaries are trained on metadata and reveal parts of it, so they should be encrypted
+    /// like the bundles that use them. The file is replaced atomically, as a damaged dictionary
+    /// makes all bundles compressed with it unreadable.
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        path: P,
+        encryption: Option<&Encryption>,
+        crypto: &Arc<Mutex<Crypto>>,
+    ) -> Result<(), DictionaryError> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        try!(self.write_to(&tmp_path, encryption, crypto));
+        try!(fs::rename(&tmp_path, path).map_err(|err| {
+            DictionaryError::Write(err, path.to_path_buf())
+        }));
+        Ok(())
+    }
+
+    fn write_to(
+        &self,
+        path: &Path,
+        encryption: Option<&Encryption>,
+        crypto: &Arc<Mutex<Crypto>>,
+    ) -> Result<(), DictionaryError> {
+        let mut file = BufWriter::new(try!(File::create(path).map_err(|err| {
+            DictionaryError::Write(err, path.to_path_buf())
+        })));
+        try!(file.write_all(&DICTIONARY_FILE_STRING).map_err(|err| {
+            DictionaryError::Write(err, path.to_path_buf())
+        }));
+        try!(file.write_all(&[DICTIONARY_FILE_VERSION]).map_err(|err| {
+            DictionaryError::Write(err, path.to_path_buf())
+        }));
+        let header = DictionaryHeader { encryption: encryption.cloned() };
+        try!(msgpack::encode_to_stream(&header, &mut file));
+        let data = if let Some(encryption) = encryption {
+            try!(crypto.lock().unwrap().encrypt(encryption, &self.data))
+        } else {
+            self.data.clone()
+        };
+        try!(file.write_all(&data).map_err(|err| {
+            DictionaryError::Write(err, path.to_path_buf())
+        }));
+        try!(file.flush().map_err(|err| {
+            DictionaryError::Write(err, path.to_path_buf())
+        }));
+        Ok(())
+    }
+}
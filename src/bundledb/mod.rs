@@ -3,12 +3,14 @@ mod reader;
 mod db;
 mod cache;
 mod uploader;
+mod dictionary;
 
 pub use self::cache::{StoredBundle, BundleCacheError};
-pub use self::writer::{BundleWriter, BundleWriterError};
+pub use self::writer::{BundleWriter, BundleWriterError, padded_size, PADDING_MIN_FRACTION};
 pub use self::reader::{BundleReader, BundleReaderError};
 pub use self::db::*;
 pub use self::uploader::BundleUploader;
+pub use self::dictionary::{Dictionary, DictionaryError};
 
 use prelude::*;
 
@@ -16,10 +18,13 @@ use std::fmt;
 use std::collections::HashMap;
 use serde;
 use rand;
+use serde_bytes::ByteBuf;
 
 
 pub static HEADER_STRING: [u8; 7] = *b"zvault\x01";
 pub static HEADER_VERSION: u8 = 1;
+/// Bundles of this version compress and encrypt groups of chunks independently
+pub static HEADER_VERSION_GROUPS: u8 = 2;
 
 
 #[derive(Hash, PartialEq, Eq, Clone, Default, Ord, PartialOrd)]
@@ -81,14 +86,26 @@ serde_impl!(BundleMode(u8) {
 });
 
 
+/// Digest of the encoded data of a bundle
+///
+/// The digests are stored in the signed bundle info, so that the signature also covers the chunk
+/// list, the group table and the contents.
+#[inline]
+pub fn bundle_digest(data: &[u8]) -> Hash {
+    HashMethod::Blake2.hash(data)
+}
+
+
 #[derive(Default, Debug, Clone)]
 pub struct BundleHeader {
     pub encryption: Option<Encryption>,
-    pub info_size: usize
+    pub info_size: usize,
+    pub signature: Option<ByteBuf>
 }
-serde_impl!(BundleHeader(u8) {
+serde_impl!(BundleHeader(u8?) {
     encryption: Option<Encryption> => 0,
-    info_size: usize => 1
+    info_size: usize => 1,
+    signature: Option<ByteBuf> => 2
 });
 
 
@@ -103,7 +120,14 @@ pub struct BundleInfo {
     pub encoded_size: usize,
     pub chunk_count: usize,
     pub chunk_list_size: usize,
-    pub timestamp: i64
+    pub timestamp: i64,
+    pub padding: Option<usize>,
+    pub incompressible: bool,
+    pub dictionary: Option<Hash>,
+    pub group_table_size: usize,
+    pub index_digest: Option<Hash>,
+    pub content_digest: Option<Hash>,
+    pub chunk_groups: bool
 }
 serde_impl!(BundleInfo(u64?) {
     id: BundleId => 0,
@@ -115,7 +139,14 @@ serde_impl!(BundleInfo(u64?) {
     encoded_size: usize => 7,
     chunk_count: usize => 8,
     chunk_list_size: usize => 9,
-    timestamp: i64 => 10
+    timestamp: i64 => 10,
+    padding: Option<usize> => 11,
+    incompressible: bool => 12,
+    dictionary: Option<Hash> => 13,
+    group_table_size: usize => 14,
+    index_digest: Option<Hash> => 15,
+    content_digest: Option<Hash> => 16,
+    chunk_groups: bool => 17
 });
 
 impl Default for BundleInfo {
@@ -130,12 +161,35 @@ impl Default for BundleInfo {
             chunk_count: 0,
             mode: BundleMode::Data,
             chunk_list_size: 0,
-            timestamp: 0
+            timestamp: 0,
+            padding: None,
+            incompressible: false,
+            dictionary: None,
+            group_table_size: 0,
+            index_digest: None,
+            content_digest: None,
+            chunk_groups: false
         }
     }
 }
 
 
+/// A group of consecutive chunks that is compressed and encrypted independently
+#[derive(Default, Debug, Clone)]
+pub struct ChunkGroup {
+    pub chunk_count: usize,
+    pub raw_size: usize,
+    pub encoded_size: usize,
+    pub digest: Option<Hash>
+}
+serde_impl!(ChunkGroup(u64?) {
+    chunk_count: usize => 0,
+    raw_size: usize => 1,
+    encoded_size: usize => 2,
+    digest: Option<Hash> => 3
+});
+
+
 #[derive(Debug)]
 pub struct BundleStatistics {
     pub raw_size: ValueStats,
@@ -147,6 +201,9 @@ pub struct BundleStatistics {
     pub raw_size_data: ValueStats,
     pub encoded_size_data: ValueStats,
     pub chunk_count_data: ValueStats,
+    pub raw_size_incompressible: ValueStats,
+    pub encoded_size_incompressible: ValueStats,
+    pub chunk_count_incompressible: ValueStats,
     pub hash_methods: HashMap<HashMethod, usize>,
     pub compressions: HashMap<Option<Compression>, usize>,
     pub encryptions: HashMap<Option<Encryption>, usize>
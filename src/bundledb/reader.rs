@@ -48,6 +48,15 @@ quick_error!{
             description(tr!("Decryption failed"))
             display("{}", tr_format!("Bundle reader error: decryption failed on bundle {:?}\n\tcaused by: {}", path, err))
         }
+        Signature(err: EncryptionError, path: PathBuf) {
+            cause(err)
+            description(tr!("Signature verification failed"))
+            display("{}", tr_format!("Bundle reader error: signature verification failed on bundle {:?}\n\tcaused by: {}", path, err))
+        }
+        MissingDictionary(bundle: BundleId, dictionary: Hash) {
+            description(tr!("Dictionary of bundle is missing"))
+            display("{}", tr_format!("Bundle reader error: bundle {:?} needs the missing dictionary {}", bundle, dictionary))
+        }
         Integrity(bundle: BundleId, reason: &'static str) {
             description(tr!("Bundle has an integrity error"))
             display("{}", tr_format!("Bundle reader error: bundle {:?} has an integrity error: {}", bundle, reason))
@@ -61,9 +70,12 @@ pub struct BundleReader {
     pub version: u8,
     pub path: PathBuf,
     crypto: Arc<Mutex<Crypto>>,
+    dictionary: Option<Arc<Vec<u8>>>,
     pub content_start: usize,
     pub chunks: Option<ChunkList>,
-    pub chunk_positions: Option<Vec<usize>>
+    pub chunk_positions: Option<Vec<usize>>,
+    groups: Option<Vec<ChunkGroup>>,
+    group_cache: Option<(usize, Vec<u8>)>
 }
 
 impl BundleReader {
@@ -80,11 +92,20 @@ impl BundleReader {
             version,
             path,
             crypto,
+            dictionary: None,
             content_start,
-            chunk_positions: None
+            chunk_positions: None,
+            groups: None,
+            group_cache: None
         }
     }
 
+    /// Sets the dictionary that is needed to decompress the contents
+    #[inline]
+    pub fn set_dictionary(&mut self, dictionary: Arc<Vec<u8>>) {
+        self.dictionary = Some(dictionary);
+    }
+
     #[inline]
     pub fn id(&self) -> BundleId {
         self.info.id.clone()
@@ -103,7 +124,7 @@ impl BundleReader {
             return Err(BundleReaderError::WrongHeader(path.to_path_buf()));
         }
         let version = header[HEADER_STRING.len()];
-        if version != HEADER_VERSION {
+        if version != HEADER_VERSION && version != HEADER_VERSION_GROUPS {
             return Err(BundleReaderError::UnsupportedVersion(
                 path.to_path_buf(),
                 version
@@ -113,6 +134,13 @@ impl BundleReader {
         let mut info_data = Vec::with_capacity(header.info_size);
         info_data.resize(header.info_size, 0);
         try!(file.read_exact(&mut info_data).context(path));
+        try!(
+            crypto
+                .lock()
+                .unwrap()
+                .verify(&info_data, header.signature.as_ref().map(|s| &s[..]))
+                .map_err(|err| BundleReaderError::Signature(err, path.to_path_buf()))
+        );
         if let Some(ref encryption) = header.encryption {
             info_data = try!(
                 crypto
@@ -124,9 +152,11 @@ impl BundleReader {
         }
         let mut info: BundleInfo = try!(msgpack::decode(&info_data).context(path));
         info.encryption = header.encryption;
+        // The flag is cached with the info, the version is the authoritative source
+        info.chunk_groups = version == HEADER_VERSION_GROUPS;
         debug!("Load bundle {}", info.id);
         let content_start = file.seek(SeekFrom::Current(0)).unwrap() as usize +
-            info.chunk_list_size;
+            info.chunk_list_size + info.group_table_size;
         Ok((info, version, content_start))
     }
 
@@ -157,16 +187,25 @@ impl BundleReader {
             self.info.mode
         );
         let mut file = BufReader::new(try!(File::open(&self.path).context(&self.path as &Path)));
-        let len = self.info.chunk_list_size;
+        let len = self.info.chunk_list_size + self.info.group_table_size;
         let start = self.content_start - len;
         try!(file.seek(SeekFrom::Start(start as u64)).context(
             &self.path as &Path
         ));
         let mut chunk_data = Vec::with_capacity(len);
-        chunk_data.resize(self.info.chunk_list_size, 0);
+        chunk_data.resize(len, 0);
         try!(file.read_exact(&mut chunk_data).context(
             &self.path as &Path
         ));
+        if let Some(ref digest) = self.info.index_digest {
+            if bundle_digest(&chunk_data) != *digest {
+                return Err(BundleReaderError::Integrity(
+                    self.id(),
+                    tr!("Chunk list does not match the digest in the header, modified data")
+                ));
+            }
+        }
+        let group_data = chunk_data.split_off(self.info.chunk_list_size);
         if let Some(ref encryption) = self.info.encryption {
             chunk_data = try!(
                 self.crypto
@@ -185,9 +224,28 @@ impl BundleReader {
         }
         self.chunks = Some(chunks);
         self.chunk_positions = Some(chunk_positions);
+        if self.has_chunk_groups() {
+            let mut group_data = group_data;
+            if let Some(ref encryption) = self.info.encryption {
+                group_data = try!(
+                    self.crypto
+                        .lock()
+                        .unwrap()
+                        .decrypt(encryption, &group_data)
+                        .context(&self.path as &Path)
+                );
+            }
+            self.groups = Some(try!(msgpack::decode(&group_data).context(&self.path as &Path)));
+        }
         Ok(())
     }
 
+    /// Whether the chunks are stored in groups that can be decoded independently
+    #[inline]
+    pub fn has_chunk_groups(&self) -> bool {
+        self.version == HEADER_VERSION_GROUPS
+    }
+
     #[inline]
     pub fn get_chunk_list(&mut self) -> Result<&ChunkList, BundleReaderError> {
         if self.chunks.is_none() {
@@ -204,11 +262,38 @@ impl BundleReader {
                 .context(&self.path as &Path)
         );
         let mut data = Vec::with_capacity(max(self.info.encoded_size, self.info.raw_size) + 1024);
-        try!(file.read_to_end(&mut data).context(&self.path as &Path));
+        // Padded bundles contain random data after the contents
+        try!(
+            file.take(self.info.encoded_size as u64)
+                .read_to_end(&mut data)
+                .context(&self.path as &Path)
+        );
         Ok(data)
     }
 
-    fn decode_contents(&self, mut data: Vec<u8>) -> Result<Vec<u8>, BundleReaderError> {
+    fn decode_contents(&self, data: Vec<u8>) -> Result<Vec<u8>, BundleReaderError> {
+        let groups = match self.groups {
+            Some(ref groups) => groups,
+            None => return self.decode_part(data, self.info.raw_size),
+        };
+        let mut contents = Vec::with_capacity(self.info.raw_size);
+        let mut pos = 0;
+        for group in groups {
+            if pos + group.encoded_size > data.len() {
+                return Err(BundleReaderError::Integrity(
+                    self.id(),
+                    tr!("Chunk groups exceed the encoded data, truncated bundle")
+                ));
+            }
+            let part = data[pos..pos + group.encoded_size].to_vec();
+            contents.extend_from_slice(&try!(self.decode_part(part, group.raw_size)));
+            pos += group.encoded_size;
+        }
+        Ok(contents)
+    }
+
+    /// Decrypts and decompresses either the whole contents or a single chunk group
+    fn decode_part(&self, mut data: Vec<u8>, raw_size: usize) -> Result<Vec<u8>, BundleReaderError> {
         if let Some(ref encryption) = self.info.encryption {
             data = try!(
                 self.crypto
@@ -219,8 +304,17 @@ impl BundleReader {
             );
         }
         if let Some(ref compression) = self.info.compression {
-            let mut stream = try!(compression.decompress_stream().context(&self.path as &Path));
-            let mut buffer = Vec::with_capacity(self.info.raw_size);
+            let stream = match self.info.dictionary {
+                Some(ref id) => {
+                    let dictionary = try!(self.dictionary.as_ref().ok_or_else(|| {
+                        BundleReaderError::MissingDictionary(self.id(), *id)
+                    }));
+                    compression.decompress_stream_with_dictionary(dictionary)
+                }
+                None => compression.decompress_stream(),
+            };
+            let mut stream = try!(stream.context(&self.path as &Path));
+            let mut buffer = Vec::with_capacity(raw_size);
             try!(stream.process(&data, &mut buffer).context(
                 &self.path as &Path
             ));
@@ -230,13 +324,114 @@ impl BundleReader {
         Ok(data)
     }
 
-    #[inline]
-    pub fn load_contents(&self) -> Result<Vec<u8>, BundleReaderError> {
+    fn verify_contents(&self, data: &[u8]) -> Result<(), BundleReaderError> {
+        if let Some(ref digest) = self.info.content_digest {
+            if bundle_digest(data) != *digest {
+                return Err(BundleReaderError::Integrity(
+                    self.id(),
+                    tr!("Encoded data does not match the digest in the header, modified data")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_contents(&mut self) -> Result<Vec<u8>, BundleReaderError> {
+        if self.has_chunk_groups() && self.groups.is_none() {
+            try!(self.load_chunklist());
+        }
+        let data = try!(self.load_encoded_contents());
+        try!(self.verify_contents(&data));
+        self.decode_contents(data)
+    }
+
+    /// Loads the contents without checking their digest
+    ///
+    /// This is only meant to salvage the readable data of damaged bundles, the chunks must be
+    /// checked against their hashes.
+    pub fn load_damaged_contents(&mut self) -> Result<Vec<u8>, BundleReaderError> {
+        if self.has_chunk_groups() && self.groups.is_none() {
+            try!(self.load_chunklist());
+        }
         self.load_encoded_contents().and_then(|data| {
             self.decode_contents(data)
         })
     }
 
+    /// Reads and decodes the chunk group with the given index
+    fn load_group(&mut self, id: usize) -> Result<&[u8], BundleReaderError> {
+        if self.group_cache.as_ref().map(|g| g.0) != Some(id) {
+            let (start, group) = {
+                let groups = self.groups.as_ref().unwrap();
+                let start: usize = groups[..id].iter().map(|g| g.encoded_size).sum();
+                (start, groups[id].clone())
+            };
+            tr_debug!("Load bundle group {} of {}", id, self.info.id);
+            let mut file = BufReader::new(try!(File::open(&self.path).context(&self.path as &Path)));
+            try!(
+                file.seek(SeekFrom::Start((self.content_start + start) as u64))
+                    .context(&self.path as &Path)
+            );
+            let mut data = Vec::with_capacity(group.encoded_size);
+            data.resize(group.encoded_size, 0);
+            try!(file.read_exact(&mut data).context(&self.path as &Path));
+            if let Some(ref digest) = group.digest {
+                if bundle_digest(&data) != *digest {
+                    return Err(BundleReaderError::Integrity(
+                        self.id(),
+                        tr!("Chunk group does not match the digest in the header, modified data")
+                    ));
+                }
+            }
+            let data = try!(self.decode_part(data, group.raw_size));
+            self.group_cache = Some((id, data));
+        }
+        Ok(&self.group_cache.as_ref().unwrap().1)
+    }
+
+    /// Loads a single chunk
+    ///
+    /// If the bundle has chunk groups, only the group containing the chunk is read and decoded,
+    /// otherwise the whole contents are.
+    pub fn load_chunk(&mut self, id: usize) -> Result<Vec<u8>, BundleReaderError> {
+        let (pos, len) = try!(self.get_chunk_position(id));
+        if !self.has_chunk_groups() {
+            let data = try!(self.load_contents());
+            return Ok(data[pos..pos + len].to_vec());
+        }
+        let (group_id, group_start) = {
+            let groups = self.groups.as_ref().unwrap();
+            let mut first_chunk = 0;
+            let mut group_start = 0;
+            let mut group_id = 0;
+            for group in groups {
+                if id < first_chunk + group.chunk_count {
+                    break;
+                }
+                first_chunk += group.chunk_count;
+                group_start += group.raw_size;
+                group_id += 1;
+            }
+            if group_id >= groups.len() {
+                return Err(BundleReaderError::Integrity(
+                    self.id(),
+                    tr!("Chunk groups do not contain all chunks")
+                ));
+            }
+            (group_id, group_start)
+        };
+        let bundle_id = self.id();
+        let group = try!(self.load_group(group_id));
+        let pos = pos - group_start;
+        if pos + len > group.len() {
+            return Err(BundleReaderError::Integrity(
+                bundle_id,
+                tr!("Chunk exceeds its group, modified data")
+            ));
+        }
+        Ok(group[pos..pos + len].to_vec())
+    }
+
     pub fn get_chunk_position(&mut self, id: usize) -> Result<(usize, usize), BundleReaderError> {
         if id >= self.info.chunk_count {
             return Err(BundleReaderError::NoSuchChunk(self.id(), id));
@@ -273,7 +468,11 @@ impl BundleReader {
         }
         if !full {
             let size = try!(fs::metadata(&self.path).context(&self.path as &Path)).len();
-            if size as usize != self.info.encoded_size + self.content_start {
+            let mut expected = self.info.encoded_size + self.content_start;
+            if let Some(min_size) = self.info.padding {
+                expected = padded_size(expected, min_size);
+            }
+            if size as usize != expected {
                 return Err(BundleReaderError::Integrity(
                     self.id(),
                     tr!("File size does not match size in header, truncated file")
@@ -288,6 +487,7 @@ impl BundleReader {
                 tr!("Encoded data size does not match size in header, truncated bundle")
             ));
         }
+        try!(self.verify_contents(&encoded_contents));
         let contents = try!(self.decode_contents(encoded_contents));
         if self.info.raw_size != contents.len() {
             return Err(BundleReaderError::Integrity(
@@ -295,10 +495,15 @@ impl BundleReader {
                 tr!("Raw data size does not match size in header, truncated bundle")
             ));
         }
+        let hash_key = if self.info.hash_method.is_keyed() {
+            try!(self.crypto.lock().unwrap().get_hash_key().context(&self.path as &Path))
+        } else {
+            vec![]
+        };
         let mut pos = 0;
         for chunk in self.chunks.as_ref().unwrap().as_ref() {
             let data = &contents[pos..pos+chunk.1 as usize];
-            if self.info.hash_method.hash(data) != chunk.0 {
+            if !self.info.hash_method.matches(&hash_key, data, &chunk.0) {
                 return Err(BundleReaderError::Integrity(
                     self.id(),
                     tr!("Stored hash does not match hash in header, modified data")
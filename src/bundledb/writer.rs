@@ -5,8 +5,10 @@ use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::{self, Write, BufWriter};
 use std::sync::{Arc, Mutex};
+use std::mem;
 
 use chrono::prelude::*;
+use sodiumoxide::randombytes::randombytes;
 
 
 quick_error!{
@@ -44,6 +46,47 @@ quick_error!{
 }
 
 
+/// Chunks are grouped until the group contains at least this much raw data
+pub const CHUNK_GROUP_SIZE: usize = 256 * 1024;
+
+
+/// The smallest size bucket of padded bundles is this fraction of the bundle size
+pub const PADDING_MIN_FRACTION: usize = 16;
+
+/// Size the encoded bundle information is padded to when padding is enabled
+///
+/// Its encrypted size is stored in the plaintext header and would otherwise reveal the number
+/// of digits in the sizes and counts of the bundle.
+pub const INFO_PADDED_SIZE: usize = 512;
+
+/// Rounds a file size up to a size bucket
+///
+/// Files are padded to at least `min_size` and to power of two multiples of `min_size` below
+/// `PADDING_MIN_FRACTION / 2` times `min_size`. This way, small bundles like the ones written at
+/// the end of a backup only reveal a rough estimate of their size.
+/// Above that, only the highest bits of the size are kept visible, so the buckets get coarser
+/// for larger sizes. The padding is then at most 3.2% for sizes of at least 64 KiB.
+pub fn padded_size(size: usize, min_size: usize) -> usize {
+    if size <= min_size {
+        return min_size;
+    }
+    if min_size > 0 && size <= min_size * (PADDING_MIN_FRACTION / 2) {
+        let mut bucket = min_size;
+        while bucket < size {
+            bucket *= 2;
+        }
+        return bucket;
+    }
+    if size < 2 {
+        return size;
+    }
+    let exponent = (0usize.leading_zeros() - size.leading_zeros() - 1) as usize;
+    let bits = (0usize.leading_zeros() - exponent.leading_zeros()) as usize;
+    let mask = (1 << (exponent - bits)) - 1;
+    (size + mask) & !mask
+}
+
+
 pub struct BundleWriter {
     mode: BundleMode,
     hash_method: HashMethod,
@@ -52,6 +95,14 @@ pub struct BundleWriter {
     compression_stream: Option<CompressionStream>,
     encryption: Option<Encryption>,
     crypto: Arc<Mutex<Crypto>>,
+    padding: Option<usize>,
+    incompressible: bool,
+    dictionary: Option<(Hash, Vec<u8>)>,
+    chunk_groups: bool,
+    group: Vec<u8>,
+    group_raw_size: usize,
+    group_chunk_count: usize,
+    groups: Vec<ChunkGroup>,
     raw_size: usize,
     chunk_count: usize,
     chunks: ChunkList
@@ -64,6 +115,7 @@ impl BundleWriter {
         compression: Option<Compression>,
         encryption: Option<Encryption>,
         crypto: Arc<Mutex<Crypto>>,
+        padding: Option<usize>,
     ) -> Result<Self, BundleWriterError> {
         let compression_stream = match compression {
             Some(ref compression) => Some(try!(compression.compress_stream().map_err(
@@ -79,46 +131,128 @@ impl BundleWriter {
             compression_stream,
             encryption,
             crypto,
+            padding,
+            incompressible: false,
+            dictionary: None,
+            chunk_groups: false,
+            group: vec![],
+            group_raw_size: 0,
+            group_chunk_count: 0,
+            groups: vec![],
             raw_size: 0,
             chunk_count: 0,
             chunks: ChunkList::new()
         })
     }
 
+    fn new_compression_stream(&self) -> Result<Option<CompressionStream>, BundleWriterError> {
+        let compression = match self.compression {
+            Some(ref compression) => compression,
+            None => return Ok(None),
+        };
+        let stream = match self.dictionary {
+            Some((_, ref dictionary)) => compression.compress_stream_with_dictionary(dictionary),
+            None => compression.compress_stream(),
+        };
+        Ok(Some(try!(stream.map_err(BundleWriterError::CompressionSetup))))
+    }
+
     pub fn add(&mut self, chunk: &[u8], hash: Hash) -> Result<usize, BundleWriterError> {
+        if self.compression_stream.is_none() {
+            // The stream of the previous chunk group has been finished
+            self.compression_stream = try!(self.new_compression_stream());
+        }
+        let output = if self.chunk_groups {
+            &mut self.group
+        } else {
+            &mut self.data
+        };
         if let Some(ref mut stream) = self.compression_stream {
-            try!(stream.process(chunk, &mut self.data).map_err(
+            try!(stream.process(chunk, output).map_err(
                 BundleWriterError::Compression
             ))
         } else {
-            self.data.extend_from_slice(chunk)
+            output.extend_from_slice(chunk)
         }
         self.raw_size += chunk.len();
         self.chunk_count += 1;
         self.chunks.push((hash, chunk.len() as u32));
+        if self.chunk_groups {
+            self.group_raw_size += chunk.len();
+            self.group_chunk_count += 1;
+            if self.group_raw_size >= CHUNK_GROUP_SIZE {
+                try!(self.finish_group());
+            }
+        }
         Ok(self.chunk_count - 1)
     }
 
-    pub fn finish(mut self, db: &BundleDb) -> Result<StoredBundle, BundleWriterError> {
-        if let Some(stream) = self.compression_stream {
-            try!(stream.finish(&mut self.data).map_err(
+    /// Compresses and encrypts the current chunk group and appends it to the data
+    fn finish_group(&mut self) -> Result<(), BundleWriterError> {
+        if self.group_chunk_count == 0 {
+            return Ok(());
+        }
+        let mut group = mem::replace(&mut self.group, vec![]);
+        if let Some(stream) = self.compression_stream.take() {
+            try!(stream.finish(&mut group).map_err(
                 BundleWriterError::Compression
             ))
         }
         if let Some(ref encryption) = self.encryption {
-            self.data = try!(self.crypto.lock().unwrap().encrypt(encryption, &self.data));
+            group = try!(self.crypto.lock().unwrap().encrypt(encryption, &group));
+        }
+        self.groups.push(ChunkGroup {
+            chunk_count: self.group_chunk_count,
+            raw_size: self.group_raw_size,
+            encoded_size: group.len(),
+            digest: Some(bundle_digest(&group))
+        });
+        self.data.extend_from_slice(&group);
+        self.group_chunk_count = 0;
+        self.group_raw_size = 0;
+        Ok(())
+    }
+
+    pub fn finish(mut self, db: &BundleDb) -> Result<StoredBundle, BundleWriterError> {
+        if self.chunk_groups {
+            try!(self.finish_group());
+        } else {
+            if let Some(stream) = self.compression_stream.take() {
+                try!(stream.finish(&mut self.data).map_err(
+                    BundleWriterError::Compression
+                ))
+            }
+            if let Some(ref encryption) = self.encryption {
+                self.data = try!(self.crypto.lock().unwrap().encrypt(encryption, &self.data));
+            }
         }
         let encoded_size = self.data.len();
         let mut chunk_data = Vec::with_capacity(self.chunks.encoded_size());
         self.chunks.write_to(&mut chunk_data).unwrap();
-        let id = BundleId(self.hash_method.hash(&chunk_data));
+        // Bundle ids must be computable without the secret key, so they are never keyed
+        let id = BundleId(self.hash_method.hash_keyed(&[], &chunk_data));
         if let Some(ref encryption) = self.encryption {
             chunk_data = try!(self.crypto.lock().unwrap().encrypt(encryption, &chunk_data));
         }
         let mut path = db.layout.temp_bundle_path();
+        let mut group_data = vec![];
+        if self.chunk_groups {
+            group_data = try!(msgpack::encode(&self.groups).context(&path as &Path));
+            if let Some(ref encryption) = self.encryption {
+                group_data = try!(self.crypto.lock().unwrap().encrypt(encryption, &group_data));
+            }
+        }
+        let mut index_data = Vec::with_capacity(chunk_data.len() + group_data.len());
+        index_data.extend_from_slice(&chunk_data);
+        index_data.extend_from_slice(&group_data);
+        let version = if self.chunk_groups {
+            HEADER_VERSION_GROUPS
+        } else {
+            HEADER_VERSION
+        };
         let mut file = BufWriter::new(try!(File::create(&path).context(&path as &Path)));
         try!(file.write_all(&HEADER_STRING).context(&path as &Path));
-        try!(file.write_all(&[HEADER_VERSION]).context(&path as &Path));
+        try!(file.write_all(&[version]).context(&path as &Path));
         let info = BundleInfo {
             mode: self.mode,
             hash_method: self.hash_method,
@@ -129,22 +263,40 @@ impl BundleWriter {
             raw_size: self.raw_size,
             encoded_size,
             chunk_list_size: chunk_data.len(),
-            timestamp: Local::now().timestamp()
+            timestamp: Local::now().timestamp(),
+            padding: self.padding,
+            incompressible: self.incompressible,
+            dictionary: self.dictionary.as_ref().map(|d| d.0),
+            group_table_size: group_data.len(),
+            index_digest: Some(bundle_digest(&index_data)),
+            content_digest: Some(bundle_digest(&self.data)),
+            chunk_groups: self.chunk_groups
         };
         let mut info_data = try!(msgpack::encode(&info).context(&path as &Path));
+        if self.padding.is_some() && info_data.len() < INFO_PADDED_SIZE {
+            // Trailing bytes are ignored when decoding
+            info_data.resize(INFO_PADDED_SIZE, 0);
+        }
         if let Some(ref encryption) = self.encryption {
             info_data = try!(self.crypto.lock().unwrap().encrypt(encryption, &info_data));
         }
         let header = BundleHeader {
+            signature: try!(self.crypto.lock().unwrap().sign(&info_data)),
             encryption: self.encryption,
             info_size: info_data.len()
         };
-        try!(msgpack::encode_to_stream(&header, &mut file).context(
-            &path as &Path
-        ));
+        let header_data = try!(msgpack::encode(&header).context(&path as &Path));
+        try!(file.write_all(&header_data).context(&path as &Path));
         try!(file.write_all(&info_data).context(&path as &Path));
-        try!(file.write_all(&chunk_data).context(&path as &Path));
+        try!(file.write_all(&index_data).context(&path as &Path));
         try!(file.write_all(&self.data).context(&path as &Path));
+        if let Some(min_size) = self.padding {
+            // Random padding is indistinguishable from encrypted data
+            let size = HEADER_STRING.len() + 1 + header_data.len() + info_data.len() +
+                chunk_data.len() + group_data.len() + self.data.len();
+            let padding = randombytes(padded_size(size, min_size) - size);
+            try!(file.write_all(&padding).context(&path as &Path));
+        }
         path = path.strip_prefix(db.layout.base_path())
             .unwrap()
             .to_path_buf();
@@ -154,6 +306,30 @@ impl BundleWriter {
         })
     }
 
+    /// Marks the bundle as containing chunks that are stored uncompressed as they are incompressible
+    #[inline]
+    pub fn set_incompressible(&mut self) {
+        self.incompressible = true;
+    }
+
+    /// Compresses the bundle with the given dictionary, must be called before adding chunks
+    pub fn set_dictionary(&mut self, id: Hash, dictionary: &[u8]) -> Result<(), BundleWriterError> {
+        assert_eq!(self.chunk_count, 0);
+        if self.compression.is_some() {
+            self.dictionary = Some((id, dictionary.to_vec()));
+            self.compression_stream = try!(self.new_compression_stream());
+        }
+        Ok(())
+    }
+
+    /// Stores the chunks in independent groups so that they can be read individually, must be
+    /// called before adding chunks
+    #[inline]
+    pub fn set_chunk_groups(&mut self) {
+        assert_eq!(self.chunk_count, 0);
+        self.chunk_groups = true;
+    }
+
     #[inline]
     pub fn raw_size(&self) -> usize {
         self.raw_size
@@ -161,6 +337,6 @@ impl BundleWriter {
 
     #[inline]
     pub fn estimate_final_size(&self) -> usize {
-        self.data.len() + self.chunk_count * 20 + 500
+        self.data.len() + self.group.len() + self.groups.len() * 16 + self.chunk_count * 20 + 500
     }
 }
@@ -75,6 +75,16 @@ impl ChunkerType {
         format!("{}/{}", self.name(), self.avg_size() / 1024)
     }
 
+    /// Returns the same chunker with another seed, chunkers without a seed are not changed
+    pub fn with_seed(&self, seed: u64) -> Self {
+        match *self {
+            ChunkerType::Ae(_size) |
+            ChunkerType::Fixed(_size) => *self,
+            ChunkerType::Rabin((size, _seed)) => ChunkerType::Rabin((size, seed as u32)),
+            ChunkerType::FastCdc((size, _seed)) => ChunkerType::FastCdc((size, seed)),
+        }
+    }
+
     pub fn seed(&self) -> u64 {
         match *self {
             ChunkerType::Ae(_size) |
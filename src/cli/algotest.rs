@@ -5,6 +5,7 @@ use std::fs::File;
 use std::collections::HashSet;
 
 use chrono::Duration;
+use sodiumoxide::randombytes::randombytes;
 
 
 struct ChunkSink {
@@ -106,8 +107,10 @@ pub fn run(
 
     tr_println!("Hashing chunks with {} ...", hash.name());
     let mut hashes = Vec::with_capacity(chunks.len());
+    // Keyed methods are tested with a random key like the one of a repository
+    let key = if hash.is_keyed() { randombytes(32) } else { vec![] };
     let hash_time = Duration::span(|| for &(pos, len) in &chunks {
-        hashes.push(hash.hash(&data[pos..pos + len]))
+        hashes.push(hash.hash_keyed(&key, &data[pos..pos + len]))
     }).num_milliseconds() as f32 / 1_000.0;
     total_write_time += hash_time;
     println!(
@@ -1,6 +1,8 @@
 use prelude::*;
 use super::*;
 
+use chrono::prelude::*;
+
 use std::path::{Path, PathBuf};
 use log;
 use clap::{App, AppSettings, Arg, SubCommand};
@@ -26,20 +28,32 @@ pub enum Arguments {
         excludes: Vec<String>,
         excludes_from: Option<String>,
         no_default_excludes: bool,
-        tar: bool
+        tar: bool,
+        no_prune: bool
     },
     Restore {
         repo_path: PathBuf,
         backup_name: String,
         inode: Option<String>,
         dst_path: String,
-        tar: bool
+        tar: bool,
+        at: Option<i64>,
+        host: Option<String>,
+        path: Option<String>
     },
     Remove {
         repo_path: PathBuf,
         backup_name: String,
         inode: Option<String>,
-        force: bool
+        force: bool,
+        ignore_locks: bool
+    },
+    Protect {
+        repo_path: PathBuf,
+        backup_name: String,
+        pinned: Option<bool>,
+        locked_until: Option<Option<i64>>,
+        ignore_locks: bool
     },
     Duplicates {
         repo_path: PathBuf,
@@ -47,20 +61,31 @@ pub enum Arguments {
         inode: Option<String>,
         min_size: u64
     },
-    Prune {
+    Deleted {
         repo_path: PathBuf,
         prefix: String,
-        daily: usize,
-        weekly: usize,
-        monthly: usize,
-        yearly: usize,
-        force: bool
+        host: Option<String>,
+        path: Option<String>,
+        restore: Option<String>
+    },
+    Prune {
+        repo_path: PathBuf,
+        policy: RetentionPolicy,
+        force: bool,
+        ignore_locks: bool
     },
     Vacuum {
         repo_path: PathBuf,
         ratio: f32,
         force: bool,
-        combine: bool
+        combine: bool,
+        limits: VacuumLimits
+    },
+    Rewrite {
+        repo_path: PathBuf,
+        selection: RewriteSelection,
+        limits: VacuumLimits,
+        force: bool
     },
     Check {
         repo_path: PathBuf,
@@ -94,7 +119,10 @@ pub enum Arguments {
         repo_path: PathBuf,
         backup_name: Option<String>,
         inode: Option<String>,
-        mount_point: String
+        mount_point: String,
+        at: Option<i64>,
+        host: Option<String>,
+        path: Option<String>
     },
     Versions { repo_path: PathBuf, path: String },
     Diff {
@@ -106,6 +134,19 @@ pub enum Arguments {
         inode_new: Option<String>
     },
     Analyze { repo_path: PathBuf },
+    Tune {
+        repo_path: PathBuf,
+        sample_size: usize,
+        speed: f32,
+        decompress_speed: f32,
+        ratio: Option<f32>,
+        apply: bool
+    },
+    TrainDict {
+        repo_path: PathBuf,
+        sample_size: usize,
+        dictionary_size: usize
+    },
     BundleList { repo_path: PathBuf },
     BundleInfo {
         repo_path: PathBuf,
@@ -114,7 +155,9 @@ pub enum Arguments {
     Import {
         repo_path: PathBuf,
         remote_path: String,
-        key_files: Vec<String>
+        key_files: Vec<String>,
+        trust: Vec<SigningPublicKey>,
+        strict: bool
     },
     Config {
         repo_path: PathBuf,
@@ -122,11 +165,22 @@ pub enum Arguments {
         chunker: Option<ChunkerType>,
         compression: Option<Option<Compression>>,
         encryption: Option<Option<PublicKey>>,
-        hash: Option<HashMethod>
+        hash: Option<HashMethod>,
+        set_retention: Option<(String, RetentionPolicy)>,
+        remove_retention: Option<String>,
+        padding: Option<bool>,
+        paranoid: Option<bool>,
+        chunk_groups: Option<bool>
     },
     GenKey {
         file: Option<String>,
-        password: Option<String>
+        password: Option<String>,
+        protect: bool
+    },
+    Passphrase {
+        repo_path: Option<PathBuf>,
+        keyfile: Option<String>,
+        remove: bool
     },
     AddKey {
         repo_path: PathBuf,
@@ -134,6 +188,52 @@ pub enum Arguments {
         password: Option<String>,
         set_default: bool
     },
+    Signing {
+        repo_path: PathBuf,
+        generate: bool,
+        trust: Vec<SigningPublicKey>,
+        untrust: Vec<SigningPublicKey>,
+        strict: Option<bool>
+    },
+    Recipients {
+        repo_path: PathBuf,
+        add: Vec<PublicKey>,
+        remove: Vec<PublicKey>
+    },
+    ListKeys {
+        repo_path: PathBuf,
+        check: bool
+    },
+    RemoveKey {
+        repo_path: PathBuf,
+        key: PublicKey,
+        force: bool
+    },
+    ExportKey {
+        repo_path: PathBuf,
+        key: PublicKey,
+        file: Option<String>,
+        paper: bool,
+        protect: bool
+    },
+    SplitKey {
+        repo_path: PathBuf,
+        key: PublicKey,
+        threshold: u8,
+        shares: u8
+    },
+    CombineKey {
+        file: String,
+        shares: Vec<String>,
+        protect: bool
+    },
+    RotateKey {
+        repo_path: PathBuf,
+        keyfile: Option<String>,
+        password: Option<String>,
+        rewrap: bool,
+        limits: VacuumLimits
+    },
     AlgoTest {
         file: String,
         bundle_size: usize,
@@ -250,6 +350,31 @@ fn validate_num(val: String) -> Result<(), String> {
     parse_num(&val).map(|_| ())
 }
 
+fn parse_share_count(num: &str) -> Result<u8, String> {
+    match num.parse::<u8>() {
+        Ok(num) if num > 0 => Ok(num),
+        _ => Err(tr!("Must be a number between 1 and 255").to_string()),
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_share_count(val: String) -> Result<(), String> {
+    parse_share_count(&val).map(|_| ())
+}
+
+fn parse_time_span(val: &str) -> Result<i64, String> {
+    if let Some(span) = ::util::parse_time_span(val) {
+        Ok(span)
+    } else {
+        Err(tr!("Invalid time span, e.g. 30d or 12h").to_string())
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_time_span(val: String) -> Result<(), String> {
+    parse_time_span(&val).map(|_| ())
+}
+
 fn parse_chunker(val: &str) -> Result<ChunkerType, String> {
     if let Ok(chunker) = ChunkerType::from_string(val) {
         Ok(chunker)
@@ -301,6 +426,29 @@ fn validate_public_key(val: String) -> Result<(), String> {
     parse_public_key(&val).map(|_| ())
 }
 
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_existing_key(val: String) -> Result<(), String> {
+    match try!(parse_public_key(&val)) {
+        Some(_) => Ok(()),
+        None => Err(tr!("A key is required").to_string()),
+    }
+}
+
+fn parse_signing_key(val: &str) -> Result<SigningPublicKey, String> {
+    let bytes = match parse_hex(val) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            return Err(tr!("Invalid hexadecimal").to_string());
+        }
+    };
+    SigningPublicKey::from_slice(&bytes).ok_or_else(|| tr!("Invalid key").to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_signing_key(val: String) -> Result<(), String> {
+    parse_signing_key(&val).map(|_| ())
+}
+
 fn parse_hash(val: &str) -> Result<HashMethod, String> {
     if let Ok(hash) = HashMethod::from(val) {
         Ok(hash)
@@ -314,6 +462,22 @@ fn validate_hash(val: String) -> Result<(), String> {
     parse_hash(&val).map(|_| ())
 }
 
+fn parse_named_retention(val: &str) -> Result<(String, RetentionPolicy), String> {
+    let pos = match val.find('=') {
+        Some(pos) => pos,
+        None => return Err(tr!("Expected a name followed by = and the rules").to_string()),
+    };
+    match RetentionPolicy::from_string(&val[pos + 1..]) {
+        Ok(policy) => Ok((val[..pos].to_string(), policy)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_named_retention(val: String) -> Result<(), String> {
+    parse_named_retention(&val).map(|_| ())
+}
+
 fn parse_bundle_id(val: &str) -> Result<BundleId, ErrorCode> {
     if let Ok(hash) = Hash::from_string(val) {
         Ok(BundleId(hash))
@@ -323,6 +487,29 @@ fn parse_bundle_id(val: &str) -> Result<BundleId, ErrorCode> {
     }
 }
 
+fn parse_date(val: &str) -> Result<i64, String> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(val) {
+        return Ok(date.timestamp());
+    }
+    for format in &["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(date) = Local.datetime_from_str(val, format) {
+            return Ok(date.timestamp());
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(val, "%Y-%m-%d") {
+        // A plain date includes the whole day
+        if let Some(date) = Local.from_local_datetime(&date.and_hms(23, 59, 59)).earliest() {
+            return Ok(date.timestamp());
+        }
+    }
+    Err(tr!("Invalid date").to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_date(val: String) -> Result<(), String> {
+    parse_date(&val).map(|_| ())
+}
+
 #[allow(unknown_lints, needless_pass_by_value)]
 fn validate_existing_path(val: String) -> Result<(), String> {
     if !Path::new(&val).exists() {
@@ -404,6 +591,8 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .arg(Arg::from_usage("--tar")
                 .help(tr!("Read the source data from a tar file"))
                 .conflicts_with_all(&["reference", "exclude", "excludes_from"]))
+            .arg(Arg::from_usage("[no_prune] --no-prune")
+                .help(tr!("Do not apply the retention policy after the backup")))
             .arg(Arg::from_usage("<SRC>")
                 .help(tr!("Source path to backup"))
                 .validator(validate_existing_path_or_stdio))
@@ -414,9 +603,18 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .about(tr!("Restore a backup or subtree"))
             .arg(Arg::from_usage("--tar")
                 .help(tr!("Restore in form of a tar file")))
+            .arg(Arg::from_usage("--at [DATE]")
+                .help(tr!("Combine the newest versions at or before this date from all backups starting with the given prefix"))
+                .validator(validate_date))
+            .arg(Arg::from_usage("--host [HOST]")
+                .requires("at")
+                .help(tr!("Only combine backups of this host")))
+            .arg(Arg::from_usage("--path [PATH]")
+                .requires("at")
+                .help(tr!("Only combine backups of this path")))
             .arg(Arg::from_usage("<BACKUP>")
                 .help(tr!("The backup/subtree path, [repository]::backup[::subtree]"))
-                .validator(|val| validate_repo_path(val, true, Some(true), None)))
+                .validator(|val| validate_repo_path(val, true, None, None)))
             .arg(Arg::from_usage("<DST>")
                 .help(tr!("Destination path for backup"))))
         .subcommand(SubCommand::with_name("remove")
@@ -424,29 +622,81 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .about(tr!("Remove a backup or a subtree"))
             .arg(Arg::from_usage("-f --force")
                 .help(tr!("Remove multiple backups in a backup folder")))
+            .arg(Arg::from_usage("[ignore_locks] --ignore-locks")
+                .help(tr!("Also remove pinned or locked backups")))
             .arg(Arg::from_usage("<BACKUP>")
                 .help(tr!("The backup/subtree path, [repository]::backup[::subtree]"))
                 .validator(|val| validate_repo_path(val, true, Some(true), None))))
+        .subcommand(SubCommand::with_name("protect")
+            .about(tr!("Pin a backup or lock it against deletion"))
+            .arg(Arg::from_usage("--pin")
+                .help(tr!("Pin the backup so that it can not be removed"))
+                .conflicts_with("unpin"))
+            .arg(Arg::from_usage("--unpin")
+                .help(tr!("Remove the pin from the backup, requires --ignore-locks")))
+            .arg(Arg::from_usage("--until [DATE]")
+                .help(tr!("Lock the backup against deletion until this date"))
+                .validator(validate_date)
+                .conflicts_with("unlock"))
+            .arg(Arg::from_usage("--unlock")
+                .help(tr!("Remove the deletion lock from the backup")))
+            .arg(Arg::from_usage("[ignore_locks] --ignore-locks")
+                .help(tr!("Allow to remove a pin or to remove or shorten an active deletion lock")))
+            .arg(Arg::from_usage("<BACKUP>")
+                .help(tr!("The backup, [repository]::backup"))
+                .validator(|val| validate_repo_path(val, true, Some(true), Some(false)))))
         .subcommand(SubCommand::with_name("prune")
             .about(tr!("Remove backups based on age"))
             .arg(Arg::from_usage("-p --prefix [PREFIX]")
                 .help(tr!("Only consider backups starting with this prefix")))
+            .arg(Arg::from_usage("-l --last [NUM]")
+                .help(tr!("Keep this number of most recent backups"))
+                .default_value("0")
+                .validator(validate_num))
+            .arg(Arg::from_usage("--within [SPAN]")
+                .help(tr!("Keep all backups within this time span of the newest backup, e.g. 30d"))
+                .validator(validate_time_span))
+            .arg(Arg::from_usage("--hourly [NUM]")
+                .help(tr!("Keep this number of hourly backups"))
+                .default_value("0")
+                .validator(validate_num))
+            .arg(Arg::from_usage("[hourly_within] --hourly-within [SPAN]")
+                .help(tr!("Keep hourly backups within this time span of the newest backup"))
+                .validator(validate_time_span))
             .arg(Arg::from_usage("-d --daily [NUM]")
                 .help(tr!("Keep this number of daily backups"))
                 .default_value("0")
                 .validator(validate_num))
+            .arg(Arg::from_usage("[daily_within] --daily-within [SPAN]")
+                .help(tr!("Keep daily backups within this time span of the newest backup"))
+                .validator(validate_time_span))
             .arg(Arg::from_usage("-w --weekly [NUM]")
                 .help(tr!("Keep this number of weekly backups"))
                 .default_value("0")
                 .validator(validate_num))
+            .arg(Arg::from_usage("[weekly_within] --weekly-within [SPAN]")
+                .help(tr!("Keep weekly backups within this time span of the newest backup"))
+                .validator(validate_time_span))
             .arg(Arg::from_usage("-m --monthly [NUM]")
                 .help(tr!("Keep this number of monthly backups"))
                 .default_value("0")
                 .validator(validate_num))
+            .arg(Arg::from_usage("[monthly_within] --monthly-within [SPAN]")
+                .help(tr!("Keep monthly backups within this time span of the newest backup"))
+                .validator(validate_time_span))
             .arg(Arg::from_usage("-y --yearly [NUM]")
                 .help(tr!("Keep this number of yearly backups"))
                 .default_value("0")
                 .validator(validate_num))
+            .arg(Arg::from_usage("[yearly_within] --yearly-within [SPAN]")
+                .help(tr!("Keep yearly backups within this time span of the newest backup"))
+                .validator(validate_time_span))
+            .arg(Arg::from_usage("[group_by_host] --group-by-host")
+                .help(tr!("Apply the rules separately for the backups of each host")))
+            .arg(Arg::from_usage("[group_by_path] --group-by-path")
+                .help(tr!("Apply the rules separately for the backups of each source path")))
+            .arg(Arg::from_usage("[ignore_locks] --ignore-locks")
+                .help(tr!("Also remove pinned or locked backups")))
             .arg(Arg::from_usage("-f --force")
                 .help(tr!("Actually run the prune instead of simulating it")))
             .arg(Arg::from_usage("<REPO>")
@@ -459,11 +709,47 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 .default_value(DEFAULT_VACUUM_RATIO_STR).validator(validate_num))
             .arg(Arg::from_usage("--combine")
                 .help(tr!("Combine small bundles into larger ones")))
+            .arg(Arg::from_usage("[max_bundles] --max-bundles [NUM]")
+                .help(tr!("Rewrite at most this many bundles"))
+                .validator(validate_num))
+            .arg(Arg::from_usage("[max_bytes] --max-bytes [SIZE]")
+                .help(tr!("Rewrite at most this much data"))
+                .validator(validate_filesize))
+            .arg(Arg::from_usage("[max_time] --max-time [SPAN]")
+                .help(tr!("Stop rewriting bundles after this time"))
+                .validator(validate_time_span))
             .arg(Arg::from_usage("-f --force")
                 .help(tr!("Actually run the vacuum instead of simulating it")))
             .arg(Arg::from_usage("<REPO>")
                 .help(tr!("Path of the repository"))
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("rewrite")
+            .about(tr!("Rewrite bundles with the current compression and encryption"))
+            .arg(Arg::from_usage("-c --compression [COMPRESSION]")
+                .help(tr!("Only rewrite bundles with this compression (or 'none')"))
+                .validator(validate_compression))
+            .arg(Arg::from_usage("-k --key [PUBLIC_KEY]")
+                .help(tr!("Only rewrite bundles encrypted with this public key (or 'none')"))
+                .validator(validate_public_key))
+            .arg(Arg::from_usage("--before [DATE]")
+                .help(tr!("Only rewrite bundles created before this date"))
+                .validator(validate_date))
+            .arg(Arg::from_usage("--rehash")
+                .help(tr!("Rehash all backups with the current hash method and rewrite old bundles")))
+            .arg(Arg::from_usage("[max_bundles] --max-bundles [NUM]")
+                .help(tr!("Rewrite at most this many bundles"))
+                .validator(validate_num))
+            .arg(Arg::from_usage("[max_bytes] --max-bytes [SIZE]")
+                .help(tr!("Rewrite at most this much data"))
+                .validator(validate_filesize))
+            .arg(Arg::from_usage("[max_time] --max-time [SPAN]")
+                .help(tr!("Stop rewriting bundles after this time"))
+                .validator(validate_time_span))
+            .arg(Arg::from_usage("-f --force")
+                .help(tr!("Actually rewrite the bundles instead of simulating it")))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("check")
             .about(tr!("Check the repository, a backup or a backup subtree"))
             .arg(Arg::from_usage("-b --bundles")
@@ -487,6 +773,15 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 .validator(|val| validate_repo_path(val, true, None, None))))
         .subcommand(SubCommand::with_name("mount")
             .about(tr!("Mount the repository, a backup or a subtree"))
+            .arg(Arg::from_usage("--at [DATE]")
+                .help(tr!("Combine the newest versions at or before this date from all backups starting with the given prefix"))
+                .validator(validate_date))
+            .arg(Arg::from_usage("--host [HOST]")
+                .requires("at")
+                .help(tr!("Only combine backups of this host")))
+            .arg(Arg::from_usage("--path [PATH]")
+                .requires("at")
+                .help(tr!("Only combine backups of this path")))
             .arg(Arg::from_usage("<PATH>")
                 .help(tr!("Path of the repository/backup/subtree, [repository][::backup[::subtree]]"))
                 .validator(|val| validate_repo_path(val, true, None, None)))
@@ -515,6 +810,13 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .about(tr!("Reconstruct a repository from the remote storage"))
             .arg(Arg::from_usage("-k --key [FILE]...")
                 .help(tr!("Key file needed to read the bundles")))
+            .arg(Arg::from_usage("--trust [PUBLIC_KEY]...")
+                .help(tr!("Trust signatures of this key"))
+                .number_of_values(1)
+                .validator(validate_signing_key))
+            .arg(Arg::from_usage("--strict")
+                .help(tr!("Refuse unsigned or untrusted backups and bundles"))
+                .requires("trust"))
             .arg(Arg::from_usage("<REMOTE>")
                 .help(tr!("Remote repository path"))
                 .validator(validate_existing_path))
@@ -531,6 +833,41 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .arg(Arg::from_usage("<REPO>")
                 .help(tr!("Path of the repository"))
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("tune")
+            .about(tr!("Find the best compression for the stored data"))
+            .arg(Arg::from_usage("[sample_size] --sample-size [SIZE]")
+                .help(tr!("Amount of stored data to test the compressions with"))
+                .default_value(DEFAULT_TUNE_SAMPLE_SIZE_STR)
+                .validator(validate_filesize))
+            .arg(Arg::from_usage("--speed [NUM]")
+                .help(tr!("Minimal compression speed in MiB/s"))
+                .default_value(DEFAULT_TUNE_SPEED_STR)
+                .validator(validate_num))
+            .arg(Arg::from_usage("[decompress_speed] --decompress-speed [NUM]")
+                .help(tr!("Minimal decompression speed in MiB/s"))
+                .default_value(DEFAULT_TUNE_DECOMPRESS_SPEED_STR)
+                .validator(validate_num))
+            .arg(Arg::from_usage("--ratio [PERCENT]")
+                .help(tr!("Maximal compressed size in percent of the original size"))
+                .validator(validate_num))
+            .arg(Arg::from_usage("-a --apply")
+                .help(tr!("Use the selected compression for new data")))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("traindict")
+            .about(tr!("Train a compression dictionary for metadata"))
+            .arg(Arg::from_usage("[sample_size] --sample-size [SIZE]")
+                .help(tr!("Amount of stored metadata to train the dictionary with"))
+                .default_value(DEFAULT_DICT_SAMPLE_SIZE_STR)
+                .validator(validate_filesize))
+            .arg(Arg::from_usage("[size] --size [SIZE]")
+                .help(tr!("Maximal size of the dictionary"))
+                .default_value(DEFAULT_DICT_SIZE_STR)
+                .validator(validate_filesize))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("versions")
             .about(tr!("Find different versions of a file in all backups"))
             .arg(Arg::from_usage("<REPO>")
@@ -556,6 +893,19 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .arg(Arg::from_usage("<BACKUP>")
                 .help(tr!("The backup/subtree path, [repository]::backup[::subtree]"))
                 .validator(|val| validate_repo_path(val, true, Some(true), None))))
+        .subcommand(SubCommand::with_name("deleted")
+            .about(tr!("Find files that have been deleted since earlier backups"))
+            .arg(Arg::from_usage("-p --prefix [PREFIX]")
+                .help(tr!("Only consider backups starting with this prefix")))
+            .arg(Arg::from_usage("--host [HOST]")
+                .help(tr!("Only consider backups of this host")))
+            .arg(Arg::from_usage("--path [PATH]")
+                .help(tr!("Only consider backups of this path")))
+            .arg(Arg::from_usage("--restore [DST]")
+                .help(tr!("Restore the deleted files into this directory")))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("copy")
             .alias("cp")
             .about(tr!("Create a copy of a backup"))
@@ -582,6 +932,26 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .arg(Arg::from_usage("--hash [HASH]")
                 .help(tr!("Set the hash method"))
                 .validator(validate_hash))
+            .arg(Arg::from_usage("[set_retention] --set-retention [NAME=RULES]")
+                .help(tr!("Add or replace a retention policy, e.g. srv=prefix=srv/,daily=7,auto"))
+                .validator(validate_named_retention))
+            .arg(Arg::from_usage("[remove_retention] --remove-retention [NAME]")
+                .help(tr!("Remove a retention policy")))
+            .arg(Arg::from_usage("--padding")
+                .help(tr!("Pad new bundles to size buckets with random data"))
+                .conflicts_with("no_padding"))
+            .arg(Arg::from_usage("[no_padding] --no-padding")
+                .help(tr!("Do not pad new bundles")))
+            .arg(Arg::from_usage("--paranoid")
+                .help(tr!("Compare chunks with the stored data before deduplicating them"))
+                .conflicts_with("no_paranoid"))
+            .arg(Arg::from_usage("[no_paranoid] --no-paranoid")
+                .help(tr!("Trust the chunk hashes for deduplication")))
+            .arg(Arg::from_usage("[chunk_groups] --chunk-groups")
+                .help(tr!("Store chunks of new bundles in groups that can be read individually"))
+                .conflicts_with("no_chunk_groups"))
+            .arg(Arg::from_usage("[no_chunk_groups] --no-chunk-groups")
+                .help(tr!("Compress and encrypt new bundles as a whole")))
             .arg(Arg::from_usage("<REPO>")
                 .help(tr!("Path of the repository"))
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
@@ -589,8 +959,22 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .about(tr!("Generate a new key pair"))
             .arg(Arg::from_usage("-p --password [PASSWORD]")
                 .help(tr!("Derive the key pair from the given password")))
+            .arg(Arg::from_usage("--protect")
+                .help(tr!("Protect the secret key with a passphrase"))
+                .requires("FILE"))
             .arg(Arg::from_usage("[FILE]")
                 .help(tr!("Destination file for the keypair"))))
+        .subcommand(SubCommand::with_name("passphrase")
+            .about(tr!("Change the passphrase of secret keys"))
+            .arg(Arg::from_usage("--remove")
+                .help(tr!("Store the secret keys without a passphrase")))
+            .arg(Arg::from_usage("-k --keyfile [FILE]")
+                .help(tr!("Change the passphrase of this keyfile instead"))
+                .validator(validate_existing_path))
+            .arg(Arg::from_usage("[REPO]")
+                .help(tr!("Path of the repository"))
+                .required_unless("keyfile")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("addkey")
             .about(tr!("Add a key pair to the repository"))
             .arg(Arg::from_usage("-g --generate")
@@ -607,6 +991,114 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .arg(Arg::from_usage("<REPO>")
                 .help(tr!("Path of the repository"))
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("signing")
+            .about(tr!("Manage the keys that backups and bundles are signed with"))
+            .arg(Arg::from_usage("-g --generate")
+                .help(tr!("Generate a new signing key pair for this repository")))
+            .arg(Arg::from_usage("--trust [PUBLIC_KEY]...")
+                .help(tr!("Trust signatures of this key"))
+                .number_of_values(1)
+                .validator(validate_signing_key))
+            .arg(Arg::from_usage("--untrust [PUBLIC_KEY]...")
+                .help(tr!("Do not trust signatures of this key anymore"))
+                .number_of_values(1)
+                .validator(validate_signing_key))
+            .arg(Arg::from_usage("--strict")
+                .help(tr!("Refuse unsigned or untrusted backups and bundles"))
+                .conflicts_with("no_strict"))
+            .arg(Arg::from_usage("[no_strict] --no-strict")
+                .help(tr!("Accept unsigned backups and bundles")))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("recipients")
+            .about(tr!("List, add or remove the public keys that can read the repository"))
+            .arg(Arg::from_usage("-a --add [PUBLIC_KEY]...")
+                .help(tr!("Add a recipient"))
+                .number_of_values(1)
+                .validator(validate_public_key))
+            .arg(Arg::from_usage("-r --remove [PUBLIC_KEY]...")
+                .help(tr!("Remove a recipient"))
+                .number_of_values(1)
+                .validator(validate_public_key))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("listkeys")
+            .about(tr!("List the keys of the repository"))
+            .arg(Arg::from_usage("-c --check")
+                .help(tr!("Count the bundles and backups that each key can decrypt")))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("removekey")
+            .about(tr!("Remove a secret key from the repository"))
+            .arg(Arg::from_usage("-f --force")
+                .help(tr!("Remove the key even if no other stored key can read new data")))
+            .arg(Arg::from_usage("<PUBLIC_KEY>")
+                .help(tr!("Public key of the key pair to remove"))
+                .validator(validate_existing_key))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("exportkey")
+            .about(tr!("Export a key pair of the repository"))
+            .arg(Arg::from_usage("--paper")
+                .help(tr!("Output the key pair in a format suitable for printing"))
+                .conflicts_with("protect"))
+            .arg(Arg::from_usage("--protect")
+                .help(tr!("Protect the exported secret key with a passphrase"))
+                .requires("FILE"))
+            .arg(Arg::from_usage("<PUBLIC_KEY>")
+                .help(tr!("Public key of the key pair to export"))
+                .validator(validate_existing_key))
+            .arg(Arg::from_usage("[FILE]")
+                .help(tr!("Destination file for the keypair")))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("splitkey")
+            .about(tr!("Split a secret key into shares for escrow"))
+            .arg(Arg::from_usage("-t --threshold <NUM>")
+                .help(tr!("Number of shares needed to restore the key"))
+                .validator(validate_share_count))
+            .arg(Arg::from_usage("-n --shares <NUM>")
+                .help(tr!("Number of shares to create"))
+                .validator(validate_share_count))
+            .arg(Arg::from_usage("<PUBLIC_KEY>")
+                .help(tr!("Public key of the key pair to split"))
+                .validator(validate_existing_key))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("combinekey")
+            .about(tr!("Restore a key pair from shares"))
+            .arg(Arg::from_usage("--protect")
+                .help(tr!("Protect the secret key with a passphrase")))
+            .arg(Arg::from_usage("<FILE>")
+                .help(tr!("Destination file for the keypair")))
+            .arg(Arg::from_usage("[SHARE]...")
+                .help(tr!("Shares of the key, read from stdin if not given"))))
+        .subcommand(SubCommand::with_name("rotatekey")
+            .about(tr!("Replace the key pair of the repository and re-encrypt all data"))
+            .arg(Arg::from_usage("-k --keyfile [FILE]")
+                .help(tr!("Destination file for the new key pair")))
+            .arg(Arg::from_usage("-p --password [PASSWORD]")
+                .help(tr!("Derive the new key pair from the given password")))
+            .arg(Arg::from_usage("--rewrap")
+                .help(tr!("Only make the data key accessible for the new key pair")))
+            .arg(Arg::from_usage("[max_bundles] --max-bundles [NUM]")
+                .help(tr!("Rewrite at most this many bundles"))
+                .validator(validate_num))
+            .arg(Arg::from_usage("[max_bytes] --max-bytes [SIZE]")
+                .help(tr!("Rewrite at most this much data"))
+                .validator(validate_filesize))
+            .arg(Arg::from_usage("[max_time] --max-time [SPAN]")
+                .help(tr!("Stop rewriting bundles after this time"))
+                .validator(validate_time_span))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("algotest")
             .about(tr!("Test a specific algorithm combination"))
             .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE]")
@@ -682,18 +1174,27 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 src_path: args.value_of("SRC").unwrap().to_string(),
                 reference: args.value_of("reference").map(|v| v.to_string()),
                 no_default_excludes: args.is_present("no_default_excludes"),
-                tar: args.is_present("tar")
+                tar: args.is_present("tar"),
+                no_prune: args.is_present("no_prune")
             }
         }
         ("restore", Some(args)) => {
             let (repository, backup, inode) =
-                parse_repo_path(args.value_of("BACKUP").unwrap(), true, Some(true), None).unwrap();
+                parse_repo_path(args.value_of("BACKUP").unwrap(), true, None, None).unwrap();
+            let at = args.value_of("at").map(|v| parse_date(v).unwrap());
+            if at.is_none() && backup.is_none() {
+                tr_error!("A backup must be specified");
+                return Err(ErrorCode::InvalidArgs);
+            }
             Arguments::Restore {
                 repo_path: repository,
-                backup_name: backup.unwrap().to_string(),
+                backup_name: backup.unwrap_or("").to_string(),
                 inode: inode.map(|v| v.to_string()),
                 dst_path: args.value_of("DST").unwrap().to_string(),
-                tar: args.is_present("tar")
+                tar: args.is_present("tar"),
+                at: at,
+                host: args.value_of("host").map(|v| v.to_string()),
+                path: args.value_of("path").map(|v| v.to_string())
             }
         }
         ("remove", Some(args)) => {
@@ -703,7 +1204,32 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 repo_path: repository,
                 backup_name: backup.unwrap().to_string(),
                 inode: inode.map(|v| v.to_string()),
-                force: args.is_present("force")
+                force: args.is_present("force"),
+                ignore_locks: args.is_present("ignore_locks")
+            }
+        }
+        ("protect", Some(args)) => {
+            let (repository, backup, _inode) =
+                parse_repo_path(args.value_of("BACKUP").unwrap(), true, Some(true), Some(false))
+                    .unwrap();
+            let pinned = if args.is_present("pin") {
+                Some(true)
+            } else if args.is_present("unpin") {
+                Some(false)
+            } else {
+                None
+            };
+            let locked_until = if args.is_present("unlock") {
+                Some(None)
+            } else {
+                args.value_of("until").map(|v| Some(parse_date(v).unwrap()))
+            };
+            Arguments::Protect {
+                repo_path: repository,
+                backup_name: backup.unwrap().to_string(),
+                pinned: pinned,
+                locked_until: locked_until,
+                ignore_locks: args.is_present("ignore_locks")
             }
         }
         ("prune", Some(args)) => {
@@ -713,14 +1239,29 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 Some(false),
                 Some(false)
             ).unwrap();
+            let span = |name| args.value_of(name).map(|v| parse_time_span(v).unwrap());
             Arguments::Prune {
                 repo_path: repository,
-                prefix: args.value_of("prefix").unwrap_or("").to_string(),
                 force: args.is_present("force"),
-                daily: parse_num(args.value_of("daily").unwrap()).unwrap() as usize,
-                weekly: parse_num(args.value_of("weekly").unwrap()).unwrap() as usize,
-                monthly: parse_num(args.value_of("monthly").unwrap()).unwrap() as usize,
-                yearly: parse_num(args.value_of("yearly").unwrap()).unwrap() as usize
+                ignore_locks: args.is_present("ignore_locks"),
+                policy: RetentionPolicy {
+                    prefix: args.value_of("prefix").unwrap_or("").to_string(),
+                    last: parse_num(args.value_of("last").unwrap()).unwrap() as usize,
+                    hourly: parse_num(args.value_of("hourly").unwrap()).unwrap() as usize,
+                    daily: parse_num(args.value_of("daily").unwrap()).unwrap() as usize,
+                    weekly: parse_num(args.value_of("weekly").unwrap()).unwrap() as usize,
+                    monthly: parse_num(args.value_of("monthly").unwrap()).unwrap() as usize,
+                    yearly: parse_num(args.value_of("yearly").unwrap()).unwrap() as usize,
+                    within: span("within"),
+                    hourly_within: span("hourly_within"),
+                    daily_within: span("daily_within"),
+                    weekly_within: span("weekly_within"),
+                    monthly_within: span("monthly_within"),
+                    yearly_within: span("yearly_within"),
+                    group_by_host: args.is_present("group_by_host"),
+                    group_by_path: args.is_present("group_by_path"),
+                    ..Default::default()
+                }
             }
         }
         ("vacuum", Some(args)) => {
@@ -734,7 +1275,38 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 repo_path: repository,
                 force: args.is_present("force"),
                 combine: args.is_present("combine"),
-                ratio: parse_num(args.value_of("ratio").unwrap()).unwrap() as f32 / 100.0
+                ratio: parse_num(args.value_of("ratio").unwrap()).unwrap() as f32 / 100.0,
+                limits: VacuumLimits {
+                    bundles: args.value_of("max_bundles").map(|v| parse_num(v).unwrap() as usize),
+                    bytes: args.value_of("max_bytes").map(|v| parse_filesize(v).unwrap()),
+                    time: args.value_of("max_time").map(|v| parse_time_span(v).unwrap())
+                }
+            }
+        }
+        ("rewrite", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Rewrite {
+                repo_path: repository,
+                selection: RewriteSelection {
+                    compression: args.value_of("compression").map(|v| {
+                        parse_compression(v).unwrap()
+                    }),
+                    encryption: args.value_of("key").map(|v| parse_public_key(v).unwrap()),
+                    before: args.value_of("before").map(|v| parse_date(v).unwrap()),
+                    rehash: args.is_present("rehash"),
+                    ..Default::default()
+                },
+                limits: VacuumLimits {
+                    bundles: args.value_of("max_bundles").map(|v| parse_num(v).unwrap() as usize),
+                    bytes: args.value_of("max_bytes").map(|v| parse_filesize(v).unwrap()),
+                    time: args.value_of("max_time").map(|v| parse_time_span(v).unwrap())
+                },
+                force: args.is_present("force")
             }
         }
         ("check", Some(args)) => {
@@ -819,7 +1391,10 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 repo_path: repository,
                 backup_name: backup.map(|v| v.to_string()),
                 inode: inode.map(|v| v.to_string()),
-                mount_point: args.value_of("MOUNTPOINT").unwrap().to_string()
+                mount_point: args.value_of("MOUNTPOINT").unwrap().to_string(),
+                at: args.value_of("at").map(|v| parse_date(v).unwrap()),
+                host: args.value_of("host").map(|v| v.to_string()),
+                path: args.value_of("path").map(|v| v.to_string())
             }
         }
         ("versions", Some(args)) => {
@@ -857,6 +1432,35 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             ).unwrap();
             Arguments::Analyze { repo_path: repository }
         }
+        ("tune", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Tune {
+                repo_path: repository,
+                sample_size: parse_filesize(args.value_of("sample_size").unwrap()).unwrap() as usize,
+                speed: parse_num(args.value_of("speed").unwrap()).unwrap() as f32,
+                decompress_speed: parse_num(args.value_of("decompress_speed").unwrap()).unwrap() as f32,
+                ratio: args.value_of("ratio").map(|v| parse_num(v).unwrap() as f32),
+                apply: args.is_present("apply")
+            }
+        }
+        ("traindict", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::TrainDict {
+                repo_path: repository,
+                sample_size: parse_filesize(args.value_of("sample_size").unwrap()).unwrap() as usize,
+                dictionary_size: parse_filesize(args.value_of("size").unwrap()).unwrap() as usize
+            }
+        }
         ("import", Some(args)) => {
             let (repository, _backup, _inode) = parse_repo_path(
                 args.value_of("REPO").unwrap(),
@@ -869,7 +1473,11 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 remote_path: args.value_of("REMOTE").unwrap().to_string(),
                 key_files: args.values_of("key")
                     .map(|v| v.map(|k| k.to_string()).collect())
-                    .unwrap_or_else(|| vec![])
+                    .unwrap_or_else(|| vec![]),
+                trust: args.values_of("trust")
+                    .map(|v| v.map(|k| parse_signing_key(k).unwrap()).collect())
+                    .unwrap_or_else(|| vec![]),
+                strict: args.is_present("strict")
             }
         }
         ("duplicates", Some(args)) => {
@@ -884,6 +1492,21 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 }).unwrap()
             }
         }
+        ("deleted", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Deleted {
+                repo_path: repository,
+                prefix: args.value_of("prefix").unwrap_or("").to_string(),
+                host: args.value_of("host").map(|v| v.to_string()),
+                path: args.value_of("path").map(|v| v.to_string()),
+                restore: args.value_of("restore").map(|v| v.to_string())
+            }
+        }
         ("config", Some(args)) => {
             let (repository, _backup, _inode) = parse_repo_path(
                 args.value_of("REPO").unwrap(),
@@ -903,13 +1526,49 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                     |v| parse_public_key(v).unwrap()
                 ),
                 hash: args.value_of("hash").map(|v| parse_hash(v).unwrap()),
+                set_retention: args.value_of("set_retention").map(|v| {
+                    parse_named_retention(v).unwrap()
+                }),
+                remove_retention: args.value_of("remove_retention").map(|v| v.to_string()),
+                padding: if args.is_present("padding") {
+                    Some(true)
+                } else if args.is_present("no_padding") {
+                    Some(false)
+                } else {
+                    None
+                },
+                paranoid: if args.is_present("paranoid") {
+                    Some(true)
+                } else if args.is_present("no_paranoid") {
+                    Some(false)
+                } else {
+                    None
+                },
+                chunk_groups: if args.is_present("chunk_groups") {
+                    Some(true)
+                } else if args.is_present("no_chunk_groups") {
+                    Some(false)
+                } else {
+                    None
+                },
                 repo_path: repository
             }
         }
         ("genkey", Some(args)) => {
             Arguments::GenKey {
                 file: args.value_of("FILE").map(|v| v.to_string()),
-                password: args.value_of("password").map(|v| v.to_string())
+                password: args.value_of("password").map(|v| v.to_string()),
+                protect: args.is_present("protect")
+            }
+        }
+        ("passphrase", Some(args)) => {
+            let repository = args.value_of("REPO").map(|repo| {
+                parse_repo_path(repo, true, Some(false), Some(false)).unwrap().0
+            });
+            Arguments::Passphrase {
+                repo_path: repository,
+                keyfile: args.value_of("keyfile").map(|v| v.to_string()),
+                remove: args.is_present("remove")
             }
         }
         ("addkey", Some(args)) => {
@@ -926,6 +1585,136 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 file: args.value_of("FILE").map(|v| v.to_string())
             }
         }
+        ("signing", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Signing {
+                repo_path: repository,
+                generate: args.is_present("generate"),
+                trust: args.values_of("trust")
+                    .map(|v| v.map(|k| parse_signing_key(k).unwrap()).collect())
+                    .unwrap_or_else(|| vec![]),
+                untrust: args.values_of("untrust")
+                    .map(|v| v.map(|k| parse_signing_key(k).unwrap()).collect())
+                    .unwrap_or_else(|| vec![]),
+                strict: if args.is_present("strict") {
+                    Some(true)
+                } else if args.is_present("no_strict") {
+                    Some(false)
+                } else {
+                    None
+                }
+            }
+        }
+        ("recipients", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Recipients {
+                repo_path: repository,
+                add: args.values_of("add")
+                    .map(|v| v.filter_map(|k| parse_public_key(k).unwrap()).collect())
+                    .unwrap_or_else(|| vec![]),
+                remove: args.values_of("remove")
+                    .map(|v| v.filter_map(|k| parse_public_key(k).unwrap()).collect())
+                    .unwrap_or_else(|| vec![])
+            }
+        }
+        ("listkeys", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::ListKeys {
+                repo_path: repository,
+                check: args.is_present("check")
+            }
+        }
+        ("removekey", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::RemoveKey {
+                repo_path: repository,
+                key: parse_public_key(args.value_of("PUBLIC_KEY").unwrap())
+                    .unwrap()
+                    .unwrap(),
+                force: args.is_present("force")
+            }
+        }
+        ("exportkey", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::ExportKey {
+                repo_path: repository,
+                key: parse_public_key(args.value_of("PUBLIC_KEY").unwrap())
+                    .unwrap()
+                    .unwrap(),
+                file: args.value_of("FILE").map(|v| v.to_string()),
+                paper: args.is_present("paper"),
+                protect: args.is_present("protect")
+            }
+        }
+        ("splitkey", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::SplitKey {
+                repo_path: repository,
+                key: parse_public_key(args.value_of("PUBLIC_KEY").unwrap())
+                    .unwrap()
+                    .unwrap(),
+                threshold: parse_share_count(args.value_of("threshold").unwrap()).unwrap(),
+                shares: parse_share_count(args.value_of("shares").unwrap()).unwrap()
+            }
+        }
+        ("combinekey", Some(args)) => {
+            Arguments::CombineKey {
+                file: args.value_of("FILE").unwrap().to_string(),
+                shares: args.values_of("SHARE")
+                    .map(|v| v.map(|s| s.to_string()).collect())
+                    .unwrap_or_else(|| vec![]),
+                protect: args.is_present("protect")
+            }
+        }
+        ("rotatekey", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::RotateKey {
+                repo_path: repository,
+                keyfile: args.value_of("keyfile").map(|v| v.to_string()),
+                password: args.value_of("password").map(|v| v.to_string()),
+                rewrap: args.is_present("rewrap"),
+                limits: VacuumLimits {
+                    bundles: args.value_of("max_bundles").map(|v| parse_num(v).unwrap() as usize),
+                    bytes: args.value_of("max_bytes").map(|v| parse_filesize(v).unwrap()),
+                    time: args.value_of("max_time").map(|v| parse_time_span(v).unwrap())
+                }
+            }
+        }
         ("algotest", Some(args)) => {
             Arguments::AlgoTest {
                 bundle_size: (parse_num(args.value_of("bundle_size").unwrap()).unwrap() *
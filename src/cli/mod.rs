@@ -8,7 +8,7 @@ use chrono::prelude::*;
 use regex::{self, RegexSet};
 
 use std::collections::HashMap;
-use std::io::{BufReader, BufRead};
+use std::io::{self, BufReader, BufRead, Write};
 use std::fs::File;
 use std::env;
 use std::str;
@@ -46,7 +46,12 @@ pub enum ErrorCode {
     VersionsRun,
     ImportRun,
     FuseMount,
-    DuplicatesRun
+    DuplicatesRun,
+    DeletedRun,
+    RewriteRun,
+    RotateKeyRun,
+    TuneRun,
+    TrainDictRun
 }
 impl ErrorCode {
     pub fn code(&self) -> i32 {
@@ -83,6 +88,11 @@ impl ErrorCode {
             ErrorCode::ImportRun => 23,
             ErrorCode::FuseMount => 24,
             ErrorCode::DuplicatesRun => 27,
+            ErrorCode::DeletedRun => 28,
+            ErrorCode::RewriteRun => 29,
+            ErrorCode::RotateKeyRun => 30,
+            ErrorCode::TuneRun => 31,
+            ErrorCode::TrainDictRun => 32,
             //
             ErrorCode::NoSuchBackup => 25,
             ErrorCode::BackupAlreadyExists => 26,
@@ -97,6 +107,11 @@ pub const DEFAULT_COMPRESSION: &str = "brotli/3";
 pub const DEFAULT_BUNDLE_SIZE_STR: &str = "25";
 pub const DEFAULT_VACUUM_RATIO_STR: &str = "0";
 pub const DEFAULT_DUPLICATES_MIN_SIZE_STR: &str = "1b";
+pub const DEFAULT_TUNE_SAMPLE_SIZE_STR: &str = "64m";
+pub const DEFAULT_TUNE_SPEED_STR: &str = "10";
+pub const DEFAULT_TUNE_DECOMPRESS_SPEED_STR: &str = "50";
+pub const DEFAULT_DICT_SAMPLE_SIZE_STR: &str = "16m";
+pub const DEFAULT_DICT_SIZE_STR: &str = "112k";
 lazy_static! {
     pub static ref ZVAULT_FOLDER: PathBuf = {
         env::home_dir().unwrap().join(".zvault")
@@ -135,6 +150,29 @@ fn get_backup(repo: &Repository, backup_name: &str) -> Result<Backup, ErrorCode>
     ))
 }
 
+fn get_backup_at(
+    repo: &mut Repository,
+    prefix: &str,
+    host: Option<&String>,
+    path: Option<&String>,
+    at: i64,
+) -> Result<Backup, ErrorCode> {
+    let series = checked!(
+        repo.get_backup_series(prefix, host.map(|v| v as &str), path.map(|v| v as &str)),
+        "load backups",
+        ErrorCode::LoadBackup
+    );
+    if !series.iter().any(|&(_, ref backup)| backup.timestamp <= at) {
+        tr_error!("No backup exists at or before that date");
+        return Err(ErrorCode::NoSuchBackup);
+    }
+    Ok(checked!(
+        repo.create_backup_at(&series, at),
+        "combine backups",
+        ErrorCode::LoadBackup
+    ))
+}
+
 fn get_inode(repo: &mut Repository, backup: &Backup, inode: Option<&String>) -> Result<Inode, ErrorCode> {
     Ok(if let Some(inode) = inode {
         checked!(
@@ -181,6 +219,15 @@ fn find_reference_backup(
     Ok(matching.pop())
 }
 
+fn print_backup_locks(backup: &Backup) {
+    tr_println!("Pinned: {}", if backup.pinned { tr!("yes") } else { tr!("no") });
+    if let Some(until) = backup.locked_until {
+        tr_println!("Locked until: {}", Local.timestamp(until, 0).to_rfc2822());
+    } else {
+        tr_println!("Locked until: -");
+    }
+}
+
 fn print_backup(backup: &Backup) {
     if backup.modified {
         tr_warn!("This backup has been modified");
@@ -222,6 +269,7 @@ fn print_backup(backup: &Backup) {
         backup.chunk_count,
         to_file_size(backup.avg_chunk_size as u64)
     );
+    print_backup_locks(backup);
 }
 
 pub fn format_inode_one_line(inode: &Inode) -> String {
@@ -303,13 +351,21 @@ fn print_backups(backup_map: &HashMap<String, Backup>) {
     let mut backups: Vec<_> = backup_map.into_iter().collect();
     backups.sort_by_key(|b| b.0);
     for (name, backup) in backups {
+        let lock = if backup.pinned {
+            tr!("pinned")
+        } else if backup.is_lock_active() {
+            tr!("locked")
+        } else {
+            ""
+        };
         println!(
-            "{:40}  {:>32}  {:7} files, {:6} dirs, {:>10}",
+            "{:40}  {:>32}  {:7} files, {:6} dirs, {:>10}  {}",
             name,
             Local.timestamp(backup.timestamp, 0).to_rfc2822(),
             backup.file_count,
             backup.dir_count,
-            to_file_size(backup.total_data_size)
+            to_file_size(backup.total_data_size),
+            lock
         );
     }
 }
@@ -330,6 +386,9 @@ fn print_repoinfo(info: &RepositoryInfo) {
         to_file_size(info.index_size as u64),
         index_usage * 100.0
     );
+    if let Some(reclaimable) = info.reclaimable_size {
+        tr_println!("Reclaimable by vacuum: about {}", to_file_size(reclaimable));
+    }
 }
 
 fn print_repostats(stats: &RepositoryStatistics) {
@@ -366,6 +425,14 @@ fn print_repostats(stats: &RepositoryStatistics) {
     tr_println!("  - encoded size: ø = {}, maximum: {}", to_file_size(esize.avg as u64), to_file_size(esize.max as u64));
     let ccount = &stats.bundles.chunk_count_data;
     tr_println!("  - chunk count: ø = {:.1}, maximum: {:.0}", ccount.avg, ccount.max);
+    if stats.bundles.raw_size_incompressible.count > 0 {
+        let tsize = (stats.bundles.raw_size_incompressible.count as f32 * stats.bundles.encoded_size_incompressible.avg) as u64;
+        tr_println!("Incompressible data bundles: {} in {} bundles", to_file_size(tsize), stats.bundles.raw_size_incompressible.count);
+        let rsize = &stats.bundles.raw_size_incompressible;
+        tr_println!("  - raw size: ø = {}, maximum: {}", to_file_size(rsize.avg as u64), to_file_size(rsize.max as u64));
+        let ccount = &stats.bundles.chunk_count_incompressible;
+        tr_println!("  - chunk count: ø = {:.1}, maximum: {:.0}", ccount.avg, ccount.max);
+    }
     println!();
     tr_println!("Bundle methods\n==============");
     tr_println!("Hash:");
@@ -407,6 +474,18 @@ fn print_bundle(bundle: &StoredBundle) {
         "none".to_string()
     };
     tr_println!("  - Encryption: {}", encryption);
+    if let Some(min_size) = bundle.info.padding {
+        tr_println!("  - Padded: at least {}", to_file_size(min_size as u64));
+    }
+    if bundle.info.incompressible {
+        tr_println!("  - Incompressible: yes");
+    }
+    if let Some(ref dictionary) = bundle.info.dictionary {
+        tr_println!("  - Dictionary: {}", dictionary);
+    }
+    if bundle.info.chunk_groups {
+        tr_println!("  - Chunk groups: yes");
+    }
     tr_println!("  - Chunks: {}", bundle.info.chunk_count);
     tr_println!(
         "  - Size: {}",
@@ -448,19 +527,79 @@ fn print_config(config: &Config) {
         tr_println!("Compression: none");
     }
     if let Some(ref encryption) = config.encryption {
-        tr_println!("Encryption: {}", to_hex(&encryption.1[..]));
+        tr_println!(
+            "Encryption: {} ({})",
+            to_hex(&encryption.1[..]),
+            encryption.0.to_string()
+        );
     } else {
         tr_println!("Encryption: none");
     }
     tr_println!("Hash method: {}", config.hash.name());
+    tr_println!("Bundle padding: {}", config.bundle_padding);
+    tr_println!("Paranoid deduplication: {}", config.paranoid_dedup);
+    tr_println!("Chunk groups: {}", config.chunk_groups);
+    if let Some(ref dictionary) = config.meta_dictionary {
+        tr_println!("Meta dictionary: {}", dictionary);
+    } else {
+        tr_println!("Meta dictionary: none");
+    }
+    for (name, policy) in &config.retention {
+        tr_println!("Retention policy {}: {}", name, policy.to_string());
+    }
+}
+
+fn print_keys(keys: &[KeyInfo], unreadable: Option<&UnreadableData>) {
+    for key in keys {
+        let mut flags = vec![];
+        if key.has_secret {
+            flags.push(tr!("secret"));
+        }
+        if key.recipient {
+            flags.push(tr!("in use"));
+        }
+        tr_println!("{}  {}  {}", key.fingerprint, to_hex(&key.public[..]), flags.join(", "));
+        if unreadable.is_some() {
+            tr_println!("  - readable: {} bundles, {} backups", key.bundles, key.backups);
+        }
+    }
+    if let Some(unreadable) = unreadable {
+        if unreadable.bundles > 0 || unreadable.backups > 0 {
+            tr_warn!(
+                "{} bundles and {} backups can not be decrypted with the stored keys",
+                unreadable.bundles,
+                unreadable.backups
+            );
+        }
+    }
+}
+
+fn format_paper_key(public: &PublicKey, secret: &SecretKey) -> String {
+    let mut text = String::new();
+    text.push_str(&tr_format!("Fingerprint: {}\n", Crypto::fingerprint(public)));
+    for &(name, data) in &[(tr!("Public key"), &public[..]), (tr!("Secret key"), &secret[..])] {
+        text.push_str(&format!("\n{}:\n", name));
+        // Every line carries a checksum so that typing errors can be located
+        for (num, line) in data.chunks(8).enumerate() {
+            let hex = to_hex(line);
+            let groups: Vec<&str> = (0..hex.len() / 4).map(|i| &hex[i * 4..i * 4 + 4]).collect();
+            let checksum = HashMethod::Blake2.hash(line).low as u8;
+            text.push_str(&format!("{:2}: {}  ({:02x})\n", num + 1, groups.join(" "), checksum));
+        }
+    }
+    text
 }
 
 fn print_analysis(analysis: &HashMap<u32, BundleAnalysis>) {
     let mut reclaim_space = [0; 11];
     let mut rewrite_size = [0; 11];
     let mut data_total = 0;
+    let mut incompressible_total = 0;
     for bundle in analysis.values() {
         data_total += bundle.info.encoded_size;
+        if bundle.info.incompressible {
+            incompressible_total += bundle.info.encoded_size;
+        }
         #[allow(unknown_lints, needless_range_loop)]
         for i in 0..11 {
             if bundle.get_usage_ratio() <= i as f32 * 0.1 {
@@ -476,6 +615,11 @@ fn print_analysis(analysis: &HashMap<u32, BundleAnalysis>) {
         to_file_size(used as u64),
         used as f32 / data_total as f32 * 100.0
     );
+    tr_println!(
+        "Stored uncompressed as incompressible: {}, {:.1} %",
+        to_file_size(incompressible_total as u64),
+        incompressible_total as f32 / data_total as f32 * 100.0
+    );
     tr_println!("Reclaimable space (depending on vacuum ratio)");
     #[allow(unknown_lints, needless_range_loop)]
     for i in 0..11 {
@@ -489,6 +633,55 @@ fn print_analysis(analysis: &HashMap<u32, BundleAnalysis>) {
     }
 }
 
+fn print_tuning(tuning: &CompressionTuning) {
+    tr_println!("Sample size: {}", to_file_size(tuning.sample_size));
+    tr_println!(
+        "Target speeds: compression {:.1} MiB/s, decompression {:.1} MiB/s",
+        tuning.target_compress_speed,
+        tuning.target_decompress_speed
+    );
+    if let Some(ratio) = tuning.target_ratio {
+        tr_println!("Target size: {:.1}% of the original size", ratio);
+    }
+    for m in &tuning.measurements {
+        let note = if !tuning.reaches_speeds(m) {
+            tr!(" (too slow)")
+        } else if !tuning.reaches_ratio(m) {
+            tr!(" (too large)")
+        } else {
+            ""
+        };
+        tr_println!(
+            "  - {:10} ratio: {:5.1}%, compression: {:>12}, decompression: {:>12}{}",
+            m.compression,
+            (m.size as f32 / tuning.sample_size as f32 - 1.0) * 100.0,
+            to_speed(tuning.sample_size, m.compress_time),
+            to_speed(tuning.sample_size, m.decompress_time),
+            note
+        );
+    }
+    tr_println!(
+        "Selected compression: {}",
+        tuning.selected.as_ref().map(|c| &c[..]).unwrap_or_else(|| tr!("none"))
+    );
+}
+
+fn print_dictionary_training(training: &DictionaryTraining) {
+    tr_println!("Sample size: {}", to_file_size(training.sample_size));
+    tr_println!("Dictionary size: {}", to_file_size(training.dictionary_size as u64));
+    tr_println!("Compression: {}", training.compression.to_string());
+    tr_println!(
+        "  - without dictionary: {}, ratio: {:.1}%",
+        to_file_size(training.size_without),
+        (training.size_without as f32 / training.sample_size as f32 - 1.0) * 100.0
+    );
+    tr_println!(
+        "  - with dictionary:    {}, ratio: {:.1}%",
+        to_file_size(training.size_with),
+        (training.size_with as f32 / training.sample_size as f32 - 1.0) * 100.0
+    );
+}
+
 fn print_duplicates(dups: Vec<(Vec<PathBuf>, u64)>) {
     for (group, size) in dups {
         tr_println!("{} duplicates found, size: {}", group.len(), to_file_size(size));
@@ -522,6 +715,10 @@ pub fn run() -> Result<(), ErrorCode> {
                 tr_error!("The remote path of a repository must be absolute.");
                 return Err(ErrorCode::InvalidArgs);
             }
+            if hash.is_keyed() && !encryption {
+                tr_error!("A keyed hash method requires encryption.");
+                return Err(ErrorCode::InvalidArgs);
+            }
             let mut repo = checked!(
                 Repository::create(
                     repo_path,
@@ -530,7 +727,8 @@ pub fn run() -> Result<(), ErrorCode> {
                         chunker,
                         compression,
                         encryption: None,
-                        hash
+                        hash,
+                        ..Default::default()
                     },
                     remote_path
                 ),
@@ -542,12 +740,17 @@ pub fn run() -> Result<(), ErrorCode> {
                 tr_info!("Created the following key pair");
                 tr_println!("public: {}", to_hex(&public[..]));
                 tr_println!("secret: {}", to_hex(&secret[..]));
-                repo.set_encryption(Some(&public));
                 checked!(
                     repo.register_key(public, secret),
                     "add key",
                     ErrorCode::AddKey
                 );
+                // This also derives the chunker seed as the repository is still empty
+                checked!(
+                    repo.set_encryption(Some(&public)),
+                    "set encryption",
+                    ErrorCode::AddKey
+                );
                 checked!(repo.save_config(), "save config", ErrorCode::SaveConfig);
                 tr_warn!(
                     "Please store this key pair in a secure location before using the repository"
@@ -566,7 +769,8 @@ pub fn run() -> Result<(), ErrorCode> {
             mut excludes,
             excludes_from,
             no_default_excludes,
-            tar
+            tar,
+            no_prune
         } => {
             let mut repo = try!(open_repository(&repo_path, true));
             if repo.has_backup(&backup_name) {
@@ -657,14 +861,14 @@ pub fn run() -> Result<(), ErrorCode> {
             } else {
                 repo.create_backup_recursively(&src_path, reference_backup.as_ref(), &options)
             };
-            let backup = match result {
+            let (backup, complete) = match result {
                 Ok(backup) => {
                     tr_info!("Backup finished");
-                    backup
+                    (backup, true)
                 }
                 Err(RepositoryError::Backup(BackupError::FailedPaths(backup, _failed_paths))) => {
                     tr_warn!("Some files are missing from the backup");
-                    backup
+                    (backup, false)
                 }
                 Err(err) => {
                     tr_error!("Backup failed: {}", err);
@@ -677,16 +881,30 @@ pub fn run() -> Result<(), ErrorCode> {
                 ErrorCode::SaveBackup
             );
             print_backup(&backup);
+            if complete && !no_prune {
+                checked!(
+                    repo.apply_retention_policy(&backup_name),
+                    "apply retention policy",
+                    ErrorCode::PruneRun
+                );
+            }
         }
         Arguments::Restore {
             repo_path,
             backup_name,
             inode,
             dst_path,
-            tar
+            tar,
+            at,
+            host,
+            path
         } => {
             let mut repo = try!(open_repository(&repo_path, true));
-            let backup = try!(get_backup(&repo, &backup_name));
+            let backup = if let Some(at) = at {
+                try!(get_backup_at(&mut repo, &backup_name, host.as_ref(), path.as_ref(), at))
+            } else {
+                try!(get_backup(&repo, &backup_name))
+            };
             let inode = try!(get_inode(&mut repo, &backup, inode.as_ref()));
             if tar {
                 checked!(
@@ -729,13 +947,14 @@ pub fn run() -> Result<(), ErrorCode> {
             repo_path,
             backup_name,
             inode,
-            force
+            force,
+            ignore_locks
         } => {
             let mut repo = try!(open_repository(&repo_path, true));
             if let Some(inode) = inode {
                 let mut backup = try!(get_backup(&repo, &backup_name));
                 checked!(
-                    repo.remove_backup_path(&mut backup, inode),
+                    repo.remove_backup_path(&mut backup, inode, ignore_locks),
                     "remove backup subpath",
                     ErrorCode::RemoveRun
                 );
@@ -751,10 +970,22 @@ pub fn run() -> Result<(), ErrorCode> {
                     "retrieve backups",
                     ErrorCode::RemoveRun
                 );
+                let locked: Vec<_> = backups
+                    .iter()
+                    .filter(|&(_, backup)| backup.is_locked())
+                    .map(|(name, _)| name)
+                    .collect();
+                if force && !ignore_locks && !locked.is_empty() {
+                    tr_error!("Denying to remove pinned or locked backups (use --ignore-locks):");
+                    for name in locked {
+                        println!("  - {}/{}", backup_name, name);
+                    }
+                    return Err(ErrorCode::RemoveRun);
+                }
                 if force {
                     for name in backups.keys() {
                         checked!(
-                            repo.delete_backup(&format!("{}/{}", &backup_name, name)),
+                            repo.delete_backup(&format!("{}/{}", &backup_name, name), true),
                             "delete backup",
                             ErrorCode::RemoveRun
                         );
@@ -767,29 +998,45 @@ pub fn run() -> Result<(), ErrorCode> {
                 }
             } else {
                 checked!(
-                    repo.delete_backup(&backup_name),
+                    repo.delete_backup(&backup_name, ignore_locks),
                     "delete backup",
                     ErrorCode::RemoveRun
                 );
                 tr_info!("The backup has been deleted, run vacuum to reclaim space");
             }
         }
+        Arguments::Protect {
+            repo_path,
+            backup_name,
+            pinned,
+            locked_until,
+            ignore_locks
+        } => {
+            let mut repo = try!(open_repository(&repo_path, false));
+            if !repo.has_backup(&backup_name) {
+                tr_error!("A backup with that name does not exist");
+                return Err(ErrorCode::NoSuchBackup);
+            }
+            let backup = checked!(
+                repo.protect_backup(&backup_name, pinned, locked_until, ignore_locks),
+                "protect backup",
+                ErrorCode::SaveBackup
+            );
+            print_backup_locks(&backup);
+        }
         Arguments::Prune {
             repo_path,
-            prefix,
-            daily,
-            weekly,
-            monthly,
-            yearly,
-            force
+            policy,
+            force,
+            ignore_locks
         } => {
             let mut repo = try!(open_repository(&repo_path, true));
-            if daily + weekly + monthly + yearly == 0 {
+            if policy.keeps_nothing() {
                 tr_error!("This would remove all those backups");
                 return Err(ErrorCode::UnsafeArgs);
             }
             checked!(
-                repo.prune_backups(&prefix, daily, weekly, monthly, yearly, force),
+                repo.prune_backups(&policy, force, ignore_locks),
                 "prune backups",
                 ErrorCode::PruneRun
             );
@@ -801,12 +1048,13 @@ pub fn run() -> Result<(), ErrorCode> {
             repo_path,
             ratio,
             force,
-            combine
+            combine,
+            limits
         } => {
             let mut repo = try!(open_repository(&repo_path, true));
             let info_before = repo.info();
             checked!(
-                repo.vacuum(ratio, combine, force),
+                repo.vacuum(ratio, combine, &limits, force),
                 "vacuum",
                 ErrorCode::VacuumRun
             );
@@ -820,6 +1068,22 @@ pub fn run() -> Result<(), ErrorCode> {
                 );
             }
         }
+        Arguments::Rewrite {
+            repo_path,
+            selection,
+            limits,
+            force
+        } => {
+            let mut repo = try!(open_repository(&repo_path, true));
+            checked!(
+                repo.rewrite(&selection, &limits, force),
+                "rewrite bundles",
+                ErrorCode::RewriteRun
+            );
+            if !force {
+                tr_info!("Run with --force to actually execute this command");
+            }
+        }
         Arguments::Check {
             repo_path,
             backup_name,
@@ -867,6 +1131,13 @@ pub fn run() -> Result<(), ErrorCode> {
                     ErrorCode::CheckRun
                 )
             }
+            if repair {
+                checked!(
+                    repo.recount_references(),
+                    "count chunk references",
+                    ErrorCode::CheckRun
+                );
+            }
             repo.set_clean();
             tr_info!("Integrity verified")
         }
@@ -962,14 +1233,95 @@ pub fn run() -> Result<(), ErrorCode> {
             );
             print_duplicates(dups);
         }
+        Arguments::Deleted {
+            repo_path,
+            prefix,
+            host,
+            path,
+            restore
+        } => {
+            let mut repo = try!(open_repository(&repo_path, true));
+            let series = checked!(
+                repo.get_backup_series(
+                    &prefix,
+                    host.as_ref().map(|v| v as &str),
+                    path.as_ref().map(|v| v as &str)
+                ),
+                "load backups",
+                ErrorCode::LoadBackup
+            );
+            if series.len() < 2 {
+                tr_info!("At least two backups are needed to find deleted files.");
+                return Ok(());
+            }
+            let deleted = checked!(
+                repo.find_deleted(&series),
+                "find deleted files",
+                ErrorCode::DeletedRun
+            );
+            if deleted.is_empty() {
+                tr_info!("No deleted files were found.");
+                return Ok(());
+            }
+            let backups: HashMap<_, _> = series.into_iter().collect();
+            for (path, name, inode) in deleted {
+                let backup = &backups[&name];
+                println!(
+                    "{}\t{}\t{}",
+                    path.display(),
+                    name,
+                    Local.timestamp(backup.timestamp, 0).to_rfc2822()
+                );
+                if let Some(ref dst) = restore {
+                    checked!(
+                        repo.restore_deleted(backup, &path, inode, dst),
+                        "restore deleted file",
+                        ErrorCode::RestoreRun
+                    );
+                }
+            }
+            if restore.is_some() {
+                tr_info!("Restore finished");
+            }
+        }
         Arguments::Mount {
             repo_path,
             backup_name,
             inode,
-            mount_point
+            mount_point,
+            at,
+            host,
+            path
         } => {
             let mut repo = try!(open_repository(&repo_path, true));
-            let fs = if let Some(backup_name) = backup_name {
+            let fs = if let Some(at) = at {
+                let prefix = backup_name.unwrap_or_default();
+                let backup = try!(get_backup_at(
+                    &mut repo,
+                    &prefix,
+                    host.as_ref(),
+                    path.as_ref(),
+                    at
+                ));
+                if let Some(inode) = inode {
+                    let inode = checked!(
+                        repo.get_backup_inode(&backup, inode),
+                        "load subpath inode",
+                        ErrorCode::LoadInode
+                    );
+                    checked!(
+                        FuseFilesystem::from_inode(&mut repo, backup, inode),
+                        "create fuse filesystem",
+                        ErrorCode::FuseMount
+                    )
+                } else {
+                    checked!(
+                        FuseFilesystem::from_backup(&mut repo, backup),
+                        "create fuse filesystem",
+                        ErrorCode::FuseMount
+                    )
+                }
+            } else if let Some(backup_name) = backup_name {
                 if repo.layout.backups_path().join(&backup_name).is_dir() {
                     checked!(
                         FuseFilesystem::from_repository(&mut repo, Some(&backup_name)),
@@ -1023,6 +1375,52 @@ pub fn run() -> Result<(), ErrorCode> {
                 ErrorCode::AnalyzeRun
             ));
         }
+        Arguments::Tune {
+            repo_path,
+            sample_size,
+            speed,
+            decompress_speed,
+            ratio,
+            apply
+        } => {
+            let mut repo = try!(open_repository(&repo_path, true));
+            let targets = TuningTargets {
+                sample_size,
+                compress_speed: speed,
+                decompress_speed,
+                ratio
+            };
+            let tuning = checked!(
+                repo.tune_compression(&targets, apply),
+                "tune compression",
+                ErrorCode::TuneRun
+            );
+            print_tuning(&tuning);
+            if tuning.applied {
+                tr_info!("The configuration has been updated.");
+            }
+            if tuning.apply_refused {
+                tr_warn!("No compression reaches the targets, the configuration has not been changed.");
+            }
+        }
+        Arguments::TrainDict {
+            repo_path,
+            sample_size,
+            dictionary_size
+        } => {
+            let mut repo = try!(open_repository(&repo_path, true));
+            let training = checked!(
+                repo.train_meta_dictionary(sample_size, dictionary_size),
+                "train dictionary",
+                ErrorCode::TrainDictRun
+            );
+            print_dictionary_training(&training);
+            if let Some(id) = training.id {
+                tr_info!("Stored dictionary {}, it will be used for new meta bundles.", id);
+            } else {
+                tr_warn!("The dictionary does not improve the compression, it has not been stored.");
+            }
+        }
         Arguments::BundleList { repo_path } => {
             let repo = try!(open_repository(&repo_path, true));
             for bundle in repo.list_bundles() {
@@ -1044,10 +1442,12 @@ pub fn run() -> Result<(), ErrorCode> {
         Arguments::Import {
             repo_path,
             remote_path,
-            key_files
+            key_files,
+            trust,
+            strict
         } => {
             checked!(
-                Repository::import(repo_path, remote_path, key_files),
+                Repository::import(repo_path, remote_path, key_files, &trust, strict),
                 "import repository",
                 ErrorCode::ImportRun
             );
@@ -1124,7 +1524,12 @@ pub fn run() -> Result<(), ErrorCode> {
             chunker,
             compression,
             encryption,
-            hash
+            hash,
+            set_retention,
+            remove_retention,
+            padding,
+            paranoid,
+            chunk_groups
         } => {
             let mut repo = try!(open_repository(&repo_path, false));
             let mut changed = false;
@@ -1137,6 +1542,11 @@ pub fn run() -> Result<(), ErrorCode> {
                     "Changing the chunker makes it impossible to use existing data for deduplication"
                 );
                 repo.config.chunker = chunker;
+                checked!(
+                    repo.derive_chunker_seed(),
+                    "derive chunker seed",
+                    ErrorCode::LoadKey
+                );
                 changed = true;
             }
             if let Some(compression) = compression {
@@ -1144,14 +1554,53 @@ pub fn run() -> Result<(), ErrorCode> {
                 changed = true;
             }
             if let Some(encryption) = encryption {
-                repo.set_encryption(encryption.as_ref());
+                checked!(
+                    repo.set_encryption(encryption.as_ref()),
+                    "set encryption",
+                    ErrorCode::AddKey
+                );
                 changed = true;
             }
             if let Some(hash) = hash {
                 tr_warn!(
-                    "Changing the hash makes it impossible to use existing data for deduplication"
+                    "Changing the hash makes it impossible to use existing data for deduplication, \
+                     use `zvault rewrite --rehash` to migrate existing backups"
                 );
                 repo.config.hash = hash;
+                if hash.is_keyed() && repo.config.hash_key.is_none() {
+                    checked!(
+                        repo.create_hash_key(),
+                        "create hash key",
+                        ErrorCode::AddKey
+                    );
+                }
+                changed = true;
+            }
+            if let Some((name, policy)) = set_retention {
+                if policy.keeps_nothing() {
+                    tr_error!("This would remove all those backups");
+                    return Err(ErrorCode::UnsafeArgs);
+                }
+                repo.config.retention.insert(name, policy);
+                changed = true;
+            }
+            if let Some(name) = remove_retention {
+                if repo.config.retention.remove(&name).is_none() {
+                    tr_error!("A retention policy with that name does not exist");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                changed = true;
+            }
+            if let Some(padding) = padding {
+                repo.config.bundle_padding = padding;
+                changed = true;
+            }
+            if let Some(paranoid) = paranoid {
+                repo.config.paranoid_dedup = paranoid;
+                changed = true;
+            }
+            if let Some(chunk_groups) = chunk_groups {
+                repo.config.chunk_groups = chunk_groups;
                 changed = true;
             }
             if changed {
@@ -1161,22 +1610,74 @@ pub fn run() -> Result<(), ErrorCode> {
                 print_config(&repo.config);
             }
         }
-        Arguments::GenKey { file, password } => {
+        Arguments::GenKey {
+            file,
+            password,
+            protect
+        } => {
             let (public, secret) = match password {
                 None => Crypto::gen_keypair(),
                 Some(ref password) => Crypto::keypair_from_password(password),
             };
             tr_info!("Created the following key pair");
             tr_println!("public: {}", to_hex(&public[..]));
-            tr_println!("secret: {}", to_hex(&secret[..]));
+            if !protect {
+                tr_println!("secret: {}", to_hex(&secret[..]));
+            }
             if let Some(file) = file {
+                let passphrase = if protect {
+                    Some(checked!(
+                        read_new_passphrase(),
+                        "read passphrase",
+                        ErrorCode::InvalidArgs
+                    ))
+                } else {
+                    None
+                };
                 checked!(
-                    Crypto::save_keypair_to_file(&public, &secret, file),
+                    KeyfileYaml::new(&public, &secret, passphrase.as_ref().map(|p| p as &str))
+                        .save(file),
                     "save key pair",
                     ErrorCode::SaveKey
                 );
             }
         }
+        Arguments::Passphrase {
+            repo_path,
+            keyfile,
+            remove
+        } => {
+            let read_new = || if remove {
+                Ok(None)
+            } else {
+                read_new_passphrase().map(Some)
+            };
+            if let Some(keyfile) = keyfile {
+                let (public, secret) = checked!(
+                    Crypto::load_keypair_from_file(&keyfile),
+                    "load key pair",
+                    ErrorCode::LoadKey
+                );
+                let passphrase = checked!(read_new(), "read passphrase", ErrorCode::InvalidArgs);
+                checked!(
+                    KeyfileYaml::new(&public, &secret, passphrase.as_ref().map(|p| p as &str))
+                        .save(&keyfile),
+                    "save key pair",
+                    ErrorCode::SaveKey
+                );
+            } else if let Some(repo_path) = repo_path {
+                let mut repo = try!(open_repository(&repo_path, false));
+                checked!(repo.unlock_keys(), "unlock keys", ErrorCode::LoadKey);
+                let passphrase = checked!(read_new(), "read passphrase", ErrorCode::InvalidArgs);
+                let passphrase = passphrase.as_ref().map(|p| p as &str);
+                let count = checked!(
+                    repo.change_passphrase(passphrase),
+                    "change passphrase",
+                    ErrorCode::SaveKey
+                );
+                tr_info!("Changed the passphrase of {} keys", count);
+            }
+        }
         Arguments::AddKey {
             repo_path,
             set_default,
@@ -1206,13 +1707,295 @@ pub fn run() -> Result<(), ErrorCode> {
                 ErrorCode::AddKey
             );
             if set_default {
-                repo.set_encryption(Some(&public));
+                checked!(
+                    repo.set_encryption(Some(&public)),
+                    "set encryption",
+                    ErrorCode::AddKey
+                );
                 checked!(repo.save_config(), "save config", ErrorCode::SaveConfig);
                 tr_warn!(
                     "Please store this key pair in a secure location before using the repository"
                 );
             }
         }
+        Arguments::Signing {
+            repo_path,
+            generate,
+            trust,
+            untrust,
+            strict
+        } => {
+            let mut repo = try!(open_repository(&repo_path, false));
+            let changed = generate || !trust.is_empty() || !untrust.is_empty() || strict.is_some();
+            if generate {
+                let public = checked!(
+                    repo.generate_signing_key(),
+                    "generate signing key",
+                    ErrorCode::SaveKey
+                );
+                tr_info!("Created a new signing key pair: {}", to_hex(&public[..]));
+            }
+            for key in trust {
+                repo.trust_signing_key(key);
+            }
+            for key in &untrust {
+                repo.untrust_signing_key(key);
+            }
+            if let Some(strict) = strict {
+                repo.set_strict_signatures(strict);
+            }
+            if changed {
+                checked!(repo.save_config(), "save config", ErrorCode::SaveConfig);
+            }
+            if let Some(public) = repo.get_signing_key() {
+                tr_println!("Signing key: {}", to_hex(&public[..]));
+            } else {
+                tr_println!("Signing key: none");
+            }
+            tr_println!("Trusted keys:");
+            for key in repo.config.get_signing_keys() {
+                println!("  - {}", to_hex(&key[..]));
+            }
+            if repo.config.strict_signatures {
+                tr_println!("Strict mode: enabled");
+            } else {
+                tr_println!("Strict mode: disabled");
+            }
+        }
+        Arguments::Recipients {
+            repo_path,
+            add,
+            remove
+        } => {
+            let mut repo = try!(open_repository(&repo_path, false));
+            let mut recipients = checked!(
+                repo.get_recipients(),
+                "load recipients",
+                ErrorCode::LoadKey
+            );
+            if !add.is_empty() || !remove.is_empty() {
+                for key in add {
+                    if !recipients.contains(&key) {
+                        recipients.push(key);
+                    }
+                }
+                recipients.retain(|key| !remove.contains(key));
+                checked!(
+                    repo.set_recipients(&recipients),
+                    "set recipients",
+                    ErrorCode::AddKey
+                );
+                if !remove.is_empty() {
+                    tr_warn!(
+                        "Removed recipients might still know the data key, use rotatekey to replace it"
+                    );
+                }
+            }
+            for key in &recipients {
+                println!("{}", to_hex(&key[..]));
+            }
+        }
+        Arguments::ListKeys { repo_path, check } => {
+            let repo = try!(open_repository(&repo_path, check));
+            let (keys, unreadable) = checked!(
+                repo.list_keys(check),
+                "list keys",
+                ErrorCode::LoadKey
+            );
+            print_keys(&keys, if check { Some(&unreadable) } else { None });
+        }
+        Arguments::RemoveKey {
+            repo_path,
+            key,
+            force
+        } => {
+            let mut repo = try!(open_repository(&repo_path, false));
+            checked!(
+                repo.remove_key(&key, force),
+                "remove key",
+                ErrorCode::SaveKey
+            );
+            tr_info!("Removed the key {}", Crypto::fingerprint(&key));
+        }
+        Arguments::ExportKey {
+            repo_path,
+            key,
+            file,
+            paper,
+            protect
+        } => {
+            let repo = try!(open_repository(&repo_path, false));
+            let (public, secret) = checked!(repo.export_key(&key), "export key", ErrorCode::LoadKey);
+            if paper {
+                let text = format_paper_key(&public, &secret);
+                if let Some(file) = file {
+                    checked!(
+                        File::create(file).and_then(|mut f| f.write_all(text.as_bytes())),
+                        "save key pair",
+                        ErrorCode::SaveKey
+                    );
+                } else {
+                    print!("{}", text);
+                }
+            } else if let Some(file) = file {
+                let passphrase = if protect {
+                    Some(checked!(
+                        read_new_passphrase(),
+                        "read passphrase",
+                        ErrorCode::InvalidArgs
+                    ))
+                } else {
+                    None
+                };
+                checked!(
+                    KeyfileYaml::new(&public, &secret, passphrase.as_ref().map(|p| p as &str))
+                        .save(file),
+                    "save key pair",
+                    ErrorCode::SaveKey
+                );
+            } else {
+                tr_println!("public: {}", to_hex(&public[..]));
+                tr_println!("secret: {}", to_hex(&secret[..]));
+            }
+        }
+        Arguments::SplitKey {
+            repo_path,
+            key,
+            threshold,
+            shares
+        } => {
+            if threshold > shares {
+                tr_error!("The threshold must not be larger than the number of shares");
+                return Err(ErrorCode::InvalidArgs);
+            }
+            let repo = try!(open_repository(&repo_path, false));
+            let (public, secret) = checked!(repo.export_key(&key), "export key", ErrorCode::LoadKey);
+            let shares = checked!(
+                Crypto::split_secret_key(&public, &secret, threshold, shares),
+                "split key",
+                ErrorCode::SaveKey
+            );
+            tr_info!(
+                "Split key {} into {} shares, {} of them are needed to restore it",
+                Crypto::fingerprint(&public),
+                shares.len(),
+                threshold
+            );
+            for share in shares {
+                println!("{}", share);
+            }
+        }
+        Arguments::CombineKey {
+            file,
+            shares,
+            protect
+        } => {
+            let lines = if shares.is_empty() {
+                let stdin = io::stdin();
+                let lines: Result<Vec<String>, _> = stdin.lock().lines().collect();
+                checked!(lines, "read shares", ErrorCode::InvalidArgs)
+            } else {
+                shares
+            };
+            let mut shares = vec![];
+            for line in lines.iter().filter(|line| !line.trim().is_empty()) {
+                shares.push(checked!(SecretShare::parse(line), "parse share", ErrorCode::InvalidArgs));
+            }
+            let (public, secret) = checked!(
+                Crypto::combine_secret_key(&shares),
+                "restore key",
+                ErrorCode::InvalidArgs
+            );
+            tr_info!("Restored the key {}", Crypto::fingerprint(&public));
+            tr_println!("public: {}", to_hex(&public[..]));
+            let passphrase = if protect {
+                Some(checked!(
+                    read_new_passphrase(),
+                    "read passphrase",
+                    ErrorCode::InvalidArgs
+                ))
+            } else {
+                None
+            };
+            checked!(
+                KeyfileYaml::new(&public, &secret, passphrase.as_ref().map(|p| p as &str)).save(file),
+                "save key pair",
+                ErrorCode::SaveKey
+            );
+        }
+        Arguments::RotateKey {
+            repo_path,
+            keyfile,
+            password,
+            rewrap,
+            limits
+        } => {
+            let mut repo = try!(open_repository(&repo_path, true));
+            // The new key pair is stored in a keyfile before the repository uses it
+            let gen_keypair = |keyfile: Option<String>| -> Result<(PublicKey, SecretKey), ErrorCode> {
+                let keyfile = match keyfile {
+                    Some(keyfile) => keyfile,
+                    None => {
+                        tr_error!("A keyfile for the new key pair is needed (use --keyfile)");
+                        return Err(ErrorCode::InvalidArgs);
+                    }
+                };
+                if Path::new(&keyfile).exists() {
+                    tr_error!("The keyfile already exists");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                let (public, secret) = match password {
+                    None => Crypto::gen_keypair(),
+                    Some(ref password) => Crypto::keypair_from_password(password),
+                };
+                checked!(
+                    Crypto::save_keypair_to_file(&public, &secret, &keyfile),
+                    "save key pair",
+                    ErrorCode::SaveKey
+                );
+                tr_info!("Created a new key pair with the public key {}", to_hex(&public[..]));
+                tr_warn!(
+                    "Please store the keyfile {} in a secure location before using the repository",
+                    keyfile
+                );
+                Ok((public, secret))
+            };
+            if rewrap {
+                if repo.is_rotating_key() {
+                    tr_error!("A key rotation is in progress, please finish it first");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                let (public, secret) = try!(gen_keypair(keyfile));
+                checked!(
+                    repo.rewrap_data_key(public, secret),
+                    "re-wrap data key",
+                    ErrorCode::RotateKeyRun
+                );
+                return Ok(());
+            }
+            if repo.is_rotating_key() {
+                tr_info!("Continuing the unfinished key rotation");
+            } else {
+                if repo.config.encryption.is_none() {
+                    tr_error!("The repository is not encrypted");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                let (public, secret) = try!(gen_keypair(keyfile));
+                checked!(
+                    repo.start_key_rotation(public, secret),
+                    "start key rotation",
+                    ErrorCode::AddKey
+                );
+            }
+            let finished = checked!(
+                repo.continue_key_rotation(&limits),
+                "rotate key",
+                ErrorCode::RotateKeyRun
+            );
+            if finished {
+                tr_info!("Key rotation finished");
+            }
+        }
         Arguments::AlgoTest {
             bundle_size,
             chunker,
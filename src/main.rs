@@ -9,7 +9,10 @@ extern crate rmp_serde;
 #[macro_use]
 extern crate serde_utils;
 extern crate squash_sys as squash;
+extern crate zstd;
 extern crate blake2_rfc as blake2;
+extern crate blake3;
+extern crate sha2;
 extern crate murmurhash3;
 extern crate serde_yaml;
 #[macro_use]
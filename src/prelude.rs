@@ -5,7 +5,9 @@ pub use chunker::{ChunkerType, Chunker, ChunkerStatus, ChunkerError};
 pub use repository::{Repository, Backup, Config, RepositoryError, RepositoryInfo, Inode, FileType,
                      IntegrityError, BackupFileError, BackupError, BackupOptions, BundleAnalysis,
                      FileData, DiffType, InodeError, RepositoryLayout, Location,
-                     RepositoryStatistics};
+                     RepositoryStatistics, RetentionPolicy, VacuumLimits,
+                     RewriteSelection, KeyInfo, UnreadableData, CompressionTuning,
+                     TuningTargets, DictionaryTraining};
 pub use index::{Index, IndexError, IndexStatistics};
 pub use mount::FuseFilesystem;
 pub use translation::CowStr;
@@ -2,7 +2,7 @@ use prelude::*;
 
 use std::fs;
 use std::path::{self, Path, PathBuf};
-use std::collections::{HashMap, BTreeMap, VecDeque};
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet, VecDeque};
 use std::os::linux::fs::MetadataExt;
 
 use chrono::prelude::*;
@@ -22,6 +22,14 @@ quick_error!{
             description(tr!("The root of a backup can not be removed"))
             display("{}", tr_format!("Backup error: the root of a backup can not be removed"))
         }
+        Locked {
+            description(tr!("The backup is pinned or locked"))
+            display("{}", tr_format!("Backup error: the backup is pinned or locked against deletion"))
+        }
+        NoBackupAtDate {
+            description(tr!("No backup exists at or before that date"))
+            display("{}", tr_format!("Backup error: no backup exists at or before that date"))
+        }
     }
 }
 
@@ -39,10 +47,40 @@ pub enum DiffType {
 }
 
 
+/// Selects the versions of a directory entry that make up the merged directory
+///
+/// The directories are ordered from new to old, each with the index of its backup. Older versions
+/// are only used as long as the newer backups have the entry or failed to read it, so deleted
+/// entries are not brought back.
+fn select_child_versions(
+    name: &str,
+    path: &Path,
+    dirs: &[(BTreeMap<String, ChunkList>, usize)],
+    failed_paths: &[HashSet<PathBuf>],
+) -> Vec<(ChunkList, usize)> {
+    let mut versions: Vec<(ChunkList, usize)> = vec![];
+    for &(ref children, backup) in dirs {
+        match children.get(name) {
+            Some(chunks) => {
+                if !versions.iter().any(|v| v.0 == *chunks) {
+                    versions.push((chunks.clone(), backup));
+                }
+            }
+            None => {
+                if !failed_paths[backup].contains(path) {
+                    break;
+                }
+            }
+        }
+    }
+    versions
+}
+
+
 impl Repository {
     pub fn get_all_backups(&self) -> Result<HashMap<String, Backup>, RepositoryError> {
         Ok(try!(Backup::get_all_from(
-            &self.crypto.lock().unwrap(),
+            &mut self.crypto.lock().unwrap(),
             self.layout.backups_path()
         )))
     }
@@ -52,7 +90,7 @@ impl Repository {
         path: P,
     ) -> Result<HashMap<String, Backup>, RepositoryError> {
         Ok(try!(Backup::get_all_from(
-            &self.crypto.lock().unwrap(),
+            &mut self.crypto.lock().unwrap(),
             self.layout.backups_path().join(path)
         )))
     }
@@ -64,7 +102,7 @@ impl Repository {
 
     pub fn get_backup(&self, name: &str) -> Result<Backup, RepositoryError> {
         Ok(try!(Backup::read_from(
-            &self.crypto.lock().unwrap(),
+            &mut self.crypto.lock().unwrap(),
             self.layout.backup_path(name)
         )))
     }
@@ -74,15 +112,23 @@ impl Repository {
         let path = self.layout.backup_path(name);
         try!(fs::create_dir_all(path.parent().unwrap()));
         try!(backup.save_to(
-            &self.crypto.lock().unwrap(),
+            &mut self.crypto.lock().unwrap(),
             self.config.encryption.clone(),
             path
         ));
+        self.refcounts_backup_saved(name, backup);
         Ok(())
     }
 
-    pub fn delete_backup(&mut self, name: &str) -> Result<(), RepositoryError> {
+    pub fn delete_backup(
+        &mut self,
+        name: &str,
+        ignore_locks: bool,
+    ) -> Result<(), RepositoryError> {
         try!(self.write_mode());
+        if !ignore_locks && try!(self.get_backup(name)).is_locked() {
+            return Err(BackupError::Locked.into());
+        }
         let mut path = self.layout.backup_path(name);
         try!(fs::remove_file(&path));
         loop {
@@ -91,94 +137,34 @@ impl Repository {
                 break;
             }
         }
+        self.refcounts_backup_deleted(name);
         Ok(())
     }
 
-
-    pub fn prune_backups(
+    pub fn protect_backup(
         &mut self,
-        prefix: &str,
-        daily: usize,
-        weekly: usize,
-        monthly: usize,
-        yearly: usize,
-        force: bool,
-    ) -> Result<(), RepositoryError> {
-        try!(self.write_mode());
-        let mut backups = Vec::new();
-        let backup_map = match self.get_all_backups() {
-            Ok(backup_map) => backup_map,
-            Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
-                                                                                _failed))) => {
-                tr_warn!("Some backups could not be read, ignoring them");
-                backup_map
-            }
-            Err(err) => return Err(err),
-        };
-        for (name, backup) in backup_map {
-            if name.starts_with(prefix) {
-                let date = Local.timestamp(backup.timestamp, 0);
-                backups.push((name, date, backup));
-            }
-        }
-        backups.sort_by_key(|backup| -backup.2.timestamp);
-        let mut keep = Bitmap::new(backups.len());
-
-        fn mark_needed<K: Eq, F: Fn(&DateTime<Local>) -> K>(
-            backups: &[(String, DateTime<Local>, Backup)],
-            keep: &mut Bitmap,
-            max: usize,
-            keyfn: F,
-        ) {
-            let mut kept = 0;
-            let mut last = None;
-            for (i, backup) in backups.iter().enumerate() {
-                let val = keyfn(&backup.1);
-                let cur = Some(val);
-                if cur != last {
-                    if kept >= max {
-                        break;
-                    }
-                    last = cur;
-                    keep.set(i);
-                    kept += 1;
-                }
-            }
-        }
-        if yearly > 0 {
-            mark_needed(&backups, &mut keep, yearly, |d| d.year());
-        }
-        if monthly > 0 {
-            mark_needed(&backups, &mut keep, monthly, |d| (d.year(), d.month()));
-        }
-        if weekly > 0 {
-            mark_needed(&backups, &mut keep, weekly, |d| {
-                let week = d.iso_week();
-                (week.year(), week.week())
-            });
-        }
-        if daily > 0 {
-            mark_needed(
-                &backups,
-                &mut keep,
-                daily,
-                |d| (d.year(), d.month(), d.day())
-            );
-        }
-        let mut remove = Vec::new();
-        println!("Removing the following backups");
-        for (i, backup) in backups.into_iter().enumerate() {
-            if !keep.get(i) {
-                println!("  - {}", backup.0);
-                remove.push(backup.0);
+        name: &str,
+        pinned: Option<bool>,
+        locked_until: Option<Option<i64>>,
+        ignore_locks: bool,
+    ) -> Result<Backup, RepositoryError> {
+        let mut backup = try!(self.get_backup(name));
+        if let Some(until) = locked_until {
+            // An active lock can only be extended
+            if !ignore_locks && backup.is_lock_active() && until < backup.locked_until {
+                return Err(BackupError::Locked.into());
             }
+            backup.locked_until = until;
         }
-        if force {
-            for name in remove {
-                try!(self.delete_backup(&name));
+        if let Some(pinned) = pinned {
+            // A pin can only be removed like an active lock
+            if !ignore_locks && backup.pinned && !pinned {
+                return Err(BackupError::Locked.into());
             }
+            backup.pinned = pinned;
         }
-        Ok(())
+        try!(self.save_backup(&backup, name));
+        Ok(backup)
     }
 
     pub fn restore_inode_tree<P: AsRef<Path>>(
@@ -342,6 +328,7 @@ impl Repository {
         backup.config = self.config.clone();
         backup.host = get_hostname().unwrap_or_else(|_| "".to_string());
         backup.path = path.as_ref().to_string_lossy().to_string();
+        let root_path = path.as_ref().to_path_buf();
         let info_before = self.info();
         let start = Local::now();
         let mut failed_paths = vec![];
@@ -353,6 +340,10 @@ impl Repository {
             &mut failed_paths
         ));
         backup.root = try!(self.put_inode(&root_inode));
+        backup.failed_paths = failed_paths
+            .iter()
+            .map(|path| path.strip_prefix(&root_path).unwrap_or(path).to_string_lossy().to_string())
+            .collect();
         try!(self.flush());
         let elapsed = Local::now().signed_duration_since(start);
         backup.timestamp = start.timestamp();
@@ -381,8 +372,12 @@ impl Repository {
         &mut self,
         backup: &mut Backup,
         path: P,
+        ignore_locks: bool,
     ) -> Result<(), RepositoryError> {
         try!(self.write_mode());
+        if !ignore_locks && backup.is_locked() {
+            return Err(BackupError::Locked.into());
+        }
         let _lock = try!(self.lock(false));
         let mut inodes = try!(self.get_backup_path(backup, path));
         let to_remove = inodes.pop().unwrap();
@@ -475,6 +470,228 @@ impl Repository {
         Ok(versions)
     }
 
+    pub fn get_backup_series(
+        &self,
+        prefix: &str,
+        host: Option<&str>,
+        path: Option<&str>,
+    ) -> Result<Vec<(String, Backup)>, RepositoryError> {
+        let backup_map = match self.get_all_backups() {
+            Ok(backup_map) => backup_map,
+            Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
+                                                                                _failed))) => {
+                tr_warn!("Some backups could not be read, ignoring them");
+                backup_map
+            }
+            Err(err) => return Err(err),
+        };
+        let mut series: Vec<_> = backup_map
+            .into_iter()
+            .filter(|&(ref name, ref backup)| {
+                name.starts_with(prefix) && host.map_or(true, |h| backup.host == h) &&
+                    path.map_or(true, |p| backup.path == p)
+            })
+            .collect();
+        series.sort_by_key(|&(_, ref b)| b.timestamp);
+        Ok(series)
+    }
+
+    #[allow(needless_pass_by_value)]
+    fn find_deleted_recurse(
+        &mut self,
+        old_inode: &Inode,
+        new_inode: &Inode,
+        path: PathBuf,
+        backup_name: &str,
+        deleted: &mut HashMap<PathBuf, (String, ChunkList)>,
+    ) -> Result<(), RepositoryError> {
+        let old_children = match old_inode.children {
+            Some(ref children) => children,
+            None => return Ok(()),
+        };
+        for (name, old_chunks) in old_children {
+            let child_path = path.join(name);
+            if let Some(new_chunks) = new_inode.children.as_ref().and_then(|c| c.get(name)) {
+                if old_chunks == new_chunks {
+                    continue;
+                }
+                let old_child = try!(self.get_inode(old_chunks));
+                if old_child.file_type != FileType::Directory {
+                    continue;
+                }
+                let new_child = try!(self.get_inode(new_chunks));
+                try!(self.find_deleted_recurse(
+                    &old_child,
+                    &new_child,
+                    child_path,
+                    backup_name,
+                    deleted
+                ));
+                continue;
+            }
+            // Already seen in a newer backup, its contents are restored with it, so entries that
+            // vanished before that are not reported separately
+            if !deleted.contains_key(&child_path) {
+                deleted.insert(child_path, (backup_name.to_string(), old_chunks.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn find_deleted(
+        &mut self,
+        series: &[(String, Backup)],
+    ) -> Result<Vec<(PathBuf, String, Inode)>, RepositoryError> {
+        let mut deleted = HashMap::new();
+        let (latest, older) = match series.split_last() {
+            Some(split) => split,
+            None => return Ok(vec![]),
+        };
+        let latest_inode = try!(self.get_inode(&latest.1.root));
+        for &(ref name, ref backup) in older.iter().rev() {
+            let inode = try!(self.get_inode(&backup.root));
+            try!(self.find_deleted_recurse(
+                &inode,
+                &latest_inode,
+                PathBuf::from("/"),
+                name,
+                &mut deleted
+            ));
+        }
+        let mut result = Vec::with_capacity(deleted.len());
+        for (path, (name, chunks)) in deleted {
+            let inode = try!(self.get_inode(&chunks));
+            result.push((path, name, inode));
+        }
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(result)
+    }
+
+    pub fn restore_deleted<P: AsRef<Path>>(
+        &mut self,
+        backup: &Backup,
+        path: &Path,
+        inode: Inode,
+        dst: P,
+    ) -> Result<(), RepositoryError> {
+        let relpath = path.strip_prefix("/").unwrap_or(path);
+        let dst = if inode.file_type == FileType::Directory {
+            dst.as_ref().join(relpath)
+        } else {
+            dst.as_ref().join(relpath.parent().unwrap_or_else(|| Path::new("")))
+        };
+        try!(fs::create_dir_all(&dst));
+        self.restore_inode_tree(backup, inode, dst)
+    }
+
+    fn merge_versions_recurse(
+        &mut self,
+        path: &Path,
+        versions: &[(ChunkList, usize)],
+        failed_paths: &[HashSet<PathBuf>],
+    ) -> Result<Inode, RepositoryError> {
+        let mut inode = try!(self.get_inode(&versions[0].0));
+        if inode.file_type != FileType::Directory || versions.len() == 1 {
+            return Ok(inode);
+        }
+        let mut dirs = vec![(inode.children.take().unwrap_or_default(), versions[0].1)];
+        for &(ref chunks, backup) in &versions[1..] {
+            let old_inode = try!(self.get_inode(chunks));
+            // Only older directories at the same path can contribute children
+            if old_inode.file_type != FileType::Directory {
+                break;
+            }
+            dirs.push((old_inode.children.unwrap_or_default(), backup));
+        }
+        let names: BTreeSet<String> = dirs.iter().flat_map(|dir| dir.0.keys().cloned()).collect();
+        let mut children = BTreeMap::new();
+        for name in names {
+            let child_versions = select_child_versions(&name, &path.join(&name), &dirs, failed_paths);
+            if !child_versions.is_empty() {
+                children.insert(name, child_versions);
+            }
+        }
+        let mut meta_size = 0;
+        inode.cum_size = inode.size;
+        inode.cum_dirs = 1;
+        inode.cum_files = 0;
+        let mut merged = BTreeMap::new();
+        for (name, child_versions) in children {
+            let (chunks, child_inode) = if child_versions.len() == 1 {
+                let child_inode = try!(self.get_inode(&child_versions[0].0));
+                (child_versions.into_iter().next().unwrap().0, child_inode)
+            } else {
+                let child_path = path.join(&name);
+                let child_inode = try!(self.merge_versions_recurse(
+                    &child_path,
+                    &child_versions,
+                    failed_paths
+                ));
+                (try!(self.put_virtual_inode(&child_inode)), child_inode)
+            };
+            inode.cum_size += child_inode.cum_size;
+            for &(_, len) in chunks.iter() {
+                meta_size += u64::from(len);
+            }
+            inode.cum_dirs += child_inode.cum_dirs;
+            inode.cum_files += child_inode.cum_files;
+            merged.insert(name, chunks);
+        }
+        inode.children = Some(merged);
+        inode.cum_size += meta_size;
+        Ok(inode)
+    }
+
+    /// Combines the backups of a series into the state of the files at the given time
+    ///
+    /// Files are only taken from older backups if the newer backups failed to read them, files
+    /// that have been deleted stay deleted. The merged directories are only kept in memory, so the backup can be restored or mounted
+    /// but must not be saved.
+    pub fn create_backup_at(
+        &mut self,
+        series: &[(String, Backup)],
+        timestamp: i64,
+    ) -> Result<Backup, RepositoryError> {
+        let mut backups: Vec<&Backup> = series
+            .iter()
+            .map(|&(_, ref backup)| backup)
+            .filter(|backup| backup.timestamp <= timestamp)
+            .collect();
+        backups.sort_by_key(|backup| -backup.timestamp);
+        let mut versions: Vec<(ChunkList, usize)> = Vec::with_capacity(backups.len());
+        for (i, backup) in backups.iter().enumerate() {
+            if !versions.iter().any(|v| v.0 == backup.root) {
+                versions.push((backup.root.clone(), i));
+            }
+        }
+        let failed_paths: Vec<HashSet<PathBuf>> = backups
+            .iter()
+            .map(|backup| backup.failed_paths.iter().map(PathBuf::from).collect())
+            .collect();
+        let mut backup = match backups.first() {
+            Some(backup) => (*backup).clone(),
+            None => return Err(BackupError::NoBackupAtDate.into()),
+        };
+        for old in backups.iter().skip(1) {
+            for (uid, name) in &old.user_names {
+                backup.user_names.entry(*uid).or_insert_with(|| name.clone());
+            }
+            for (gid, name) in &old.group_names {
+                backup.group_names.entry(*gid).or_insert_with(|| name.clone());
+            }
+        }
+        let root_inode = try!(self.merge_versions_recurse(Path::new(""), &versions, &failed_paths));
+        backup.root = try!(self.put_virtual_inode(&root_inode));
+        backup.total_data_size = root_inode.cum_size;
+        for &(_, len) in backup.root.iter() {
+            backup.total_data_size += u64::from(len);
+        }
+        backup.file_count = root_inode.cum_files;
+        backup.dir_count = root_inode.cum_dirs;
+        backup.modified = true;
+        Ok(backup)
+    }
+
     #[allow(needless_pass_by_value)]
     fn find_differences_recurse(
         &mut self,
@@ -588,3 +805,65 @@ impl Repository {
         Ok(dups)
     }
 }
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn chunks(n: u64) -> ChunkList {
+        let mut list = ChunkList::new();
+        list.push((Hash { high: 0, low: n }, 10));
+        list
+    }
+
+    #[allow(dead_code)]
+    fn dir(entries: &[(&str, u64)], backup: usize) -> (BTreeMap<String, ChunkList>, usize) {
+        (entries.iter().map(|&(name, n)| (name.to_string(), chunks(n))).collect(), backup)
+    }
+
+    #[test]
+    fn test_select_child_versions_deleted() {
+        // The file was deleted between the backups
+        let dirs = vec![dir(&[("kept", 1)], 0), dir(&[("kept", 1), ("deleted", 2)], 1)];
+        let failed = vec![HashSet::new(), HashSet::new()];
+        assert!(select_child_versions("deleted", Path::new("deleted"), &dirs, &failed).is_empty());
+        assert_eq!(
+            select_child_versions("kept", Path::new("kept"), &dirs, &failed),
+            vec![(chunks(1), 0)]
+        );
+    }
+
+    #[test]
+    fn test_select_child_versions_failed() {
+        // The newer backup failed to read the file
+        let dirs = vec![dir(&[("kept", 1)], 0), dir(&[("kept", 1), ("failed", 2)], 1)];
+        let mut failed = vec![HashSet::new(), HashSet::new()];
+        failed[0].insert(PathBuf::from("dir/failed"));
+        assert_eq!(
+            select_child_versions("failed", Path::new("dir/failed"), &dirs, &failed),
+            vec![(chunks(2), 1)]
+        );
+    }
+
+    #[test]
+    fn test_select_child_versions_deleted_before_failure() {
+        // The file was deleted before the backup that failed to read it
+        let dirs = vec![dir(&[], 0), dir(&[], 1), dir(&[("file", 3)], 2)];
+        let mut failed = vec![HashSet::new(), HashSet::new(), HashSet::new()];
+        failed[0].insert(PathBuf::from("file"));
+        assert!(select_child_versions("file", Path::new("file"), &dirs, &failed).is_empty());
+    }
+
+    #[test]
+    fn test_select_child_versions_changed() {
+        let dirs = vec![dir(&[("file", 1)], 0), dir(&[("file", 2)], 1), dir(&[("file", 1)], 2)];
+        let failed = vec![HashSet::new(), HashSet::new(), HashSet::new()];
+        assert_eq!(
+            select_child_versions("file", Path::new("file"), &dirs, &failed),
+            vec![(chunks(1), 0), (chunks(2), 1)]
+        );
+    }
+}
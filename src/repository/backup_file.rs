@@ -5,6 +5,9 @@ use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 
+use serde_bytes::ByteBuf;
+use chrono::prelude::*;
+
 
 static HEADER_STRING: [u8; 7] = *b"zvault\x03";
 static HEADER_VERSION: u8 = 1;
@@ -49,6 +52,11 @@ quick_error!{
             description(tr!("Decryption failed"))
             display("{}", tr_format!("Backup file error: decryption failed on backup {:?}\n\tcaused by: {}", path, err))
         }
+        Signature(err: EncryptionError, path: PathBuf) {
+            cause(err)
+            description(tr!("Signature verification failed"))
+            display("{}", tr_format!("Backup file error: signature verification failed on backup {:?}\n\tcaused by: {}", path, err))
+        }
         Encryption(err: EncryptionError) {
             from()
             cause(err)
@@ -64,10 +72,12 @@ quick_error!{
 
 #[derive(Default, Debug, Clone)]
 struct BackupHeader {
-    pub encryption: Option<Encryption>
+    pub encryption: Option<Encryption>,
+    pub signature: Option<ByteBuf>
 }
-serde_impl!(BackupHeader(u8) {
-    encryption: Option<Encryption> => 0
+serde_impl!(BackupHeader(u8?) {
+    encryption: Option<Encryption> => 0,
+    signature: Option<ByteBuf> => 1
 });
 
 
@@ -90,7 +100,10 @@ pub struct Backup {
     pub config: Config,
     pub modified: bool,
     pub user_names: HashMap<u32, String>,
-    pub group_names: HashMap<u32, String>
+    pub group_names: HashMap<u32, String>,
+    pub pinned: bool,
+    pub locked_until: Option<i64>,
+    pub failed_paths: Vec<String> // Paths relative to the root that could not be backed up
 }
 serde_impl!(Backup(u8?) {
     root: ChunkList => 0,
@@ -110,12 +123,24 @@ serde_impl!(Backup(u8?) {
     config: Config => 14,
     modified: bool => 15,
     user_names: HashMap<u32, String> => 16,
-    group_names: HashMap<u32, String> => 17
+    group_names: HashMap<u32, String> => 17,
+    pinned: bool => 18,
+    locked_until: Option<i64> => 19,
+    failed_paths: Vec<String> => 20
 });
 
 impl Backup {
-    pub fn read_from<P: AsRef<Path>>(crypto: &Crypto, path: P) -> Result<Self, BackupFileError> {
-        let path = path.as_ref();
+    #[inline]
+    pub fn is_lock_active(&self) -> bool {
+        self.locked_until.map_or(false, |until| until > Local::now().timestamp())
+    }
+
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.pinned || self.is_lock_active()
+    }
+
+    fn read_header(path: &Path) -> Result<(BufReader<File>, BackupHeader), BackupFileError> {
         let mut file = BufReader::new(try!(File::open(path).map_err(|err| {
             BackupFileError::Read(err, path.to_path_buf())
         })));
@@ -134,10 +159,27 @@ impl Backup {
             ));
         }
         let header: BackupHeader = try!(msgpack::decode_from_stream(&mut file).context(path));
+        Ok((file, header))
+    }
+
+    /// Returns the encryption of a backup file without decrypting it
+    pub fn get_encryption_from<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<Option<Encryption>, BackupFileError> {
+        let (_file, header) = try!(Self::read_header(path.as_ref()));
+        Ok(header.encryption)
+    }
+
+    pub fn read_from<P: AsRef<Path>>(crypto: &mut Crypto, path: P) -> Result<Self, BackupFileError> {
+        let path = path.as_ref();
+        let (mut file, header) = try!(Self::read_header(path));
         let mut data = Vec::new();
         try!(file.read_to_end(&mut data).map_err(|err| {
             BackupFileError::Read(err, path.to_path_buf())
         }));
+        try!(crypto.verify(&data, header.signature.as_ref().map(|s| &s[..])).map_err(|err| {
+            BackupFileError::Signature(err, path.to_path_buf())
+        }));
         if let Some(ref encryption) = header.encryption {
             data = try!(crypto.decrypt(encryption, &data));
         }
@@ -146,7 +188,7 @@ impl Backup {
 
     pub fn save_to<P: AsRef<Path>>(
         &self,
-        crypto: &Crypto,
+        crypto: &mut Crypto,
         encryption: Option<Encryption>,
         path: P,
     ) -> Result<(), BackupFileError> {
@@ -164,7 +206,10 @@ impl Backup {
         try!(file.write_all(&[HEADER_VERSION]).map_err(|err| {
             BackupFileError::Write(err, path.to_path_buf())
         }));
-        let header = BackupHeader { encryption };
+        let header = BackupHeader {
+            signature: try!(crypto.sign(&data)),
+            encryption
+        };
         try!(msgpack::encode_to_stream(&header, &mut file).context(path));
         try!(file.write_all(&data).map_err(|err| {
             BackupFileError::Write(err, path.to_path_buf())
@@ -173,7 +218,7 @@ impl Backup {
     }
 
     pub fn get_all_from<P: AsRef<Path>>(
-        crypto: &Crypto,
+        crypto: &mut Crypto,
         path: P,
     ) -> Result<HashMap<String, Backup>, BackupFileError> {
         let mut backups = HashMap::new();
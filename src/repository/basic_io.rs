@@ -1,4 +1,5 @@
 use prelude::*;
+use super::{VERIFIED_CHUNKS_MAGIC, VERIFIED_CHUNKS_VERSION};
 
 use std::mem;
 use std::cmp::min;
@@ -6,6 +7,27 @@ use std::collections::VecDeque;
 use std::io::{self, Read, Write, Cursor};
 
 
+/// The bundles that are written at the same time
+///
+/// Incompressible data chunks are collected in separate bundles that are stored uncompressed.
+#[derive(Clone, Copy, PartialEq)]
+enum BundleSlot {
+    Data,
+    Meta,
+    Raw
+}
+
+impl BundleSlot {
+    #[inline]
+    fn mode(&self) -> BundleMode {
+        match *self {
+            BundleSlot::Data | BundleSlot::Raw => BundleMode::Data,
+            BundleSlot::Meta => BundleMode::Meta,
+        }
+    }
+}
+
+
 pub struct ChunkReader<'a> {
     chunks: VecDeque<Chunk>,
     data: Vec<u8>,
@@ -67,6 +89,9 @@ impl Repository {
     }
 
     pub fn get_chunk(&mut self, hash: Hash) -> Result<Option<Vec<u8>>, RepositoryError> {
+        if let Some(data) = self.virtual_chunks.get(&hash) {
+            return Ok(Some(data.clone()));
+        }
         // Find bundle and chunk id in index
         let found = if let Some(found) = self.index.get(&hash) {
             found
@@ -81,68 +106,165 @@ impl Repository {
         )))
     }
 
-    #[inline]
+    /// Stores the chunk unless it is already in the index and returns the hash it is stored under
+    ///
+    /// In paranoid mode, the stored chunk is compared to the data first. If they differ, the
+    /// chunk is stored under an alternative hash instead.
     pub fn put_chunk(
         &mut self,
         mode: BundleMode,
         hash: Hash,
         data: &[u8],
-    ) -> Result<(), RepositoryError> {
+    ) -> Result<Hash, RepositoryError> {
         // If this chunk is in the index, ignore it
-        if self.index.contains(&hash) {
-            return Ok(());
+        if !self.index.contains(&hash) {
+            try!(self.put_chunk_override(mode, hash, data));
+            return Ok(hash);
+        }
+        if !self.config.paranoid_dedup || try!(self.is_same_chunk(hash, data)) {
+            return Ok(hash);
         }
-        self.put_chunk_override(mode, hash, data)
+        let hash_key = try!(self.get_hash_key());
+        for attempt in 1..MAX_HASH_ATTEMPTS + 1 {
+            let alt_hash = self.config.hash.hash_attempt(&hash_key, data, attempt);
+            if !self.index.contains(&alt_hash) {
+                tr_warn!("Hash collision on chunk {}, storing it as {}", hash, alt_hash);
+                try!(self.put_chunk_override(mode, alt_hash, data));
+                return Ok(alt_hash);
+            }
+            if try!(self.is_same_chunk(alt_hash, data)) {
+                return Ok(alt_hash);
+            }
+        }
+        Err(IntegrityError::HashCollision(hash).into())
+    }
+
+    /// Returns the table of strong hashes of chunks that have been written or compared before
+    ///
+    /// The table is a local cache that is kept between runs. It is only opened when paranoid
+    /// deduplication needs it and recreated if it can not be read.
+    fn get_verified_chunks(&mut self) -> Result<&mut Index<Hash, Hash>, RepositoryError> {
+        if self.verified_chunks.is_none() {
+            let path = self.layout.verified_chunks_path();
+            let index = match unsafe {
+                Index::open(&path, &VERIFIED_CHUNKS_MAGIC, VERIFIED_CHUNKS_VERSION)
+            } {
+                Ok(index) => index,
+                Err(err) => {
+                    if path.exists() {
+                        tr_warn!(
+                            "Failed to load verified chunks, recreating them\n\tcaused by: {}",
+                            err
+                        );
+                    }
+                    try!(Index::create(&path, &VERIFIED_CHUNKS_MAGIC, VERIFIED_CHUNKS_VERSION))
+                }
+            };
+            self.verified_chunks = Some(index);
+        }
+        Ok(self.verified_chunks.as_mut().unwrap())
+    }
+
+    /// Compares the data with the chunk stored under that hash
+    ///
+    /// Chunks that have been written or compared before are only compared by a strong hash to
+    /// avoid reading them again.
+    fn is_same_chunk(&mut self, hash: Hash, data: &[u8]) -> Result<bool, RepositoryError> {
+        let strong_hash = HashMethod::Blake2.hash(data);
+        if let Some(stored) = try!(self.get_verified_chunks()).get(&hash) {
+            return Ok(stored == strong_hash);
+        }
+        let stored = try!(try!(self.get_chunk(hash)).ok_or_else(|| {
+            IntegrityError::MissingChunk(hash)
+        }));
+        let stored_hash = HashMethod::Blake2.hash(&stored);
+        try!(try!(self.get_verified_chunks()).set(&hash, &stored_hash));
+        Ok(stored == data)
     }
 
     fn write_chunk_to_bundle_and_index(
         &mut self,
-        mode: BundleMode,
+        slot: BundleSlot,
         hash: Hash,
         data: &[u8],
     ) -> Result<(), RepositoryError> {
-        let writer = match mode {
-            BundleMode::Data => &mut self.data_bundle,
-            BundleMode::Meta => &mut self.meta_bundle,
+        let chunk_id = {
+            let writer = match slot {
+                BundleSlot::Data => &mut self.data_bundle,
+                BundleSlot::Meta => &mut self.meta_bundle,
+                BundleSlot::Raw => &mut self.raw_bundle,
+            };
+            // ...alocate one if needed
+            if writer.is_none() {
+                let mut dictionary = None;
+                if let (BundleSlot::Meta, Some(id)) = (slot, self.config.meta_dictionary) {
+                    dictionary = self.bundles.get_dictionary(&id).map(|data| (id, data));
+                    if dictionary.is_none() {
+                        tr_warn!("Meta dictionary {} is not available, compressing without it", id);
+                    }
+                }
+                let compression = if slot == BundleSlot::Raw {
+                    None
+                } else if dictionary.is_some() {
+                    self.config.get_dictionary_compression()
+                } else {
+                    self.config.compression.clone()
+                };
+                let mut new_writer = try!(self.bundles.create_bundle(
+                    slot.mode(),
+                    self.config.hash,
+                    compression,
+                    self.config.encryption.clone(),
+                    self.config.get_bundle_padding()
+                ));
+                if slot == BundleSlot::Raw {
+                    new_writer.set_incompressible();
+                }
+                if self.config.chunk_groups {
+                    new_writer.set_chunk_groups();
+                }
+                if let Some((id, data)) = dictionary {
+                    try!(new_writer.set_dictionary(id, &data));
+                }
+                *writer = Some(new_writer);
+            }
+            debug_assert!(writer.is_some());
+            // Add chunk to bundle writer and determine the size of the bundle
+            let writer_obj = writer.as_mut().unwrap();
+            try!(writer_obj.add(data, hash))
         };
-        // ...alocate one if needed
-        if writer.is_none() {
-            *writer = Some(try!(self.bundles.create_bundle(
-                mode,
-                self.config.hash,
-                self.config.compression.clone(),
-                self.config.encryption.clone()
-            )));
-        }
-        debug_assert!(writer.is_some());
-        // Add chunk to bundle writer and determine the size of the bundle
-        let writer_obj = writer.as_mut().unwrap();
-        let chunk_id = try!(writer_obj.add(data, hash));
-        let bundle_id = match mode {
-            BundleMode::Data => self.next_data_bundle,
-            BundleMode::Meta => self.next_meta_bundle,
+        let bundle_id = match slot {
+            BundleSlot::Data => self.next_data_bundle,
+            BundleSlot::Meta => self.next_meta_bundle,
+            BundleSlot::Raw => self.next_raw_bundle,
         };
         // Add location to the index
         try!(self.index.set(
             &hash,
             &Location::new(bundle_id, chunk_id as u32)
         ));
+        if self.config.paranoid_dedup {
+            let strong_hash = HashMethod::Blake2.hash(data);
+            try!(try!(self.get_verified_chunks()).set(&hash, &strong_hash));
+        }
         Ok(())
     }
 
-    fn finish_bundle(&mut self, mode: BundleMode) -> Result<(), RepositoryError> {
+    fn finish_bundle(&mut self, slot: BundleSlot) -> Result<(), RepositoryError> {
         // Calculate the next free bundle id now (late lifetime prevents this)
         let next_free_bundle_id = self.next_free_bundle_id();
-        let writer = match mode {
-            BundleMode::Data => &mut self.data_bundle,
-            BundleMode::Meta => &mut self.meta_bundle,
+        let writer = match slot {
+            BundleSlot::Data => &mut self.data_bundle,
+            BundleSlot::Meta => &mut self.meta_bundle,
+            BundleSlot::Raw => &mut self.raw_bundle,
         };
         if writer.is_none() {
             return Ok(());
         }
-        let bundle_id = match mode {
-            BundleMode::Data => self.next_data_bundle,
-            BundleMode::Meta => self.next_meta_bundle,
+        let bundle_id = match slot {
+            BundleSlot::Data => self.next_data_bundle,
+            BundleSlot::Meta => self.next_meta_bundle,
+            BundleSlot::Raw => self.next_raw_bundle,
         };
         let mut finished = None;
         mem::swap(writer, &mut finished);
@@ -154,14 +276,28 @@ impl Repository {
         if self.next_data_bundle == bundle_id {
             self.next_data_bundle = next_free_bundle_id
         }
+        if self.next_raw_bundle == bundle_id {
+            self.next_raw_bundle = next_free_bundle_id
+        }
         Ok(())
     }
 
-    fn finish_bundle_if_needed(&mut self, mode: BundleMode) -> Result<(), RepositoryError> {
+    /// Finishes all open bundles
+    ///
+    /// The data bundles are finished first as the meta bundle refers to their chunks.
+    pub fn finish_bundles(&mut self) -> Result<(), RepositoryError> {
+        for slot in &[BundleSlot::Data, BundleSlot::Raw, BundleSlot::Meta] {
+            try!(self.finish_bundle(*slot));
+        }
+        Ok(())
+    }
+
+    fn finish_bundle_if_needed(&mut self, slot: BundleSlot) -> Result<(), RepositoryError> {
         let (size, raw_size) = {
-            let writer = match mode {
-                BundleMode::Data => &mut self.data_bundle,
-                BundleMode::Meta => &mut self.meta_bundle,
+            let writer = match slot {
+                BundleSlot::Data => &mut self.data_bundle,
+                BundleSlot::Meta => &mut self.meta_bundle,
+                BundleSlot::Raw => &mut self.raw_bundle,
             };
             if let Some(ref writer) = *writer {
                 (writer.estimate_final_size(), writer.raw_size())
@@ -170,11 +306,12 @@ impl Repository {
             }
         };
         if size >= self.config.bundle_size || raw_size >= 4 * self.config.bundle_size {
-            if mode == BundleMode::Meta {
-                //First store the current data bundle as meta referrs to those chunks
-                try!(self.finish_bundle(BundleMode::Data))
+            if slot == BundleSlot::Meta {
+                //First store the current data bundles as meta referrs to those chunks
+                try!(self.finish_bundle(BundleSlot::Data));
+                try!(self.finish_bundle(BundleSlot::Raw))
             }
-            try!(self.finish_bundle(mode))
+            try!(self.finish_bundle(slot))
         }
         Ok(())
     }
@@ -186,8 +323,16 @@ impl Repository {
         hash: Hash,
         data: &[u8],
     ) -> Result<(), RepositoryError> {
-        try!(self.write_chunk_to_bundle_and_index(mode, hash, data));
-        self.finish_bundle_if_needed(mode)
+        let slot = match mode {
+            BundleMode::Meta => BundleSlot::Meta,
+            // Compressing incompressible data only wastes time
+            BundleMode::Data if self.config.compression.is_some() && is_incompressible(data) => {
+                BundleSlot::Raw
+            }
+            BundleMode::Data => BundleSlot::Data,
+        };
+        try!(self.write_chunk_to_bundle_and_index(slot, hash, data));
+        self.finish_bundle_if_needed(slot)
     }
 
     #[inline]
@@ -206,6 +351,7 @@ impl Repository {
         data: &mut R,
     ) -> Result<ChunkList, RepositoryError> {
         let avg_size = self.config.chunker.avg_size();
+        let hash_key = try!(self.get_hash_key());
         let mut chunks = Vec::new();
         let mut chunk = Vec::with_capacity(avg_size * 2);
         loop {
@@ -213,8 +359,8 @@ impl Repository {
             let mut output = Cursor::new(chunk);
             let res = try!(self.chunker.chunk(data, &mut output));
             chunk = output.into_inner();
-            let hash = self.config.hash.hash(&chunk);
-            try!(self.put_chunk(mode, hash, &chunk));
+            let hash = self.config.hash.hash_keyed(&hash_key, &chunk);
+            let hash = try!(self.put_chunk(mode, hash, &chunk));
             chunks.push((hash, chunk.len() as u32));
             if res == ChunkerStatus::Finished {
                 break;
@@ -223,6 +369,30 @@ impl Repository {
         Ok(chunks.into())
     }
 
+    /// Chunks the data like `put_data` but only keeps the new chunks in memory
+    ///
+    /// The returned chunk list can be read like stored data as long as the repository is open.
+    pub fn put_virtual_data(&mut self, data: &[u8]) -> Result<ChunkList, RepositoryError> {
+        let avg_size = self.config.chunker.avg_size();
+        let hash_key = try!(self.get_hash_key());
+        let mut input = Cursor::new(data);
+        let mut chunks = Vec::new();
+        loop {
+            let mut output = Cursor::new(Vec::with_capacity(avg_size * 2));
+            let res = try!(self.chunker.chunk(&mut input, &mut output));
+            let chunk = output.into_inner();
+            let hash = self.config.hash.hash_keyed(&hash_key, &chunk);
+            chunks.push((hash, chunk.len() as u32));
+            if !self.index.contains(&hash) {
+                self.virtual_chunks.insert(hash, chunk);
+            }
+            if res == ChunkerStatus::Finished {
+                break;
+            }
+        }
+        Ok(chunks.into())
+    }
+
     pub fn get_data(&mut self, chunks: &[Chunk]) -> Result<Vec<u8>, RepositoryError> {
         let mut data =
             Vec::with_capacity(chunks.iter().map(|&(_, size)| size).sum::<u32>() as usize);
@@ -1,10 +1,17 @@
 use prelude::*;
+use bundledb::PADDING_MIN_FRACTION;
 
 use serde_yaml;
+use serde_bytes::ByteBuf;
 
 use std::fs::File;
 use std::path::Path;
 use std::io;
+use std::collections::BTreeMap;
+
+
+/// Compression of meta bundles with a dictionary if the configured method does not support it
+static DICTIONARY_COMPRESSION: &'static str = "zstd/9";
 
 
 quick_error!{
@@ -121,12 +128,144 @@ serde_impl!(EncryptionYaml(String) {
 
 
 
+struct RetentionPolicyYaml {
+    prefix: String,
+    last: usize,
+    hourly: usize,
+    daily: usize,
+    weekly: usize,
+    monthly: usize,
+    yearly: usize,
+    within: Option<String>,
+    hourly_within: Option<String>,
+    daily_within: Option<String>,
+    weekly_within: Option<String>,
+    monthly_within: Option<String>,
+    yearly_within: Option<String>,
+    group_by_host: bool,
+    group_by_path: bool,
+    auto: bool,
+    vacuum_ratio: Option<usize>,
+    vacuum_max_bundles: Option<usize>
+}
+impl Default for RetentionPolicyYaml {
+    fn default() -> Self {
+        RetentionPolicyYaml {
+            prefix: "".to_string(),
+            last: 0,
+            hourly: 0,
+            daily: 0,
+            weekly: 0,
+            monthly: 0,
+            yearly: 0,
+            within: None,
+            hourly_within: None,
+            daily_within: None,
+            weekly_within: None,
+            monthly_within: None,
+            yearly_within: None,
+            group_by_host: false,
+            group_by_path: false,
+            auto: false,
+            vacuum_ratio: None,
+            vacuum_max_bundles: None
+        }
+    }
+}
+serde_impl!(RetentionPolicyYaml(String) {
+    prefix: String => "prefix",
+    last: usize => "last",
+    hourly: usize => "hourly",
+    daily: usize => "daily",
+    weekly: usize => "weekly",
+    monthly: usize => "monthly",
+    yearly: usize => "yearly",
+    within: Option<String> => "within",
+    hourly_within: Option<String> => "hourly_within",
+    daily_within: Option<String> => "daily_within",
+    weekly_within: Option<String> => "weekly_within",
+    monthly_within: Option<String> => "monthly_within",
+    yearly_within: Option<String> => "yearly_within",
+    group_by_host: bool => "group_by_host",
+    group_by_path: bool => "group_by_path",
+    auto: bool => "auto",
+    vacuum_ratio: Option<usize> => "vacuum_ratio",
+    vacuum_max_bundles: Option<usize> => "vacuum_max_bundles"
+});
+
+impl RetentionPolicy {
+    fn from_yaml(yaml: RetentionPolicyYaml) -> Result<Self, ConfigError> {
+        let span = |val: Option<String>| -> Result<Option<i64>, ConfigError> {
+            match val {
+                Some(val) => match parse_time_span(&val) {
+                    Some(span) => Ok(Some(span)),
+                    None => Err(ConfigError::Parse(tr!("Invalid time span"))),
+                },
+                None => Ok(None),
+            }
+        };
+        Ok(RetentionPolicy {
+            prefix: yaml.prefix,
+            last: yaml.last,
+            hourly: yaml.hourly,
+            daily: yaml.daily,
+            weekly: yaml.weekly,
+            monthly: yaml.monthly,
+            yearly: yaml.yearly,
+            within: try!(span(yaml.within)),
+            hourly_within: try!(span(yaml.hourly_within)),
+            daily_within: try!(span(yaml.daily_within)),
+            weekly_within: try!(span(yaml.weekly_within)),
+            monthly_within: try!(span(yaml.monthly_within)),
+            yearly_within: try!(span(yaml.yearly_within)),
+            group_by_host: yaml.group_by_host,
+            group_by_path: yaml.group_by_path,
+            auto: yaml.auto,
+            vacuum_ratio: yaml.vacuum_ratio,
+            vacuum_max_bundles: yaml.vacuum_max_bundles
+        })
+    }
+
+    fn to_yaml(&self) -> RetentionPolicyYaml {
+        RetentionPolicyYaml {
+            prefix: self.prefix.clone(),
+            last: self.last,
+            hourly: self.hourly,
+            daily: self.daily,
+            weekly: self.weekly,
+            monthly: self.monthly,
+            yearly: self.yearly,
+            within: self.within.map(to_time_span),
+            hourly_within: self.hourly_within.map(to_time_span),
+            daily_within: self.daily_within.map(to_time_span),
+            weekly_within: self.weekly_within.map(to_time_span),
+            monthly_within: self.monthly_within.map(to_time_span),
+            yearly_within: self.yearly_within.map(to_time_span),
+            group_by_host: self.group_by_host,
+            group_by_path: self.group_by_path,
+            auto: self.auto,
+            vacuum_ratio: self.vacuum_ratio,
+            vacuum_max_bundles: self.vacuum_max_bundles
+        }
+    }
+}
+
+
+
 struct ConfigYaml {
     compression: Option<String>,
     encryption: Option<EncryptionYaml>,
     bundle_size: usize,
     chunker: ChunkerYaml,
-    hash: String
+    hash: String,
+    retention: BTreeMap<String, RetentionPolicyYaml>,
+    signing_keys: Vec<String>,
+    strict_signatures: bool,
+    hash_key: Option<String>,
+    bundle_padding: bool,
+    paranoid_dedup: bool,
+    meta_dictionary: Option<String>,
+    chunk_groups: bool
 }
 impl Default for ConfigYaml {
     fn default() -> Self {
@@ -135,7 +274,15 @@ impl Default for ConfigYaml {
             encryption: None,
             bundle_size: 25 * 1024 * 1024,
             chunker: ChunkerYaml::default(),
-            hash: "blake2".to_string()
+            hash: "blake2".to_string(),
+            retention: BTreeMap::new(),
+            signing_keys: vec![],
+            strict_signatures: false,
+            hash_key: None,
+            bundle_padding: false,
+            paranoid_dedup: false,
+            meta_dictionary: None,
+            chunk_groups: false
         }
     }
 }
@@ -144,7 +291,15 @@ serde_impl!(ConfigYaml(String) {
     encryption: Option<EncryptionYaml> => "encryption",
     bundle_size: usize => "bundle_size",
     chunker: ChunkerYaml => "chunker",
-    hash: String => "hash"
+    hash: String => "hash",
+    retention: BTreeMap<String, RetentionPolicyYaml> => "retention",
+    signing_keys: Vec<String> => "signing_keys",
+    strict_signatures: bool => "strict_signatures",
+    hash_key: Option<String> => "hash_key",
+    bundle_padding: bool => "bundle_padding",
+    paranoid_dedup: bool => "paranoid_dedup",
+    meta_dictionary: Option<String> => "meta_dictionary",
+    chunk_groups: bool => "chunk_groups"
 });
 
 
@@ -155,7 +310,15 @@ pub struct Config {
     pub encryption: Option<Encryption>,
     pub bundle_size: usize,
     pub chunker: ChunkerType,
-    pub hash: HashMethod
+    pub hash: HashMethod,
+    pub retention: BTreeMap<String, RetentionPolicy>,
+    pub signing_keys: Vec<ByteBuf>,
+    pub strict_signatures: bool,
+    pub hash_key: Option<ByteBuf>,
+    pub bundle_padding: bool,
+    pub paranoid_dedup: bool,
+    pub meta_dictionary: Option<Hash>,
+    pub chunk_groups: bool
 }
 impl Default for Config {
     fn default() -> Self {
@@ -164,16 +327,32 @@ impl Default for Config {
             encryption: None,
             bundle_size: 25 * 1024 * 1024,
             chunker: ChunkerType::from_string("fastcdc/16").unwrap(),
-            hash: HashMethod::Blake2
+            hash: HashMethod::Blake2,
+            retention: BTreeMap::new(),
+            signing_keys: vec![],
+            strict_signatures: false,
+            hash_key: None,
+            bundle_padding: false,
+            paranoid_dedup: false,
+            meta_dictionary: None,
+            chunk_groups: false
         }
     }
 }
-serde_impl!(Config(u64) {
+serde_impl!(Config(u64?) {
     compression: Option<Compression> => 0,
     encryption: Option<Encryption> => 1,
     bundle_size: usize => 2,
     chunker: ChunkerType => 3,
-    hash: HashMethod => 4
+    hash: HashMethod => 4,
+    retention: BTreeMap<String, RetentionPolicy> => 5,
+    signing_keys: Vec<ByteBuf> => 6,
+    strict_signatures: bool => 7,
+    hash_key: Option<ByteBuf> => 8,
+    bundle_padding: bool => 9,
+    paranoid_dedup: bool => 11,
+    meta_dictionary: Option<Hash> => 12,
+    chunk_groups: bool => 13
 });
 
 impl Config {
@@ -192,12 +371,48 @@ impl Config {
         } else {
             None
         };
+        let mut retention = BTreeMap::new();
+        for (name, policy) in yaml.retention {
+            retention.insert(name, try!(RetentionPolicy::from_yaml(policy)));
+        }
+        let mut signing_keys = Vec::with_capacity(yaml.signing_keys.len());
+        for key in &yaml.signing_keys {
+            let key = try!(parse_hex(key).map_err(|_| {
+                ConfigError::Parse(tr!("Invalid signing key"))
+            }));
+            signing_keys.push(key.into());
+        }
+        let hash_key = if let Some(id) = yaml.hash_key {
+            Some(try!(parse_hex(&id).map_err(|_| {
+                ConfigError::Parse(tr!("Invalid hash key id"))
+            })).into())
+        } else {
+            None
+        };
+        let meta_dictionary = if let Some(id) = yaml.meta_dictionary {
+            if id.len() != 32 {
+                return Err(ConfigError::Parse(tr!("Invalid dictionary id")));
+            }
+            Some(try!(Hash::from_string(&id).map_err(|_| {
+                ConfigError::Parse(tr!("Invalid dictionary id"))
+            })))
+        } else {
+            None
+        };
         Ok(Config {
             compression,
             encryption,
             bundle_size: yaml.bundle_size,
             chunker: try!(ChunkerType::from_yaml(&yaml.chunker)),
-            hash: try!(HashMethod::from_yaml(&yaml.hash))
+            hash: try!(HashMethod::from_yaml(&yaml.hash)),
+            retention,
+            signing_keys,
+            strict_signatures: yaml.strict_signatures,
+            hash_key,
+            bundle_padding: yaml.bundle_padding,
+            paranoid_dedup: yaml.paranoid_dedup,
+            meta_dictionary,
+            chunk_groups: yaml.chunk_groups
         })
     }
 
@@ -212,7 +427,19 @@ impl Config {
             }),
             bundle_size: self.bundle_size,
             chunker: self.chunker.to_yaml(),
-            hash: self.hash.to_yaml()
+            hash: self.hash.to_yaml(),
+            retention: self
+                .retention
+                .iter()
+                .map(|(name, policy)| (name.clone(), policy.to_yaml()))
+                .collect(),
+            signing_keys: self.signing_keys.iter().map(|key| to_hex(&key[..])).collect(),
+            strict_signatures: self.strict_signatures,
+            hash_key: self.hash_key.as_ref().map(|id| to_hex(&id[..])),
+            bundle_padding: self.bundle_padding,
+            paranoid_dedup: self.paranoid_dedup,
+            meta_dictionary: self.meta_dictionary.as_ref().map(|id| id.to_string()),
+            chunk_groups: self.chunk_groups
         }
     }
 
@@ -222,6 +449,34 @@ impl Config {
         Config::from_yaml(config)
     }
 
+    /// Returns the trusted public keys for signatures
+    pub fn get_signing_keys(&self) -> Vec<SigningPublicKey> {
+        self.signing_keys
+            .iter()
+            .filter_map(|key| SigningPublicKey::from_slice(key))
+            .collect()
+    }
+
+    /// Returns the minimal padded size of new bundles if padding is enabled
+    pub fn get_bundle_padding(&self) -> Option<usize> {
+        if self.bundle_padding {
+            Some(self.bundle_size / PADDING_MIN_FRACTION)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the compression of meta bundles that use the meta dictionary
+    ///
+    /// Only zstd supports dictionaries, so other compression methods are replaced by zstd.
+    pub fn get_dictionary_compression(&self) -> Option<Compression> {
+        self.compression.as_ref().map(|compression| if compression.supports_dictionary() {
+            compression.clone()
+        } else {
+            Compression::from_string(DICTIONARY_COMPRESSION).unwrap()
+        })
+    }
+
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
         let mut f = try!(File::create(path));
         try!(serde_yaml::to_writer(&mut f, &self.to_yaml()));
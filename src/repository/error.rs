@@ -7,6 +7,8 @@ use super::backup_file::BackupFileError;
 use super::backup::BackupError;
 use super::bundle_map::BundleMapError;
 use super::config::ConfigError;
+use super::refcount::RefCountsError;
+use super::journal::VacuumJournalError;
 use super::metadata::InodeError;
 
 
@@ -72,12 +74,46 @@ quick_error!{
             description(tr!("Bundle map error"))
             display("{}", tr_format!("Repository error: bundle map error\n\tcaused by: {}", err))
         }
+        RefCounts(err: RefCountsError) {
+            from()
+            cause(err)
+            description(tr!("Reference count error"))
+            display("{}", tr_format!("Repository error: reference count error\n\tcaused by: {}", err))
+        }
+        VacuumJournal(err: VacuumJournalError) {
+            from()
+            cause(err)
+            description(tr!("Vacuum journal error"))
+            display("{}", tr_format!("Repository error: vacuum journal error\n\tcaused by: {}", err))
+        }
+        Tuning(err: TuningError) {
+            from()
+            cause(err)
+            description(tr!("Compression tuning error"))
+            display("{}", tr_format!("Repository error: compression tuning error\n\tcaused by: {}", err))
+        }
         Integrity(err: IntegrityError) {
             from()
             cause(err)
             description(tr!("Integrity error"))
             display("{}", tr_format!("Repository error: integrity error\n\tcaused by: {}", err))
         }
+        KeyInUse(key: PublicKey) {
+            description(tr!("Key is in use"))
+            display("{}", tr_format!("Repository error: the key {} is the only stored key that can read new data", to_hex(&key[..])))
+        }
+        NoHashKey {
+            description(tr!("No hash key"))
+            display("{}", tr_format!("Repository error: the keyed hash method needs a hash key"))
+        }
+        NoRecipients {
+            description(tr!("No recipients"))
+            display("{}", tr_format!("Repository error: at least one recipient is needed"))
+        }
+        NotEncrypted {
+            description(tr!("Repository not encrypted"))
+            display("{}", tr_format!("Repository error: the repository is not encrypted"))
+        }
         Dirty {
             description(tr!("Dirty repository"))
             display("{}", tr_format!("The repository is dirty, please run a check"))
@@ -88,6 +124,11 @@ quick_error!{
             description(tr!("Failed to create a backup"))
             display("{}", tr_format!("Repository error: failed to create backup\n\tcaused by: {}", err))
         }
+        UnfinishedVacuum(err: LockError) {
+            cause(err)
+            description(tr!("Unfinished vacuum"))
+            display("{}", tr_format!("Repository error: an unfinished vacuum can not be completed while the repository is locked, remove stale locks to complete it\n\tcaused by: {}", err))
+        }
         Lock(err: LockError) {
             from()
             cause(err)
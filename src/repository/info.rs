@@ -24,6 +24,16 @@ impl BundleAnalysis {
     pub fn get_unused_size(&self) -> usize {
         ((1.0 - self.get_usage_ratio()) * self.info.encoded_size as f32) as usize
     }
+
+    /// Reclaimable space per byte that has to be rewritten
+    #[inline]
+    pub fn get_reclaim_priority(&self) -> f32 {
+        let used = self.get_used_size();
+        if used == 0 {
+            return ::std::f32::INFINITY;
+        }
+        self.get_unused_size() as f32 / used as f32
+    }
 }
 
 pub struct RepositoryInfo {
@@ -35,7 +45,8 @@ pub struct RepositoryInfo {
     pub avg_chunk_size: f32,
     pub index_size: usize,
     pub index_capacity: usize,
-    pub index_entries: usize
+    pub index_entries: usize,
+    pub reclaimable_size: Option<u64>
 }
 
 
@@ -151,7 +162,8 @@ impl Repository {
             avg_chunk_size: raw_data_size as f32 / chunk_count as f32,
             index_size: self.index.size(),
             index_capacity: self.index.capacity(),
-            index_entries: self.index.len()
+            index_entries: self.index.len(),
+            reclaimable_size: self.estimate_reclaimable_size()
         }
     }
 
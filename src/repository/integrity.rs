@@ -15,6 +15,10 @@ quick_error!{
             description(tr!("Missing chunk"))
             display("{}", tr_format!("Missing chunk: {}", hash))
         }
+        HashCollision(hash: Hash) {
+            description(tr!("Unresolvable hash collision"))
+            display("{}", tr_format!("Unresolvable hash collision on chunk: {}", hash))
+        }
         MissingBundleId(id: u32) {
             description(tr!("Missing bundle"))
             display("{}", tr_format!("Missing bundle: {}", id))
@@ -439,6 +443,9 @@ impl Repository {
             if self.next_data_bundle == bundle_id {
                 self.next_data_bundle = self.next_free_bundle_id()
             }
+            if self.next_raw_bundle == bundle_id {
+                self.next_raw_bundle = self.next_free_bundle_id()
+            }
         }
         self.save_bundle_map()
     }
@@ -0,0 +1,85 @@
+use prelude::*;
+
+use std::path::Path;
+use std::io::{self, BufReader, Read, Write, BufWriter};
+use std::fs::{self, File};
+
+
+static HEADER_STRING: [u8; 7] = *b"zvacjnl";
+static HEADER_VERSION: u8 = 1;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum VacuumJournalError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description(tr!("Failed to read/write vacuum journal"))
+        }
+        Decode(err: msgpack::DecodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to decode vacuum journal"))
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to encode vacuum journal"))
+        }
+        WrongHeader {
+            description(tr!("Wrong header"))
+        }
+        WrongVersion(version: u8) {
+            description(tr!("Wrong version"))
+            display("{}", tr_format!("Wrong version: {}", version))
+        }
+    }
+}
+
+
+/// State of a vacuum step that has not been completed yet
+///
+/// Bundles in `rewrite` are being rewritten and are still needed, bundles in `delete` have been
+/// rewritten completely and their new bundles have been stored.
+#[derive(Default)]
+pub struct VacuumJournal {
+    pub rewrite: Vec<BundleId>,
+    pub delete: Vec<BundleId>
+}
+serde_impl!(VacuumJournal(u8) {
+    rewrite: Vec<BundleId> => 0,
+    delete: Vec<BundleId> => 1
+});
+
+impl VacuumJournal {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, VacuumJournalError> {
+        let mut file = BufReader::new(try!(File::open(path.as_ref())));
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header));
+        if header[..HEADER_STRING.len()] != HEADER_STRING {
+            return Err(VacuumJournalError::WrongHeader);
+        }
+        let version = header[HEADER_STRING.len()];
+        if version != HEADER_VERSION {
+            return Err(VacuumJournalError::WrongVersion(version));
+        }
+        Ok(try!(msgpack::decode_from_stream(&mut file)))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), VacuumJournalError> {
+        // Write to a temporary file first so that the journal is never incomplete
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = BufWriter::new(try!(File::create(&tmp_path)));
+            try!(file.write_all(&HEADER_STRING));
+            try!(file.write_all(&[HEADER_VERSION]));
+            try!(msgpack::encode_to_stream(self, &mut file));
+            try!(file.flush());
+        }
+        try!(fs::rename(&tmp_path, path));
+        Ok(())
+    }
+}
+
@@ -0,0 +1,150 @@
+use prelude::*;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+
+/// Information on a key pair that is registered in the repository or that can read its data
+pub struct KeyInfo {
+    pub public: PublicKey,
+    pub fingerprint: String,
+    pub has_secret: bool,
+    pub recipient: bool,
+    pub bundles: usize,
+    pub backups: usize
+}
+
+
+/// Counts of bundles and backups that none of the listed keys can read
+#[derive(Default)]
+pub struct UnreadableData {
+    pub bundles: usize,
+    pub backups: usize
+}
+
+
+impl Repository {
+    /// Lists all stored keys and recipients
+    ///
+    /// If `check` is set, the bundles and backups that each key can decrypt are counted.
+    pub fn list_keys(
+        &self,
+        check: bool,
+    ) -> Result<(Vec<KeyInfo>, UnreadableData), RepositoryError> {
+        let local = self.crypto.lock().unwrap().public_keys();
+        let recipients = try!(self.get_recipients());
+        let mut keys: Vec<KeyInfo> = local
+            .iter()
+            .chain(recipients.iter().filter(|key| !local.contains(key)))
+            .map(|public| {
+                KeyInfo {
+                    public: *public,
+                    fingerprint: Crypto::fingerprint(public),
+                    has_secret: local.contains(public),
+                    recipient: recipients.contains(public),
+                    bundles: 0,
+                    backups: 0
+                }
+            })
+            .collect();
+        keys.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+        let mut unreadable = UnreadableData::default();
+        if !check {
+            return Ok((keys, unreadable));
+        }
+        let mut readers_cache = HashMap::new();
+        for bundle in self.list_bundles() {
+            if let Some(ref encryption) = bundle.encryption {
+                let readers = try!(self.get_readers(encryption, &mut readers_cache));
+                if !local.iter().any(|key| readers.contains(key)) {
+                    unreadable.bundles += 1;
+                }
+                for key in &mut keys {
+                    if readers.contains(&key.public) {
+                        key.bundles += 1;
+                    }
+                }
+            }
+        }
+        for path in try!(self.list_backup_files()) {
+            if let Some(encryption) = try!(Backup::get_encryption_from(&path)) {
+                let readers = try!(self.get_readers(&encryption, &mut readers_cache));
+                if !local.iter().any(|key| readers.contains(key)) {
+                    unreadable.backups += 1;
+                }
+                for key in &mut keys {
+                    if readers.contains(&key.public) {
+                        key.backups += 1;
+                    }
+                }
+            }
+        }
+        Ok((keys, unreadable))
+    }
+
+    /// Returns the public keys whose secret keys can decrypt data with the given encryption
+    pub fn get_readers(
+        &self,
+        encryption: &Encryption,
+        cache: &mut HashMap<Vec<u8>, Vec<PublicKey>>,
+    ) -> Result<Vec<PublicKey>, RepositoryError> {
+        let (ref method, ref key) = *encryption;
+        if let Some(readers) = cache.get(&key[..]) {
+            return Ok(readers.clone());
+        }
+        let readers = match *method {
+            EncryptionMethod::Sodium => {
+                vec![try!(PublicKey::from_slice(key).ok_or(EncryptionError::InvalidKey))]
+            }
+            EncryptionMethod::SecretBox => {
+                let path = self.layout.data_key_path(key);
+                if path.exists() {
+                    try!(try!(DataKeyYaml::load(path)).get_recipients())
+                } else {
+                    vec![]
+                }
+            }
+        };
+        cache.insert(key.to_vec(), readers.clone());
+        Ok(readers)
+    }
+
+    fn list_backup_files(&self) -> Result<Vec<PathBuf>, RepositoryError> {
+        let mut files = vec![];
+        let mut paths = vec![self.layout.backups_path()];
+        while let Some(path) = paths.pop() {
+            for entry in try!(fs::read_dir(&path)) {
+                let path = try!(entry).path();
+                if path.is_dir() {
+                    paths.push(path);
+                } else if path.extension() == Some("backup".as_ref()) {
+                    files.push(path);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Removes a secret key from the repository
+    ///
+    /// Unless `force` is set, the last stored key that can read new data is not removed.
+    pub fn remove_key(&mut self, public: &PublicKey, force: bool) -> Result<(), RepositoryError> {
+        let recipients = try!(self.get_recipients());
+        if !force && recipients.contains(public) {
+            let local = self.crypto.lock().unwrap().public_keys();
+            if !local.iter().any(|key| key != public && recipients.contains(key)) {
+                return Err(RepositoryError::KeyInUse(*public));
+            }
+        }
+        try!(self.crypto.lock().unwrap().remove_secret_key(public));
+        Ok(())
+    }
+
+    /// Returns the key pair for the public key if the secret key is stored in the repository
+    #[inline]
+    pub fn export_key(&self, public: &PublicKey) -> Result<(PublicKey, SecretKey), RepositoryError> {
+        let secret = try!(self.crypto.lock().unwrap().export_secret_key(public));
+        Ok((*public, secret))
+    }
+}
@@ -20,6 +20,11 @@ impl RepositoryLayout {
         self.0.join("config.yaml")
     }
 
+    #[inline]
+    pub fn tuning_path(&self) -> PathBuf {
+        self.0.join("tuning.yaml")
+    }
+
     #[inline]
     pub fn excludes_path(&self) -> PathBuf {
         self.0.join("excludes")
@@ -30,16 +35,31 @@ impl RepositoryLayout {
         self.0.join("index")
     }
 
+    #[inline]
+    pub fn verified_chunks_path(&self) -> PathBuf {
+        self.0.join("verified_chunks")
+    }
+
     #[inline]
     pub fn keys_path(&self) -> PathBuf {
         self.0.join("keys")
     }
 
+    #[inline]
+    pub fn signing_key_path(&self) -> PathBuf {
+        self.0.join("signing.yaml")
+    }
+
     #[inline]
     pub fn bundle_map_path(&self) -> PathBuf {
         self.0.join("bundles.map")
     }
 
+    #[inline]
+    pub fn refcounts_path(&self) -> PathBuf {
+        self.0.join("refcounts")
+    }
+
     #[inline]
     pub fn local_locks_path(&self) -> PathBuf {
         self.0.join("locks")
@@ -71,6 +91,26 @@ impl RepositoryLayout {
         self.0.join("remote/README.md")
     }
 
+    #[inline]
+    pub fn vacuum_journal_path(&self) -> PathBuf {
+        self.0.join("remote/vacuum.journal")
+    }
+
+    #[inline]
+    pub fn data_keys_path(&self) -> PathBuf {
+        self.0.join("remote/keys")
+    }
+
+    #[inline]
+    pub fn data_key_path(&self, id: &[u8]) -> PathBuf {
+        self.data_keys_path().join(format!("{}.yaml", to_hex(id)))
+    }
+
+    #[inline]
+    pub fn key_rotation_path(&self) -> PathBuf {
+        self.0.join("remote/keyrotation.yaml")
+    }
+
     #[inline]
     pub fn remote_locks_path(&self) -> PathBuf {
         self.0.join("remote/locks")
@@ -81,6 +121,26 @@ impl RepositoryLayout {
         self.0.join("remote/bundles")
     }
 
+    #[inline]
+    pub fn remote_dictionaries_path(&self) -> PathBuf {
+        self.0.join("remote/dictionaries")
+    }
+
+    #[inline]
+    pub fn local_dictionaries_path(&self) -> PathBuf {
+        self.0.join("bundles/dictionaries")
+    }
+
+    #[inline]
+    pub fn remote_dictionary_path(&self, id: &Hash) -> PathBuf {
+        self.remote_dictionaries_path().join(format!("{}.dict", id))
+    }
+
+    #[inline]
+    pub fn local_dictionary_path(&self, id: &Hash) -> PathBuf {
+        self.local_dictionaries_path().join(format!("{}.dict", id))
+    }
+
     #[inline]
     pub fn local_bundles_path(&self) -> PathBuf {
         self.0.join("bundles/cached")
@@ -389,6 +389,12 @@ impl Repository {
         self.put_data(BundleMode::Meta, &try!(inode.encode()))
     }
 
+    /// Encodes the inode like `put_inode` but only keeps it in memory
+    #[inline]
+    pub fn put_virtual_inode(&mut self, inode: &Inode) -> Result<ChunkList, RepositoryError> {
+        self.put_virtual_data(&try!(inode.encode()))
+    }
+
     #[inline]
     pub fn get_inode(&mut self, chunks: &[Chunk]) -> Result<Inode, RepositoryError> {
         Ok(try!(Inode::decode(&try!(self.get_data(chunks)))))
@@ -5,16 +5,24 @@ mod basic_io;
 mod info;
 mod metadata;
 mod backup;
+mod prune;
 mod error;
 mod vacuum;
 mod backup_file;
 mod tarfile;
 mod layout;
+mod refcount;
+mod journal;
+mod rotation;
+mod signing;
+mod keys;
+mod rehash;
+mod tuning;
 
 use prelude::*;
 
-use std::mem;
 use std::cmp::max;
+use std::collections::HashMap;
 use std::path::Path;
 use std::fs::{self, File};
 use std::sync::{Arc, Mutex};
@@ -25,11 +33,17 @@ pub use self::error::RepositoryError;
 pub use self::config::Config;
 pub use self::metadata::{Inode, FileType, FileData, InodeError};
 pub use self::backup::{BackupError, BackupOptions, DiffType};
+pub use self::prune::RetentionPolicy;
+pub use self::vacuum::{VacuumLimits, RewriteSelection};
 pub use self::backup_file::{Backup, BackupFileError};
 pub use self::integrity::IntegrityError;
 pub use self::info::{RepositoryInfo, BundleAnalysis, RepositoryStatistics};
 pub use self::layout::RepositoryLayout;
+pub use self::keys::{KeyInfo, UnreadableData};
+pub use self::tuning::{CompressionTuning, CompressionMeasurement, TuningTargets, TuningError,
+                       DictionaryTraining};
 use self::bundle_map::BundleMap;
+use self::refcount::RefCounts;
 
 
 const REPOSITORY_README: &[u8] = include_bytes!("../../docs/repository_readme.md");
@@ -38,6 +52,9 @@ const DEFAULT_EXCLUDES: &[u8] = include_bytes!("../../docs/excludes.default");
 const INDEX_MAGIC: [u8; 7] = *b"zvault\x02";
 const INDEX_VERSION: u8 = 1;
 
+const VERIFIED_CHUNKS_MAGIC: [u8; 7] = *b"zvault\x06";
+const VERIFIED_CHUNKS_VERSION: u8 = 1;
+
 
 #[derive(Clone, Copy, PartialEq, Debug, Default)]
 pub struct Location {
@@ -55,6 +72,8 @@ impl Location {
 
 impl ::index::Value for Location {}
 
+impl ::index::Value for Hash {}
+
 impl ::index::Key for Hash {
     fn hash(&self) -> u64 {
         self.low
@@ -76,15 +95,20 @@ pub struct Repository {
     index: Index<Hash, Location>,
     crypto: Arc<Mutex<Crypto>>,
     bundle_map: BundleMap,
+    refcounts: Option<RefCounts>,
     next_data_bundle: u32,
     next_meta_bundle: u32,
+    next_raw_bundle: u32,
     bundles: BundleDb,
     data_bundle: Option<BundleWriter>,
     meta_bundle: Option<BundleWriter>,
+    raw_bundle: Option<BundleWriter>,
     chunker: Box<Chunker>,
     remote_locks: LockFolder,
     local_locks: LockFolder,
     lock: LockHandle,
+    verified_chunks: Option<Index<Hash, Hash>>,
+    virtual_chunks: HashMap<Hash, Vec<u8>>,
     dirty: bool
 }
 
@@ -117,6 +141,7 @@ impl Repository {
             INDEX_VERSION
         ));
         try!(BundleMap::create().save(layout.bundle_map_path()));
+        try!(RefCounts::default().save(layout.refcounts_path()));
         try!(fs::create_dir_all(layout.backups_path()));
         Self::open(path, true)
     }
@@ -132,8 +157,19 @@ impl Repository {
         try!(fs::create_dir_all(layout.local_locks_path())); // Added after v0.1.0
         let local_locks = LockFolder::new(layout.local_locks_path());
         let lock = try!(local_locks.lock(false));
-        let crypto = Arc::new(Mutex::new(try!(Crypto::open(layout.keys_path()))));
-        let (bundles, new, gone) = try!(BundleDb::open(layout.clone(), crypto.clone(), online));
+        let mut crypto = try!(Crypto::open(layout.keys_path()));
+        try!(crypto.load_data_keys(layout.data_keys_path()));
+        if layout.signing_key_path().exists() {
+            try!(crypto.load_signing_key(layout.signing_key_path()));
+        }
+        crypto.set_trusted_keys(config.get_signing_keys(), config.strict_signatures);
+        crypto.set_hash_key_id(config.hash_key.as_ref().map(|id| id.to_vec()));
+        let crypto = Arc::new(Mutex::new(crypto));
+        let (bundles, new, gone) = try!(BundleDb::open(
+            layout.clone(),
+            crypto.clone(),
+            online
+        ));
         let (index, mut rebuild_index) =
             match unsafe { Index::open(layout.index_path(), &INDEX_MAGIC, INDEX_VERSION) } {
                 Ok(index) => (index, false),
@@ -156,7 +192,9 @@ impl Repository {
                 (BundleMap::create(), true)
             }
         };
-        let dirty = layout.dirtyfile_path().exists();
+        // Reference counts are only a cache, they are rebuilt when needed
+        let refcounts = RefCounts::load(layout.refcounts_path()).ok();
+        let mut dirty = layout.dirtyfile_path().exists();
         let mut repo = Repository {
             layout,
             dirty: true,
@@ -165,14 +203,19 @@ impl Repository {
             index,
             crypto,
             bundle_map,
+            refcounts,
             next_data_bundle: 0,
             next_meta_bundle: 0,
+            next_raw_bundle: 0,
             bundles,
             data_bundle: None,
             meta_bundle: None,
+            raw_bundle: None,
             lock,
             remote_locks,
-            local_locks
+            local_locks,
+            verified_chunks: None,
+            virtual_chunks: HashMap::new()
         };
         if !rebuild_bundle_map {
             let mut save_bundle_map = false;
@@ -204,6 +247,7 @@ impl Repository {
         }
         repo.next_meta_bundle = repo.next_free_bundle_id();
         repo.next_data_bundle = repo.next_free_bundle_id();
+        repo.next_raw_bundle = repo.next_free_bundle_id();
         if rebuild_bundle_map {
             try!(repo.write_mode());
             try!(repo.rebuild_bundle_map());
@@ -213,26 +257,46 @@ impl Repository {
             try!(repo.write_mode());
             try!(repo.rebuild_index());
         }
+        if online && try!(repo.recover_vacuum()) {
+            // The vacuum marks the repository dirty while it runs, the journal completed it
+            dirty = false;
+        }
         repo.dirty = dirty;
         Ok(repo)
     }
 
+    /// Creates a local repository for existing remote storage
+    ///
+    /// The configuration is taken from the newest backup, except for the trusted signing keys and
+    /// the strict mode. Those must not be controlled by the remote storage, so they are given by
+    /// the caller.
     pub fn import<P: AsRef<Path>, R: AsRef<Path>>(
         path: P,
         remote: R,
         key_files: Vec<String>,
+        trust: &[SigningPublicKey],
+        strict_signatures: bool,
     ) -> Result<Self, RepositoryError> {
         let path = path.as_ref();
-        let mut repo = try!(Repository::create(path, &Config::default(), remote));
+        let mut config = Config::default();
+        config.signing_keys = trust.iter().map(|key| key[..].to_vec().into()).collect();
+        config.strict_signatures = strict_signatures;
+        let mut repo = try!(Repository::create(path, &config, remote));
         for file in key_files {
             try!(repo.crypto.lock().unwrap().register_keyfile(file));
         }
         repo = try!(Repository::open(path, true));
+        if trust.is_empty() {
+            tr_warn!("No trusted signing keys given, signatures of the imported data are not checked.");
+        }
         let mut backups: Vec<(String, Backup)> = try!(repo.get_all_backups()).into_iter().collect();
         backups.sort_by_key(|&(_, ref b)| b.timestamp);
         if let Some((name, backup)) = backups.pop() {
             tr_info!("Taking configuration from the last backup '{}'", name);
-            repo.config = backup.config;
+            let mut config = backup.config;
+            config.signing_keys = repo.config.signing_keys.clone();
+            config.strict_signatures = repo.config.strict_signatures;
+            repo.config = config;
             try!(repo.save_config())
         } else {
             tr_warn!(
@@ -249,13 +313,24 @@ impl Repository {
         secret: SecretKey,
     ) -> Result<(), RepositoryError> {
         try!(self.write_mode());
-        try!(self.crypto.lock().unwrap().register_secret_key(
-            public,
-            secret
-        ));
+        let mut crypto = self.crypto.lock().unwrap();
+        try!(crypto.register_secret_key(public, secret));
+        // The new key might open more data keys
+        try!(crypto.load_data_keys(self.layout.data_keys_path()));
         Ok(())
     }
 
+    #[inline]
+    pub fn unlock_keys(&mut self) -> Result<(), RepositoryError> {
+        Ok(try!(self.crypto.lock().unwrap().unlock_keys()))
+    }
+
+    /// Protects all stored secret keys with a new passphrase or removes the protection
+    #[inline]
+    pub fn change_passphrase(&mut self, passphrase: Option<&str>) -> Result<usize, RepositoryError> {
+        Ok(try!(self.crypto.lock().unwrap().change_passphrase(passphrase)))
+    }
+
     #[inline]
     pub fn save_config(&mut self) -> Result<(), RepositoryError> {
         try!(self.write_mode());
@@ -263,18 +338,163 @@ impl Repository {
         Ok(())
     }
 
-    #[inline]
-    pub fn set_encryption(&mut self, public: Option<&PublicKey>) {
+    /// Encrypts new data with a new data key for the given public key
+    ///
+    /// If the repository does not contain any data yet, this also creates the repository secret
+    /// and derives the chunker seed from it. Later, this would break the deduplication with the
+    /// existing data.
+    pub fn set_encryption(&mut self, public: Option<&PublicKey>) -> Result<(), RepositoryError> {
         if let Some(key) = public {
             if !self.crypto.lock().unwrap().contains_secret_key(key) {
                 tr_warn!("The secret key for that public key is not stored in the repository.")
             }
-            let mut key_bytes = Vec::new();
-            key_bytes.extend_from_slice(&key[..]);
-            self.config.encryption = Some((EncryptionMethod::Sodium, key_bytes.into()))
+            try!(self.create_data_key(&[*key]));
+            if self.config.hash_key.is_none() && self.index.is_empty() {
+                try!(self.create_hash_key());
+            }
         } else {
             self.config.encryption = None
         }
+        Ok(())
+    }
+
+    /// Creates a new data key for the recipients and uses it for new data
+    pub fn create_data_key(&mut self, recipients: &[PublicKey]) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        let (id, key) = Crypto::gen_data_key();
+        try!(fs::create_dir_all(self.layout.data_keys_path()));
+        try!(Crypto::wrap_data_key(&id, &key, recipients).save(
+            self.layout.data_key_path(&id)
+        ));
+        self.crypto.lock().unwrap().add_data_key(id.clone(), key);
+        self.config.encryption = Some((EncryptionMethod::SecretBox, id.into()));
+        Ok(())
+    }
+
+    /// Returns the public keys that can read new data
+    pub fn get_recipients(&self) -> Result<Vec<PublicKey>, RepositoryError> {
+        match self.config.encryption {
+            Some((EncryptionMethod::SecretBox, ref id)) => {
+                let data_key = try!(DataKeyYaml::load(self.layout.data_key_path(id)));
+                Ok(try!(data_key.get_recipients()))
+            }
+            Some((EncryptionMethod::Sodium, ref key)) => {
+                Ok(vec![try!(PublicKey::from_slice(key).ok_or(EncryptionError::InvalidKey))])
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Makes all data keys accessible for exactly the given recipients
+    ///
+    /// Data that has been encrypted directly for a public key is not affected, instead a new data
+    /// key is created for new data. Data keys that can not be opened with the local keys are
+    /// skipped.
+    pub fn set_recipients(&mut self, recipients: &[PublicKey]) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        if recipients.is_empty() {
+            return Err(RepositoryError::NoRecipients);
+        }
+        let current = match self.config.encryption {
+            Some((EncryptionMethod::SecretBox, ref id)) => id.to_vec(),
+            Some((EncryptionMethod::Sodium, _)) => {
+                try!(self.create_data_key(recipients));
+                return self.save_config();
+            }
+            None => return Err(RepositoryError::NotEncrypted),
+        };
+        // The current data key is checked first so that a missing key changes nothing
+        try!(self.rewrap_key(&current, recipients));
+        let data_keys_path = self.layout.data_keys_path();
+        for entry in try!(fs::read_dir(&data_keys_path)) {
+            let path = try!(entry).path();
+            if path.extension() != Some("yaml".as_ref()) {
+                continue;
+            }
+            let data_key = try!(DataKeyYaml::load(&path));
+            if try!(data_key.get_id()) == current {
+                continue;
+            }
+            if !try!(self.rewrap_key_file(&path, recipients)) {
+                tr_warn!("Data key {:?} can not be opened with the local keys, skipping it", path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Seals an existing data key to the given recipients only
+    fn rewrap_key(&self, id: &[u8], recipients: &[PublicKey]) -> Result<(), RepositoryError> {
+        if try!(self.rewrap_key_file(&self.layout.data_key_path(id), recipients)) {
+            Ok(())
+        } else {
+            Err(EncryptionError::MissingDataKey(id.to_vec()).into())
+        }
+    }
+
+    /// Seals the data key stored in `path` to the given recipients only
+    ///
+    /// Returns `false` if the data key can not be opened with the local keys.
+    fn rewrap_key_file(
+        &self,
+        path: &Path,
+        recipients: &[PublicKey],
+    ) -> Result<bool, RepositoryError> {
+        let data_key = try!(DataKeyYaml::load(path));
+        let (id, key) = match try!(self.crypto.lock().unwrap().unwrap_data_key(&data_key)) {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+        // Write the new key first so that the data key is never lost
+        let tmp_path = path.with_extension("tmp");
+        try!(Crypto::wrap_data_key(&id, &key, recipients).save(&tmp_path));
+        try!(fs::rename(&tmp_path, path));
+        Ok(true)
+    }
+
+    /// Creates the repository secret and derives the chunker seed from it
+    ///
+    /// The secret is the key of keyed hash methods. It is stored like a data key, so the
+    /// repository has to be encrypted.
+    pub fn create_hash_key(&mut self) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        let recipients = try!(self.get_recipients());
+        if recipients.is_empty() {
+            return Err(RepositoryError::NotEncrypted);
+        }
+        let (id, key) = Crypto::gen_data_key();
+        try!(fs::create_dir_all(self.layout.data_keys_path()));
+        try!(Crypto::wrap_data_key(&id, &key, &recipients).save(
+            self.layout.data_key_path(&id)
+        ));
+        {
+            let mut crypto = self.crypto.lock().unwrap();
+            crypto.add_data_key(id.clone(), key);
+            crypto.set_hash_key_id(Some(id.clone()));
+        }
+        self.config.hash_key = Some(id.into());
+        self.derive_chunker_seed()
+    }
+
+    /// Derives the chunker seed from the hash key so that chunk boundaries do not leak
+    pub fn derive_chunker_seed(&mut self) -> Result<(), RepositoryError> {
+        if self.config.hash_key.is_none() {
+            return Ok(());
+        }
+        let key = try!(self.crypto.lock().unwrap().get_hash_key());
+        let seed = HashMethod::Blake2Keyed.hash_keyed(&key, b"chunker seed").low;
+        self.config.chunker = self.config.chunker.with_seed(seed);
+        self.chunker = self.config.chunker.create();
+        Ok(())
+    }
+
+    fn get_hash_key(&self) -> Result<Vec<u8>, RepositoryError> {
+        if !self.config.hash.is_keyed() {
+            return Ok(vec![]);
+        }
+        if self.config.hash_key.is_none() {
+            return Err(RepositoryError::NoHashKey);
+        }
+        Ok(try!(self.crypto.lock().unwrap().get_hash_key()))
     }
 
     #[inline]
@@ -285,7 +505,10 @@ impl Repository {
 
     #[inline]
     fn next_free_bundle_id(&self) -> u32 {
-        let mut id = max(self.next_data_bundle, self.next_meta_bundle) + 1;
+        let mut id = max(
+            max(self.next_data_bundle, self.next_meta_bundle),
+            self.next_raw_bundle
+        ) + 1;
         while self.bundle_map.get(id).is_some() {
             id += 1;
         }
@@ -306,30 +529,7 @@ impl Repository {
         if self.dirty && !dirtyfile.exists() {
             try!(File::create(&dirtyfile));
         }
-        if self.data_bundle.is_some() {
-            let mut finished = None;
-            mem::swap(&mut self.data_bundle, &mut finished);
-            {
-                let bundle = try!(self.bundles.add_bundle(finished.unwrap()));
-                self.bundle_map.set(
-                    self.next_data_bundle,
-                    bundle.id.clone()
-                );
-            }
-            self.next_data_bundle = self.next_free_bundle_id()
-        }
-        if self.meta_bundle.is_some() {
-            let mut finished = None;
-            mem::swap(&mut self.meta_bundle, &mut finished);
-            {
-                let bundle = try!(self.bundles.add_bundle(finished.unwrap()));
-                self.bundle_map.set(
-                    self.next_meta_bundle,
-                    bundle.id.clone()
-                );
-            }
-            self.next_meta_bundle = self.next_free_bundle_id()
-        }
+        try!(self.finish_bundles());
         try!(self.bundles.flush());
         try!(self.save_bundle_map());
         if !self.dirty && dirtyfile.exists() {
@@ -355,6 +555,9 @@ impl Repository {
         if self.next_data_bundle == bundle_id {
             self.next_data_bundle = self.next_free_bundle_id()
         }
+        if self.next_raw_bundle == bundle_id {
+            self.next_raw_bundle = self.next_free_bundle_id()
+        }
         for (i, (hash, _len)) in chunks.into_inner().into_iter().enumerate() {
             if let Some(old) = try!(self.index.set(
                 &hash,
@@ -0,0 +1,359 @@
+use prelude::*;
+
+use std::collections::BTreeMap;
+
+use chrono::prelude::*;
+
+
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub prefix: String,
+    pub last: usize,
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    pub yearly: usize,
+    pub within: Option<i64>,
+    pub hourly_within: Option<i64>,
+    pub daily_within: Option<i64>,
+    pub weekly_within: Option<i64>,
+    pub monthly_within: Option<i64>,
+    pub yearly_within: Option<i64>,
+    pub group_by_host: bool,
+    pub group_by_path: bool,
+    pub auto: bool,
+    pub vacuum_ratio: Option<usize>,
+    pub vacuum_max_bundles: Option<usize>
+}
+serde_impl!(RetentionPolicy(u64) {
+    prefix: String => 0,
+    last: usize => 1,
+    hourly: usize => 2,
+    daily: usize => 3,
+    weekly: usize => 4,
+    monthly: usize => 5,
+    yearly: usize => 6,
+    within: Option<i64> => 7,
+    hourly_within: Option<i64> => 8,
+    daily_within: Option<i64> => 9,
+    weekly_within: Option<i64> => 10,
+    monthly_within: Option<i64> => 11,
+    yearly_within: Option<i64> => 12,
+    group_by_host: bool => 13,
+    group_by_path: bool => 14,
+    auto: bool => 15,
+    vacuum_ratio: Option<usize> => 16,
+    vacuum_max_bundles: Option<usize> => 17
+});
+
+impl RetentionPolicy {
+    pub fn from_string(val: &str) -> Result<Self, &'static str> {
+        let mut policy = RetentionPolicy::default();
+        for part in val.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let (key, value) = match part.find('=') {
+                Some(pos) => (&part[..pos], Some(&part[pos + 1..])),
+                None => (part, None),
+            };
+            let num = || -> Result<usize, &'static str> {
+                value.and_then(|v| v.parse().ok()).ok_or_else(|| tr!("Invalid number"))
+            };
+            let span = || -> Result<Option<i64>, &'static str> {
+                value.and_then(parse_time_span).map(Some).ok_or_else(|| tr!("Invalid time span"))
+            };
+            match key {
+                "prefix" => policy.prefix = value.unwrap_or("").to_string(),
+                "last" => policy.last = try!(num()),
+                "hourly" => policy.hourly = try!(num()),
+                "daily" => policy.daily = try!(num()),
+                "weekly" => policy.weekly = try!(num()),
+                "monthly" => policy.monthly = try!(num()),
+                "yearly" => policy.yearly = try!(num()),
+                "within" => policy.within = try!(span()),
+                "hourly-within" => policy.hourly_within = try!(span()),
+                "daily-within" => policy.daily_within = try!(span()),
+                "weekly-within" => policy.weekly_within = try!(span()),
+                "monthly-within" => policy.monthly_within = try!(span()),
+                "yearly-within" => policy.yearly_within = try!(span()),
+                "group-by-host" => policy.group_by_host = true,
+                "group-by-path" => policy.group_by_path = true,
+                "auto" => policy.auto = true,
+                "vacuum-ratio" => policy.vacuum_ratio = Some(try!(num())),
+                "vacuum-max-bundles" => policy.vacuum_max_bundles = Some(try!(num())),
+                _ => return Err(tr!("Unknown retention rule")),
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut parts = vec![format!("prefix={}", self.prefix)];
+        for &(name, num) in &[
+            ("last", self.last),
+            ("hourly", self.hourly),
+            ("daily", self.daily),
+            ("weekly", self.weekly),
+            ("monthly", self.monthly),
+            ("yearly", self.yearly),
+        ]
+        {
+            if num > 0 {
+                parts.push(format!("{}={}", name, num));
+            }
+        }
+        for &(name, span) in &[
+            ("within", self.within),
+            ("hourly-within", self.hourly_within),
+            ("daily-within", self.daily_within),
+            ("weekly-within", self.weekly_within),
+            ("monthly-within", self.monthly_within),
+            ("yearly-within", self.yearly_within),
+        ]
+        {
+            if let Some(span) = span {
+                parts.push(format!("{}={}", name, to_time_span(span)));
+            }
+        }
+        if self.group_by_host {
+            parts.push("group-by-host".to_string());
+        }
+        if self.group_by_path {
+            parts.push("group-by-path".to_string());
+        }
+        if self.auto {
+            parts.push("auto".to_string());
+        }
+        if let Some(ratio) = self.vacuum_ratio {
+            parts.push(format!("vacuum-ratio={}", ratio));
+        }
+        if let Some(max_bundles) = self.vacuum_max_bundles {
+            parts.push(format!("vacuum-max-bundles={}", max_bundles));
+        }
+        parts.join(",")
+    }
+
+    pub fn keeps_nothing(&self) -> bool {
+        self.last + self.hourly + self.daily + self.weekly + self.monthly + self.yearly == 0 &&
+            self.within.is_none() && self.hourly_within.is_none() &&
+            self.daily_within.is_none() && self.weekly_within.is_none() &&
+            self.monthly_within.is_none() && self.yearly_within.is_none()
+    }
+
+    /// Calculates for each backup (sorted newest first) the rules that retain it
+    pub fn apply(
+        &self,
+        backups: &[(String, DateTime<Local>, Backup)],
+    ) -> Vec<Vec<&'static str>> {
+        let mut reasons = vec![vec![]; backups.len()];
+        let newest = match backups.first() {
+            Some(backup) => backup.2.timestamp,
+            None => return reasons,
+        };
+
+        fn mark_needed<K: Eq, F: Fn(&DateTime<Local>) -> K>(
+            backups: &[(String, DateTime<Local>, Backup)],
+            reasons: &mut [Vec<&'static str>],
+            max: usize,
+            keyfn: F,
+            reason: &'static str,
+        ) {
+            let mut kept = 0;
+            let mut last = None;
+            for (i, backup) in backups.iter().enumerate() {
+                let val = keyfn(&backup.1);
+                let cur = Some(val);
+                if cur != last {
+                    if kept >= max {
+                        break;
+                    }
+                    last = cur;
+                    reasons[i].push(reason);
+                    kept += 1;
+                }
+            }
+        }
+
+        fn mark_within<K: Eq, F: Fn(&DateTime<Local>) -> K>(
+            backups: &[(String, DateTime<Local>, Backup)],
+            reasons: &mut [Vec<&'static str>],
+            since: i64,
+            keyfn: F,
+            reason: &'static str,
+        ) {
+            let mut last = None;
+            for (i, backup) in backups.iter().enumerate() {
+                if backup.2.timestamp < since {
+                    break;
+                }
+                let cur = Some(keyfn(&backup.1));
+                if cur != last {
+                    last = cur;
+                    reasons[i].push(reason);
+                }
+            }
+        }
+
+        let hour = |d: &DateTime<Local>| (d.year(), d.ordinal(), d.hour());
+        let day = |d: &DateTime<Local>| (d.year(), d.month(), d.day());
+        let week = |d: &DateTime<Local>| {
+            let week = d.iso_week();
+            (week.year(), week.week())
+        };
+        let month = |d: &DateTime<Local>| (d.year(), d.month());
+        let year = |d: &DateTime<Local>| d.year();
+        for reason in reasons.iter_mut().take(self.last) {
+            reason.push(tr!("last"));
+        }
+        if let Some(within) = self.within {
+            for (i, backup) in backups.iter().enumerate() {
+                if backup.2.timestamp < newest - within {
+                    break;
+                }
+                reasons[i].push(tr!("within"));
+            }
+        }
+        if self.hourly > 0 {
+            mark_needed(backups, &mut reasons, self.hourly, hour, tr!("hourly"));
+        }
+        if self.daily > 0 {
+            mark_needed(backups, &mut reasons, self.daily, day, tr!("daily"));
+        }
+        if self.weekly > 0 {
+            mark_needed(backups, &mut reasons, self.weekly, week, tr!("weekly"));
+        }
+        if self.monthly > 0 {
+            mark_needed(backups, &mut reasons, self.monthly, month, tr!("monthly"));
+        }
+        if self.yearly > 0 {
+            mark_needed(backups, &mut reasons, self.yearly, year, tr!("yearly"));
+        }
+        if let Some(within) = self.hourly_within {
+            mark_within(backups, &mut reasons, newest - within, hour, tr!("hourly within"));
+        }
+        if let Some(within) = self.daily_within {
+            mark_within(backups, &mut reasons, newest - within, day, tr!("daily within"));
+        }
+        if let Some(within) = self.weekly_within {
+            mark_within(backups, &mut reasons, newest - within, week, tr!("weekly within"));
+        }
+        if let Some(within) = self.monthly_within {
+            mark_within(backups, &mut reasons, newest - within, month, tr!("monthly within"));
+        }
+        if let Some(within) = self.yearly_within {
+            mark_within(backups, &mut reasons, newest - within, year, tr!("yearly within"));
+        }
+        reasons
+    }
+}
+
+
+impl Repository {
+    pub fn prune_backups(
+        &mut self,
+        policy: &RetentionPolicy,
+        force: bool,
+        ignore_locks: bool,
+    ) -> Result<Vec<String>, RepositoryError> {
+        try!(self.write_mode());
+        let backup_map = match self.get_all_backups() {
+            Ok(backup_map) => backup_map,
+            Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
+                                                                                _failed))) => {
+                tr_warn!("Some backups could not be read, ignoring them");
+                backup_map
+            }
+            Err(err) => return Err(err),
+        };
+        let mut groups = BTreeMap::new();
+        for (name, backup) in backup_map {
+            if name.starts_with(&policy.prefix) {
+                let host = if policy.group_by_host {
+                    Some(backup.host.clone())
+                } else {
+                    None
+                };
+                let path = if policy.group_by_path {
+                    Some(backup.path.clone())
+                } else {
+                    None
+                };
+                let date = Local.timestamp(backup.timestamp, 0);
+                groups.entry((host, path)).or_insert_with(Vec::new).push((name, date, backup));
+            }
+        }
+        let mut remove = Vec::new();
+        for ((host, path), mut backups) in groups {
+            backups.sort_by_key(|backup| -backup.2.timestamp);
+            let mut reasons = policy.apply(&backups);
+            if !ignore_locks {
+                for (backup, reasons) in backups.iter().zip(&mut reasons) {
+                    if backup.2.pinned {
+                        reasons.insert(0, tr!("pinned"));
+                    } else if backup.2.is_lock_active() {
+                        reasons.insert(0, tr!("locked"));
+                    }
+                }
+            }
+            match (host, path) {
+                (Some(host), Some(path)) => tr_println!("Backups of {}:{}", host, path),
+                (Some(host), None) => tr_println!("Backups of host {}", host),
+                (None, Some(path)) => tr_println!("Backups of path {}", path),
+                (None, None) => (),
+            }
+            tr_println!("Keeping the following backups");
+            for (backup, reasons) in backups.iter().zip(&reasons) {
+                if !reasons.is_empty() {
+                    println!("  - {} ({})", backup.0, reasons.join(", "));
+                }
+            }
+            tr_println!("Removing the following backups");
+            for (backup, reasons) in backups.into_iter().zip(&reasons) {
+                if reasons.is_empty() {
+                    println!("  - {}", backup.0);
+                    remove.push(backup.0);
+                }
+            }
+        }
+        if force {
+            for name in &remove {
+                try!(self.delete_backup(name, true));
+            }
+        }
+        Ok(remove)
+    }
+
+    pub fn get_retention_policy(&self, backup_name: &str) -> Option<(&str, &RetentionPolicy)> {
+        self.config
+            .retention
+            .iter()
+            .filter(|&(_, policy)| backup_name.starts_with(&policy.prefix))
+            .max_by_key(|&(_, policy)| policy.prefix.len())
+            .map(|(name, policy)| (name as &str, policy))
+    }
+
+    pub fn apply_retention_policy(&mut self, backup_name: &str) -> Result<bool, RepositoryError> {
+        let policy = match self.get_retention_policy(backup_name) {
+            Some((name, policy)) if policy.auto => {
+                tr_info!("Applying retention policy {}", name);
+                policy.clone()
+            }
+            _ => return Ok(false),
+        };
+        if policy.keeps_nothing() {
+            tr_warn!("Retention policy would remove all backups, ignoring it");
+            return Ok(false);
+        }
+        let removed = try!(self.prune_backups(&policy, true, false));
+        if removed.is_empty() {
+            return Ok(true);
+        }
+        if let Some(ratio) = policy.vacuum_ratio {
+            let limits = VacuumLimits {
+                bundles: policy.vacuum_max_bundles,
+                ..Default::default()
+            };
+            try!(self.vacuum(ratio as f32 / 100.0, false, &limits, true));
+        }
+        Ok(true)
+    }
+}
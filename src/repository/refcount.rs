@@ -0,0 +1,337 @@
+use prelude::*;
+
+use std::collections::{hash_map, HashMap};
+use std::path::Path;
+use std::io::{self, BufReader, Read, Write, BufWriter};
+use std::fs::{self, File};
+
+
+static HEADER_STRING: [u8; 7] = *b"zrefcnt";
+static HEADER_VERSION: u8 = 1;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum RefCountsError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description(tr!("Failed to read/write reference counts"))
+        }
+        Decode(err: msgpack::DecodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to decode reference counts"))
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to encode reference counts"))
+        }
+        WrongHeader {
+            description(tr!("Wrong header"))
+        }
+        WrongVersion(version: u8) {
+            description(tr!("Wrong version"))
+            display("{}", tr_format!("Wrong version: {}", version))
+        }
+    }
+}
+
+
+/// Number of backups referencing each chunk and the roots of the counted backups
+#[derive(Default)]
+pub struct RefCounts {
+    chunks: HashMap<Hash, (u32, u32)>,
+    backups: HashMap<String, ChunkList>
+}
+serde_impl!(RefCounts(u8) {
+    chunks: HashMap<Hash, (u32, u32)> => 0,
+    backups: HashMap<String, ChunkList> => 1
+});
+
+impl RefCounts {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RefCountsError> {
+        let mut file = BufReader::new(try!(File::open(path.as_ref())));
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header));
+        if header[..HEADER_STRING.len()] != HEADER_STRING {
+            return Err(RefCountsError::WrongHeader);
+        }
+        let version = header[HEADER_STRING.len()];
+        if version != HEADER_VERSION {
+            return Err(RefCountsError::WrongVersion(version));
+        }
+        Ok(try!(msgpack::decode_from_stream(&mut file)))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), RefCountsError> {
+        // Write to a temporary file first so that the counts are never incomplete
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = BufWriter::new(try!(File::create(&tmp_path)));
+            try!(file.write_all(&HEADER_STRING));
+            try!(file.write_all(&[HEADER_VERSION]));
+            try!(msgpack::encode_to_stream(self, &mut file));
+            try!(file.flush());
+        }
+        try!(fs::rename(&tmp_path, path));
+        Ok(())
+    }
+
+    fn add(&mut self, name: &str, root: &ChunkList, chunks: &HashMap<Hash, u32>) {
+        for (hash, len) in chunks {
+            self.chunks.entry(*hash).or_insert((0, *len)).0 += 1;
+        }
+        self.backups.insert(name.to_string(), root.clone());
+    }
+
+    fn remove(&mut self, name: &str, chunks: &HashMap<Hash, u32>) {
+        for hash in chunks.keys() {
+            let unused = if let Some(entry) = self.chunks.get_mut(hash) {
+                entry.0 = entry.0.saturating_sub(1);
+                entry.0 == 0
+            } else {
+                false
+            };
+            if unused {
+                self.chunks.remove(hash);
+            }
+        }
+        self.backups.remove(name);
+    }
+
+    #[inline]
+    pub fn get(&self, hash: &Hash) -> Option<(u32, u32)> {
+        self.chunks.get(hash).cloned()
+    }
+
+    /// Iterates over all referenced chunks with their reference count and length
+    #[inline]
+    pub fn iter(&self) -> hash_map::Iter<Hash, (u32, u32)> {
+        self.chunks.iter()
+    }
+}
+
+
+impl Repository {
+    fn collect_chunks(
+        &mut self,
+        root: &ChunkList,
+        chunks: &mut HashMap<Hash, u32>,
+    ) -> Result<(), RepositoryError> {
+        fn add(chunks: &mut HashMap<Hash, u32>, list: &[Chunk]) -> bool {
+            let mut new = false;
+            for &(hash, len) in list {
+                if chunks.insert(hash, len).is_none() {
+                    new = true;
+                }
+            }
+            new
+        }
+        let mut todo = vec![root.clone()];
+        while let Some(list) = todo.pop() {
+            if !add(chunks, &list) {
+                continue;
+            }
+            let inode = try!(self.get_inode(&list));
+            match inode.data {
+                None |
+                Some(FileData::Inline(_)) => (),
+                Some(FileData::ChunkedDirect(list)) => {
+                    add(chunks, &list);
+                }
+                Some(FileData::ChunkedIndirect(list)) => {
+                    if add(chunks, &list) {
+                        let chunk_data = try!(self.get_data(&list));
+                        add(chunks, &ChunkList::read_from(&chunk_data));
+                    }
+                }
+            }
+            if let Some(children) = inode.children {
+                for (_name, list) in children {
+                    todo.push(list);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn save_refcounts(&self) -> Result<(), RepositoryError> {
+        if let Some(ref refcounts) = self.refcounts {
+            try!(refcounts.save(self.layout.refcounts_path()));
+        }
+        Ok(())
+    }
+
+    /// Moves the references of a backup to a new root (or removes them if `root` is `None`)
+    fn update_refcounts(&mut self, name: &str, root: Option<&ChunkList>) -> Result<(), RepositoryError> {
+        let old_root = match self.refcounts {
+            Some(ref refcounts) => refcounts.backups.get(name).cloned(),
+            None => return Ok(()),
+        };
+        if old_root.as_ref() == root {
+            return Ok(());
+        }
+        let mut old_chunks = HashMap::new();
+        if let Some(ref old_root) = old_root {
+            try!(self.collect_chunks(old_root, &mut old_chunks));
+        }
+        let mut new_chunks = HashMap::new();
+        if let Some(root) = root {
+            try!(self.collect_chunks(root, &mut new_chunks));
+        }
+        if let Some(ref mut refcounts) = self.refcounts {
+            refcounts.remove(name, &old_chunks);
+            if let Some(root) = root {
+                refcounts.add(name, root, &new_chunks);
+            }
+        }
+        self.save_refcounts()
+    }
+
+    /// Drops the reference counts after a failed update so that they are counted again
+    ///
+    /// The saved counts stay consistent as they record the roots of the counted backups, so
+    /// sync_refcounts can still catch up with them later.
+    fn refcounts_failed(&mut self, err: RepositoryError) {
+        tr_warn!("Failed to update the chunk reference counts, they will be counted again: {}", err);
+        self.refcounts = None;
+    }
+
+    pub fn refcounts_backup_saved(&mut self, name: &str, backup: &Backup) {
+        if let Err(err) = self.update_refcounts(name, Some(&backup.root)) {
+            self.refcounts_failed(err);
+        }
+    }
+
+    pub fn refcounts_backup_deleted(&mut self, name: &str) {
+        if let Err(err) = self.update_refcounts(name, None) {
+            self.refcounts_failed(err);
+        }
+    }
+
+    pub fn recount_references(&mut self) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        tr_info!("Counting chunk references of all backups");
+        let backups = try!(self.get_all_backups());
+        let mut refcounts = RefCounts::default();
+        for (name, backup) in ProgressIter::new(
+            tr!("counting references"),
+            backups.len(),
+            backups.into_iter()
+        )
+        {
+            let mut chunks = HashMap::new();
+            try!(self.collect_chunks(&backup.root, &mut chunks));
+            refcounts.add(&name, &backup.root, &chunks);
+        }
+        self.refcounts = Some(refcounts);
+        self.save_refcounts()
+    }
+
+    /// Brings the reference counts up to date with backups changed by other clients
+    pub fn sync_refcounts(&mut self) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        if self.refcounts.is_none() {
+            return self.recount_references();
+        }
+        let backups = try!(self.get_all_backups());
+        let mut changed = vec![];
+        let mut gone = vec![];
+        if let Some(ref refcounts) = self.refcounts {
+            for (name, root) in &refcounts.backups {
+                match backups.get(name) {
+                    Some(backup) if backup.root == *root => (),
+                    Some(_) => changed.push(name.clone()),
+                    None => gone.push(name.clone()),
+                }
+            }
+            for name in backups.keys() {
+                if !refcounts.backups.contains_key(name) {
+                    changed.push(name.clone());
+                }
+            }
+        }
+        if changed.is_empty() && gone.is_empty() {
+            return Ok(());
+        }
+        tr_info!("Updating chunk references of {} backups", changed.len() + gone.len());
+        for name in &gone {
+            if self.update_refcounts(name, None).is_err() {
+                // The old backup tree can not be read anymore
+                return self.recount_references();
+            }
+        }
+        for name in &changed {
+            if self.update_refcounts(name, Some(&backups[name].root)).is_err() {
+                return self.recount_references();
+            }
+        }
+        Ok(())
+    }
+
+    /// Calculates the bundle usage from the reference counts instead of walking all backups
+    pub fn get_usage(&mut self) -> Result<HashMap<u32, BundleAnalysis>, RepositoryError> {
+        if self.dirty {
+            return Err(RepositoryError::Dirty);
+        }
+        try!(self.sync_refcounts());
+        try!(self.set_dirty());
+        let mut usage = HashMap::new();
+        for (id, bundle) in self.bundle_map.bundles() {
+            let bundle = try!(self.bundles.get_bundle_info(&bundle).ok_or_else(|| {
+                IntegrityError::MissingBundle(bundle)
+            }));
+            usage.insert(
+                id,
+                BundleAnalysis {
+                    chunk_usage: Bitmap::new(bundle.info.chunk_count),
+                    info: bundle.info.clone(),
+                    used_raw_size: 0
+                }
+            );
+        }
+        {
+            // Every referenced chunk must be in the index, otherwise the repository is broken
+            let refcounts = self.refcounts.as_ref().unwrap();
+            for (hash, &(_count, len)) in refcounts.iter() {
+                let location = try!(self.index.get(hash).ok_or_else(|| {
+                    IntegrityError::MissingChunk(*hash)
+                }));
+                if let Some(bundle) = usage.get_mut(&location.bundle) {
+                    bundle.chunk_usage.set(location.chunk as usize);
+                    bundle.used_raw_size += len as usize;
+                } else {
+                    return Err(IntegrityError::MissingBundleId(location.bundle).into());
+                }
+            }
+        }
+        self.dirty = false;
+        Ok(usage)
+    }
+
+    /// Estimates the space vacuum could reclaim, based on the last known reference counts
+    pub fn estimate_reclaimable_size(&self) -> Option<u64> {
+        let refcounts = match self.refcounts {
+            Some(ref refcounts) => refcounts,
+            None => return None,
+        };
+        let mut used_raw_size = HashMap::new();
+        for (hash, location) in self.index.iter() {
+            if let Some((_count, len)) = refcounts.get(hash) {
+                *used_raw_size.entry(location.bundle).or_insert(0) += len as u64;
+            }
+        }
+        let mut reclaimable = 0;
+        for (id, bundle) in self.bundle_map.bundles() {
+            if let Some(bundle) = self.bundles.get_bundle_info(&bundle) {
+                let used = used_raw_size.get(&id).cloned().unwrap_or(0);
+                let ratio = used as f32 / bundle.info.raw_size as f32;
+                reclaimable += ((1.0 - ratio.min(1.0)) * bundle.info.encoded_size as f32) as u64;
+            }
+        }
+        Some(reclaimable)
+    }
+}
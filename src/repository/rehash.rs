@@ -0,0 +1,116 @@
+use prelude::*;
+
+use std::collections::{BTreeMap, HashMap};
+
+
+/// Translation tables from old to new chunk lists, shared by all backups
+#[derive(Default)]
+struct RehashMaps {
+    chunks: HashMap<Hash, Hash>,
+    inodes: HashMap<Vec<Chunk>, ChunkList>
+}
+
+
+impl Repository {
+    fn rehash_chunks(
+        &mut self,
+        mode: BundleMode,
+        chunks: &[Chunk],
+        hash_key: &[u8],
+        maps: &mut RehashMaps,
+    ) -> Result<ChunkList, RepositoryError> {
+        let mut new = Vec::with_capacity(chunks.len());
+        for &(hash, len) in chunks {
+            if let Some(new_hash) = maps.chunks.get(&hash) {
+                new.push((*new_hash, len));
+                continue;
+            }
+            let data = match try!(self.get_chunk(hash)) {
+                Some(data) => data,
+                None => return Err(IntegrityError::MissingChunk(hash).into()),
+            };
+            let new_hash = self.config.hash.hash_keyed(hash_key, &data);
+            let new_hash = try!(self.put_chunk(mode, new_hash, &data));
+            maps.chunks.insert(hash, new_hash);
+            new.push((new_hash, len));
+        }
+        Ok(new.into())
+    }
+
+    fn rehash_inode(
+        &mut self,
+        chunks: &[Chunk],
+        hash_key: &[u8],
+        maps: &mut RehashMaps,
+    ) -> Result<ChunkList, RepositoryError> {
+        // Unchanged subtrees are shared between backups
+        if let Some(new) = maps.inodes.get(chunks) {
+            return Ok(new.clone());
+        }
+        let mut inode = try!(self.get_inode(chunks));
+        inode.data = match inode.data.take() {
+            Some(FileData::ChunkedDirect(list)) => {
+                let list = try!(self.rehash_chunks(BundleMode::Data, &list, hash_key, maps));
+                Some(FileData::ChunkedDirect(list))
+            }
+            Some(FileData::ChunkedIndirect(list)) => {
+                let list = ChunkList::read_from(&try!(self.get_data(&list)));
+                let list = try!(self.rehash_chunks(BundleMode::Data, &list, hash_key, maps));
+                let mut chunk_data = Vec::with_capacity(list.encoded_size());
+                list.write_to(&mut chunk_data).unwrap();
+                Some(FileData::ChunkedIndirect(
+                    try!(self.put_data(BundleMode::Meta, &chunk_data))
+                ))
+            }
+            data => data,
+        };
+        if let Some(children) = inode.children.take() {
+            let mut new_children = BTreeMap::new();
+            for (name, chunks) in children {
+                let chunks = try!(self.rehash_inode(&chunks, hash_key, maps));
+                new_children.insert(name, chunks);
+            }
+            inode.children = Some(new_children);
+        }
+        let new = try!(self.put_inode(&inode));
+        maps.inodes.insert(chunks.to_vec(), new.clone());
+        Ok(new)
+    }
+
+    /// Returns the names of all backups that use another hash method than the repository
+    pub fn get_backups_to_rehash(&self) -> Result<Vec<String>, RepositoryError> {
+        let backups = try!(self.get_all_backups());
+        let mut names: Vec<String> = backups
+            .into_iter()
+            .filter(|&(_, ref backup)| backup.config.hash != self.config.hash)
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Stores all chunks of the backups again under hashes of the configured hash method
+    ///
+    /// The backups are rewritten to refer to the new chunks, the old chunks are not used anymore
+    /// and are removed by rewriting or vacuuming the bundles afterwards. Each backup is saved as
+    /// soon as its new chunks are stored, so an interrupted run can simply be started again and
+    /// only continues with the remaining backups.
+    pub fn rehash_backups(&mut self, names: &[String]) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        let hash_key = try!(self.get_hash_key());
+        let mut maps = RehashMaps::default();
+        for name in ProgressIter::new(tr!("rehashing backups"), names.len(), names.iter()) {
+            let mut backup = try!(self.get_backup(name));
+            if backup.config.hash == self.config.hash {
+                continue;
+            }
+            backup.root = try!(self.rehash_inode(&backup.root, &hash_key, &mut maps));
+            backup.config.hash = self.config.hash;
+            backup.config.hash_key = self.config.hash_key.clone();
+            // The new chunks have to be stored before the backup refers to them
+            try!(self.flush());
+            try!(self.save_backup(&backup, name));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use prelude::*;
+
+use serde_yaml;
+
+use std::fs::{self, File};
+use std::path::Path;
+
+use super::config::ConfigError;
+
+
+/// Progress of a key rotation, stored in the remote storage until it is finished
+struct KeyRotationYaml {
+    old_keys: Vec<String>,
+    old_data_keys: Vec<String>
+}
+impl Default for KeyRotationYaml {
+    fn default() -> Self {
+        KeyRotationYaml {
+            old_keys: vec![],
+            old_data_keys: vec![]
+        }
+    }
+}
+serde_impl!(KeyRotationYaml(String) {
+    old_keys: Vec<String> => "old_keys",
+    old_data_keys: Vec<String> => "old_data_keys"
+});
+
+impl KeyRotationYaml {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let f = try!(File::open(path));
+        Ok(try!(serde_yaml::from_reader(f)))
+    }
+
+    fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        let mut f = try!(File::create(path));
+        try!(serde_yaml::to_writer(&mut f, &self));
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<PublicKey>, ConfigError> {
+        let mut keys = vec![];
+        for key in &self.old_keys {
+            let bytes = try!(parse_hex(key).map_err(|_| {
+                ConfigError::Parse(tr!("Invalid public key"))
+            }));
+            keys.push(try!(PublicKey::from_slice(&bytes).ok_or_else(|| {
+                ConfigError::Parse(tr!("Invalid public key"))
+            })));
+        }
+        Ok(keys)
+    }
+}
+
+
+impl Repository {
+    #[inline]
+    pub fn is_rotating_key(&self) -> bool {
+        self.layout.key_rotation_path().exists()
+    }
+
+    /// Switches the repository to a new key pair and records the keys that have to be replaced
+    ///
+    /// Only the local keys that are current recipients are replaced, other local keys are kept.
+    pub fn start_key_rotation(
+        &mut self,
+        public: PublicKey,
+        secret: SecretKey,
+    ) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        if self.config.encryption.is_none() {
+            return Err(RepositoryError::NotEncrypted);
+        }
+        let local = self.crypto.lock().unwrap().public_keys();
+        let old_keys = try!(self.get_recipients())
+            .into_iter()
+            .filter(|key| *key != public && local.contains(key))
+            .map(|key| to_hex(&key[..]))
+            .collect();
+        // All data keys that exist now will be replaced
+        let mut old_data_keys = vec![];
+        let path = self.layout.data_keys_path();
+        if path.exists() {
+            for entry in try!(fs::read_dir(path)) {
+                let path = try!(entry).path();
+                if path.extension() != Some("yaml".as_ref()) {
+                    continue;
+                }
+                let data_key = try!(DataKeyYaml::load(&path));
+                let id = try!(data_key.get_id());
+                // The hash key is kept, otherwise existing chunks could not be deduplicated
+                if self.config.hash_key.as_ref().map_or(false, |key| key[..] == id[..]) {
+                    continue;
+                }
+                old_data_keys.push(to_hex(&id));
+            }
+        }
+        let rotation = KeyRotationYaml {
+            old_keys,
+            old_data_keys
+        };
+        let recipients = try!(self.rotated_recipients(public));
+        try!(rotation.save(self.layout.key_rotation_path()));
+        try!(self.register_key(public, secret));
+        try!(self.create_data_key(&recipients));
+        if let Some(id) = self.config.hash_key.clone() {
+            try!(self.rewrap_key(&id, &recipients));
+        }
+        self.save_config()
+    }
+
+    /// Makes all data keys accessible only for a new key pair without rewriting any data
+    ///
+    /// Older data keys are rewrapped as well, otherwise the old key pair could still read the
+    /// data that uses them. This does not help if the old secret key could have been used to
+    /// open the data keys.
+    pub fn rewrap_data_key(
+        &mut self,
+        public: PublicKey,
+        secret: SecretKey,
+    ) -> Result<(), RepositoryError> {
+        match self.config.encryption {
+            Some((EncryptionMethod::SecretBox, _)) => (),
+            _ => return Err(RepositoryError::NotEncrypted),
+        }
+        let recipients = try!(self.rotated_recipients(public));
+        try!(self.register_key(public, secret));
+        self.set_recipients(&recipients)
+    }
+
+    /// Replaces the local keys in the recipients by the new key, other recipients are kept
+    fn rotated_recipients(&self, public: PublicKey) -> Result<Vec<PublicKey>, RepositoryError> {
+        let local = self.crypto.lock().unwrap().public_keys();
+        let mut recipients: Vec<PublicKey> = try!(self.get_recipients())
+            .into_iter()
+            .filter(|key| !local.contains(key))
+            .collect();
+        recipients.push(public);
+        Ok(recipients)
+    }
+
+    /// Re-encrypts backups, bundles and dictionaries under the current key and retires the old keys
+    /// when done
+    ///
+    /// Returns `false` if the limits stopped the rotation before all data has been re-encrypted.
+    pub fn continue_key_rotation(&mut self, limits: &VacuumLimits) -> Result<bool, RepositoryError> {
+        try!(self.write_mode());
+        let rotation = try!(KeyRotationYaml::load(self.layout.key_rotation_path()));
+        let old_keys = try!(rotation.keys());
+        let encryption = self.config.encryption.clone();
+        tr_info!("Re-encrypting backup files");
+        let backups = match self.get_all_backups() {
+            Ok(backups) => backups,
+            Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backups, _failed))) => {
+                tr_warn!("Some backups could not be read, ignoring them");
+                backups
+            }
+            Err(err) => return Err(err),
+        };
+        let names: Vec<String> = backups.keys().cloned().collect();
+        for (name, backup) in ProgressIter::new(
+            tr!("re-encrypting backups"),
+            backups.len(),
+            backups.into_iter()
+        )
+        {
+            if try!(self.uses_old_key(&name, &encryption)) {
+                try!(self.save_backup(&backup, &name));
+            }
+        }
+        let selection = RewriteSelection {
+            other_key: true,
+            ..Default::default()
+        };
+        try!(self.rewrite(&selection, limits, true));
+        tr_info!("Re-encrypting dictionaries");
+        // Dictionaries are needed to read the meta bundles, they must not depend on the old keys
+        let dictionaries = try!(self.bundles.reencrypt_dictionaries(encryption.as_ref()));
+        tr_info!("Verifying that the old keys are not used anymore");
+        let bundles = self.list_bundles()
+            .into_iter()
+            .filter(|b| b.encryption != encryption)
+            .count();
+        let mut backups = 0;
+        for name in &names {
+            if try!(self.uses_old_key(name, &encryption)) {
+                backups += 1;
+            }
+        }
+        if bundles > 0 || backups > 0 || dictionaries > 0 {
+            tr_info!(
+                "{} bundles, {} backups and {} dictionaries still use the old keys, run again to continue",
+                bundles,
+                backups,
+                dictionaries
+            );
+            return Ok(false);
+        }
+        {
+            let mut crypto = self.crypto.lock().unwrap();
+            for key in &old_keys {
+                try!(crypto.remove_secret_key(key));
+            }
+        }
+        for id in &rotation.old_data_keys {
+            let path = self.layout.data_keys_path().join(format!("{}.yaml", id));
+            if path.exists() {
+                try!(fs::remove_file(path));
+            }
+        }
+        try!(fs::remove_file(self.layout.key_rotation_path()));
+        tr_info!(
+            "Removed {} old keys, they can not access the repository anymore",
+            old_keys.len()
+        );
+        Ok(true)
+    }
+
+    fn uses_old_key(
+        &self,
+        name: &str,
+        encryption: &Option<Encryption>,
+    ) -> Result<bool, RepositoryError> {
+        let backup_encryption = try!(Backup::get_encryption_from(self.layout.backup_path(name)));
+        Ok(backup_encryption != *encryption)
+    }
+}
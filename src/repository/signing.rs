@@ -0,0 +1,47 @@
+use prelude::*;
+
+
+impl Repository {
+    /// Returns the public key that new backup files and bundles are signed with
+    #[inline]
+    pub fn get_signing_key(&self) -> Option<SigningPublicKey> {
+        self.crypto.lock().unwrap().get_signing_key()
+    }
+
+    /// Creates a signing key pair for this repository and trusts its public key
+    pub fn generate_signing_key(&mut self) -> Result<SigningPublicKey, RepositoryError> {
+        let (public, secret) = Crypto::gen_signing_keypair();
+        try!(self.crypto.lock().unwrap().register_signing_key(
+            public,
+            secret,
+            self.layout.signing_key_path()
+        ));
+        self.trust_signing_key(public);
+        Ok(public)
+    }
+
+    pub fn trust_signing_key(&mut self, key: SigningPublicKey) {
+        if !self.config.get_signing_keys().contains(&key) {
+            self.config.signing_keys.push(key[..].to_vec().into());
+        }
+        self.update_trusted_keys();
+    }
+
+    pub fn untrust_signing_key(&mut self, key: &SigningPublicKey) {
+        self.config.signing_keys.retain(|k| k[..] != key[..]);
+        self.update_trusted_keys();
+    }
+
+    /// In strict mode, unsigned backup files and bundles are refused
+    pub fn set_strict_signatures(&mut self, strict: bool) {
+        self.config.strict_signatures = strict;
+        self.update_trusted_keys();
+    }
+
+    fn update_trusted_keys(&mut self) {
+        self.crypto.lock().unwrap().set_trusted_keys(
+            self.config.get_signing_keys(),
+            self.config.strict_signatures
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use prelude::*;
+
+use std::f32;
+use std::cmp::{max, Ordering};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+use chrono::prelude::*;
+use rand::{thread_rng, Rng};
+use serde_yaml;
+
+
+/// Compression settings that are evaluated, roughly ordered from fast to strong
+static CANDIDATES: [&'static str; 12] = [
+    "lz4/1",
+    "zstd/-5",
+    "zstd/1",
+    "zstd/3",
+    "deflate/6",
+    "brotli/3",
+    "zstd/9",
+    "brotli/6",
+    "zstd/15",
+    "zstd/19",
+    "brotli/9",
+    "lzma/6"
+];
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum TuningError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description(tr!("Failed to read/write compression tuning"))
+        }
+        Yaml(err: serde_yaml::Error) {
+            from()
+            cause(err)
+            description(tr!("Failed to encode compression tuning"))
+        }
+        Compression(err: CompressionError) {
+            from()
+            cause(err)
+            description(tr!("Failed to compress sample"))
+            display("{}", tr_format!("Failed to compress sample\n\tcaused by: {}", err))
+        }
+        NoData {
+            description(tr!("No data bundles to take samples from"))
+        }
+        NoMetaData {
+            description(tr!("No meta bundles to take samples from"))
+        }
+        NoCompression {
+            description(tr!("Dictionaries can not be used without compression"))
+        }
+    }
+}
+
+
+/// The minimal speeds in MiB/s and the maximal size in percent of the sample that a compression
+/// setting must reach to be selected
+pub struct TuningTargets {
+    pub sample_size: usize,
+    pub compress_speed: f32,
+    pub decompress_speed: f32,
+    pub ratio: Option<f32>
+}
+
+
+/// Measurements of one compression setting on the sample
+#[derive(Default, Clone)]
+pub struct CompressionMeasurement {
+    pub compression: String,
+    pub size: u64,
+    pub compress_time: f32,
+    pub decompress_time: f32
+}
+serde_impl!(CompressionMeasurement(String) {
+    compression: String => "compression",
+    size: u64 => "size",
+    compress_time: f32 => "compress_time",
+    decompress_time: f32 => "decompress_time"
+});
+
+
+/// The result of a compression tuning run, stored in the repository for later reference
+#[derive(Default)]
+pub struct CompressionTuning {
+    pub timestamp: i64,
+    pub sample_size: u64,
+    pub target_compress_speed: f32,
+    pub target_decompress_speed: f32,
+    pub target_ratio: Option<f32>,
+    pub measurements: Vec<CompressionMeasurement>,
+    pub selected: Option<String>,
+    pub applied: bool,
+    pub apply_refused: bool
+}
+serde_impl!(CompressionTuning(String) {
+    timestamp: i64 => "timestamp",
+    sample_size: u64 => "sample_size",
+    target_compress_speed: f32 => "target_compress_speed",
+    target_decompress_speed: f32 => "target_decompress_speed",
+    target_ratio: Option<f32> => "target_ratio",
+    measurements: Vec<CompressionMeasurement> => "measurements",
+    selected: Option<String> => "selected",
+    applied: bool => "applied",
+    apply_refused: bool => "apply_refused"
+});
+
+impl CompressionTuning {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), TuningError> {
+        let mut f = try!(File::create(path));
+        try!(serde_yaml::to_writer(&mut f, self));
+        Ok(())
+    }
+
+    /// Speed in MiB/s for the given time to process the sample
+    #[inline]
+    pub fn speed(&self, time: f32) -> f32 {
+        if time > 0.0 {
+            self.sample_size as f32 / time / (1024.0 * 1024.0)
+        } else {
+            f32::INFINITY
+        }
+    }
+
+    /// Size of the compressed sample in percent of the sample size
+    #[inline]
+    pub fn ratio(&self, size: u64) -> f32 {
+        size as f32 / self.sample_size as f32 * 100.0
+    }
+
+    #[inline]
+    pub fn reaches_speeds(&self, m: &CompressionMeasurement) -> bool {
+        self.speed(m.compress_time) >= self.target_compress_speed &&
+            self.speed(m.decompress_time) >= self.target_decompress_speed
+    }
+
+    #[inline]
+    pub fn reaches_ratio(&self, m: &CompressionMeasurement) -> bool {
+        self.target_ratio.map_or(true, |ratio| self.ratio(m.size) <= ratio)
+    }
+
+    /// Selects a setting that reaches all targets
+    ///
+    /// Without a size target, the setting with the smallest output is selected. With a size
+    /// target, any setting reaching it is small enough, so the fastest one is selected.
+    fn select(&self) -> Option<String> {
+        let candidates = self.measurements
+            .iter()
+            .filter(|m| self.reaches_speeds(m) && self.reaches_ratio(m));
+        let selected = if self.target_ratio.is_some() {
+            candidates.min_by(|a, b| {
+                a.compress_time.partial_cmp(&b.compress_time).unwrap_or(Ordering::Equal)
+            })
+        } else {
+            candidates.min_by_key(|m| m.size)
+        };
+        selected.map(|m| m.compression.clone())
+    }
+}
+
+
+/// The result of training a dictionary for meta bundles
+pub struct DictionaryTraining {
+    pub sample_size: u64,
+    pub dictionary_size: usize,
+    pub compression: Compression,
+    pub size_without: u64,
+    pub size_with: u64,
+    pub id: Option<Hash>
+}
+
+
+#[inline]
+fn elapsed_secs(start: Instant) -> f32 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+/// Compresses the chunks into bundle sized streams like the bundle writer and decompresses them
+fn measure_compression(
+    compression: &Compression,
+    chunks: &[Vec<u8>],
+    bundle_size: usize,
+    dictionary: Option<&[u8]>,
+) -> Result<CompressionMeasurement, TuningError> {
+    let compress_stream = || match dictionary {
+        Some(dictionary) => compression.compress_stream_with_dictionary(dictionary),
+        None => compression.compress_stream(),
+    };
+    let decompress_stream = || match dictionary {
+        Some(dictionary) => compression.decompress_stream_with_dictionary(dictionary),
+        None => compression.decompress_stream(),
+    };
+    let mut bundles = vec![];
+    let start = Instant::now();
+    let mut bundle = Vec::with_capacity(bundle_size);
+    let mut stream = try!(compress_stream());
+    for chunk in chunks {
+        try!(stream.process(chunk, &mut bundle));
+        if bundle.len() >= bundle_size {
+            try!(stream.finish(&mut bundle));
+            bundles.push(bundle);
+            bundle = Vec::with_capacity(bundle_size);
+            stream = try!(compress_stream());
+        }
+    }
+    try!(stream.finish(&mut bundle));
+    bundles.push(bundle);
+    let compress_time = elapsed_secs(start);
+    let mut data = Vec::with_capacity(4 * bundle_size);
+    let start = Instant::now();
+    for bundle in &bundles {
+        data.clear();
+        let mut stream = try!(decompress_stream());
+        try!(stream.process(bundle, &mut data));
+        try!(stream.finish(&mut data));
+    }
+    let decompress_time = elapsed_secs(start);
+    Ok(CompressionMeasurement {
+        compression: compression.to_string(),
+        size: bundles.iter().map(|b| b.len() as u64).sum(),
+        compress_time,
+        decompress_time
+    })
+}
+
+
+impl Repository {
+    /// Reads chunks from randomly selected bundles of the given mode until the sample size is
+    /// reached
+    ///
+    /// Bundles with incompressible chunks are skipped as they are stored uncompressed anyway.
+    fn sample_chunks(
+        &mut self,
+        mode: BundleMode,
+        size: usize,
+    ) -> Result<Vec<Vec<u8>>, RepositoryError> {
+        let mut bundles: Vec<BundleId> = self.bundles
+            .list_bundles()
+            .into_iter()
+            .filter(|bundle| bundle.mode == mode && !bundle.incompressible)
+            .map(|bundle| bundle.id.clone())
+            .collect();
+        thread_rng().shuffle(&mut bundles);
+        let mut chunks = vec![];
+        let mut total = 0;
+        for bundle in bundles {
+            if total >= size {
+                break;
+            }
+            let chunk_count = try!(self.bundles.get_chunk_list(&bundle)).len();
+            for id in 0..chunk_count {
+                let data = try!(self.bundles.get_chunk(&bundle, id));
+                total += data.len();
+                chunks.push(data);
+                if total >= size {
+                    break;
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Evaluates several compression settings on a sample of the stored data
+    ///
+    /// A setting that reaches the targets is selected and used for new bundles if `apply` is set.
+    /// If no setting reaches them, no compression is selected and the configuration is not
+    /// changed. The result is stored in the repository.
+    pub fn tune_compression(
+        &mut self,
+        targets: &TuningTargets,
+        apply: bool,
+    ) -> Result<CompressionTuning, RepositoryError> {
+        tr_info!("Reading sample data");
+        let chunks = try!(self.sample_chunks(BundleMode::Data, targets.sample_size));
+        let sample_size = chunks.iter().map(|c| c.len() as u64).sum();
+        if sample_size == 0 {
+            return Err(TuningError::NoData.into());
+        }
+        let mut tuning = CompressionTuning {
+            timestamp: Local::now().timestamp(),
+            sample_size,
+            target_compress_speed: targets.compress_speed,
+            target_decompress_speed: targets.decompress_speed,
+            target_ratio: targets.ratio,
+            ..Default::default()
+        };
+        for name in ProgressIter::new(
+            tr!("testing compressions"),
+            CANDIDATES.len(),
+            CANDIDATES.iter()
+        )
+        {
+            let compression = Compression::from_string(name).unwrap();
+            tuning.measurements.push(try!(
+                measure_compression(&compression, &chunks, self.config.bundle_size, None)
+            ));
+        }
+        tuning.selected = tuning.select();
+        if apply {
+            if let Some(ref name) = tuning.selected {
+                // All candidates are valid settings
+                let compression = Some(Compression::from_string(name).unwrap());
+                if compression != self.config.compression {
+                    self.config.compression = compression;
+                    try!(self.save_config());
+                }
+                tuning.applied = true;
+            } else {
+                // Disabling compression is not what anyone asked for
+                tuning.apply_refused = true;
+            }
+        }
+        try!(tuning.save(self.layout.tuning_path()));
+        Ok(tuning)
+    }
+
+    /// Trains a compression dictionary for meta bundles on a sample of the stored metadata
+    ///
+    /// Half of the sampled chunks is used for training, the other half to compare the
+    /// compression with and without the dictionary. Only if the dictionary helps, it is stored
+    /// in the repository and used for new meta bundles.
+    pub fn train_meta_dictionary(
+        &mut self,
+        sample_size: usize,
+        dictionary_size: usize,
+    ) -> Result<DictionaryTraining, RepositoryError> {
+        let compression = match self.config.get_dictionary_compression() {
+            Some(compression) => compression,
+            None => return Err(TuningError::NoCompression.into()),
+        };
+        tr_info!("Reading sample metadata");
+        let chunks = try!(self.sample_chunks(BundleMode::Meta, sample_size));
+        if chunks.len() < 2 {
+            return Err(TuningError::NoMetaData.into());
+        }
+        let (training, test): (Vec<_>, Vec<_>) = chunks
+            .into_iter()
+            .enumerate()
+            .partition(|&(i, _)| i % 2 == 0);
+        let training: Vec<_> = training.into_iter().map(|(_, chunk)| chunk).collect();
+        let test: Vec<_> = test.into_iter().map(|(_, chunk)| chunk).collect();
+        tr_info!("Training dictionary");
+        let dictionary = try!(train_dictionary(&training, dictionary_size).map_err(
+            TuningError::Compression
+        ));
+        // Meta bundles are typically small as they are finished at the end of each backup
+        let bundle_size = max(self.bundles.statistics().raw_size_meta.avg as usize, 1);
+        let without = try!(measure_compression(&compression, &test, bundle_size, None));
+        let with = try!(measure_compression(
+            &compression,
+            &test,
+            bundle_size,
+            Some(&dictionary[..])
+        ));
+        let mut result = DictionaryTraining {
+            sample_size: test.iter().map(|c| c.len() as u64).sum(),
+            dictionary_size: dictionary.len(),
+            compression,
+            size_without: without.size,
+            size_with: with.size,
+            id: None
+        };
+        if result.size_with < result.size_without {
+            let id = try!(self.bundles.add_dictionary(dictionary, self.config.encryption.as_ref()));
+            self.config.meta_dictionary = Some(id);
+            try!(self.save_config());
+            result.id = Some(id);
+        }
+        Ok(result)
+    }
+}
@@ -1,6 +1,80 @@
 use prelude::*;
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::cmp::Ordering;
+use std::time::Instant;
+
+use super::journal::VacuumJournal;
+
+
+/// Limits for a single vacuum run, remaining bundles are left for later runs
+#[derive(Default, Clone, Debug)]
+pub struct VacuumLimits {
+    pub bundles: Option<usize>,
+    pub bytes: Option<u64>,
+    pub time: Option<i64>
+}
+
+
+/// Filters for bundles that should be rewritten with the current settings
+#[derive(Default, Clone, Debug)]
+pub struct RewriteSelection {
+    pub compression: Option<Option<Compression>>,
+    pub encryption: Option<Option<PublicKey>>,
+    pub before: Option<i64>,
+    pub other_key: bool,
+    pub rehash: bool
+}
+
+impl RewriteSelection {
+    /// Whether the bundle should be rewritten, `readers` are the public keys that can decrypt it
+    pub fn matches(&self, info: &BundleInfo, readers: &[PublicKey], config: &Config) -> bool {
+        let key = info.encryption.as_ref().map(|e| &e.1[..]);
+        let config_key = config.encryption.as_ref().map(|e| &e.1[..]);
+        let other_hash = self.rehash && info.hash_method != config.hash;
+        let same_compression = if info.mode == BundleMode::Meta && config.meta_dictionary.is_some() {
+            info.compression == config.get_dictionary_compression() &&
+                info.dictionary == config.meta_dictionary
+        } else {
+            info.compression == config.compression
+        };
+        // Incompressible chunks would end up uncompressed again
+        let same_compression = same_compression || info.incompressible;
+        let same_format = info.chunk_groups == config.chunk_groups;
+        if same_compression && same_format && key == config_key && !other_hash {
+            // Nothing would change
+            return false;
+        }
+        if self.other_key && key == config_key {
+            return false;
+        }
+        if self.rehash && !other_hash {
+            return false;
+        }
+        if let Some(ref compression) = self.compression {
+            if info.compression != *compression {
+                return false;
+            }
+        }
+        if let Some(ref encryption) = self.encryption {
+            // Data keys are resolved to the public keys they are wrapped for
+            let encrypted_for = match *encryption {
+                Some(ref public) => info.encryption.is_some() && readers.contains(public),
+                None => info.encryption.is_none(),
+            };
+            if !encrypted_for {
+                return false;
+            }
+        }
+        if let Some(before) = self.before {
+            if info.timestamp >= before {
+                return false;
+            }
+        }
+        true
+    }
+}
 
 
 impl Repository {
@@ -13,19 +87,199 @@ impl Repository {
         }
     }
 
+    fn save_vacuum_journal(&self, journal: &VacuumJournal) -> Result<(), RepositoryError> {
+        try!(journal.save(self.layout.vacuum_journal_path()));
+        Ok(())
+    }
+
+    fn remove_vacuum_journal(&self) -> Result<(), RepositoryError> {
+        let path = self.layout.vacuum_journal_path();
+        if path.exists() {
+            try!(fs::remove_file(path));
+        }
+        Ok(())
+    }
+
+    /// Completes a vacuum step that has been interrupted, returns whether there was one
+    ///
+    /// This fails if the repository is locked, as the index must not be used before the vacuum
+    /// is completed.
+    pub fn recover_vacuum(&mut self) -> Result<bool, RepositoryError> {
+        let path = self.layout.vacuum_journal_path();
+        if !path.exists() {
+            return Ok(false);
+        }
+        let journal = try!(VacuumJournal::load(&path));
+        let _lock = match self.lock(true) {
+            Ok(lock) => lock,
+            Err(RepositoryError::Lock(err)) => return Err(RepositoryError::UnfinishedVacuum(err)),
+            Err(err) => return Err(err),
+        };
+        try!(self.write_mode());
+        tr_warn!("Found an unfinished vacuum, completing it");
+        // Bundles that have not been completely rewritten stay in use
+        for bundle in &journal.rewrite {
+            if let Some(id) = self.bundle_map.find(bundle) {
+                let chunks = try!(self.bundles.get_chunk_list(bundle));
+                for (i, (hash, _len)) in chunks.into_inner().into_iter().enumerate() {
+                    try!(self.index.set(
+                        &hash,
+                        &Location {
+                            bundle: id,
+                            chunk: i as u32
+                        }
+                    ));
+                }
+            }
+        }
+        // Bundles that have been rewritten completely can be removed
+        let mut deleted = vec![];
+        for bundle in &journal.delete {
+            if let Some(id) = self.bundle_map.find(bundle) {
+                try!(self.delete_bundle(id));
+                deleted.push(id);
+            }
+        }
+        let mut missing = false;
+        for (_hash, location) in self.index.iter() {
+            if deleted.contains(&location.bundle) {
+                missing = true;
+                break;
+            }
+        }
+        try!(self.save_bundle_map());
+        if missing {
+            // Only clients that did not run the vacuum can get here
+            try!(self.rebuild_index());
+        }
+        try!(self.remove_vacuum_journal());
+        Ok(true)
+    }
+
+    /// Removes rewritten bundles once all their used chunks are stored in new bundles
+    fn finish_vacuum_step(
+        &mut self,
+        rewritten: &mut HashSet<u32>,
+        journal: &mut VacuumJournal,
+    ) -> Result<(), RepositoryError> {
+        if rewritten.is_empty() {
+            return Ok(());
+        }
+        try!(self.flush());
+        for (hash, location) in self.index.iter() {
+            let loc_bundle = location.bundle;
+            let loc_chunk = location.chunk;
+            if rewritten.contains(&loc_bundle) {
+                tr_panic!(
+                    "Removed bundle is still referenced in index: hash:{}, bundle:{}, chunk:{}",
+                    hash,
+                    loc_bundle,
+                    loc_chunk
+                );
+            }
+        }
+        journal.delete = journal.rewrite.drain(..).collect();
+        try!(self.save_vacuum_journal(journal));
+        for id in rewritten.drain() {
+            try!(self.delete_bundle(id));
+        }
+        try!(self.save_bundle_map());
+        journal.delete.clear();
+        self.remove_vacuum_journal()
+    }
+
+    /// Selects bundles in the given order until the limits on bundles and bytes are reached
+    fn apply_vacuum_limits(
+        usage: &HashMap<u32, BundleAnalysis>,
+        candidates: Vec<u32>,
+        limits: &VacuumLimits,
+    ) -> Vec<u32> {
+        let mut selected = vec![];
+        let mut rewrite_data = 0;
+        for id in candidates {
+            if let Some(max_bundles) = limits.bundles {
+                if selected.len() >= max_bundles {
+                    break;
+                }
+            }
+            let bundle = &usage[&id];
+            if let Some(max_bytes) = limits.bytes {
+                if (rewrite_data + bundle.get_used_size()) as u64 > max_bytes {
+                    continue;
+                }
+            }
+            selected.push(id);
+            rewrite_data += bundle.get_used_size();
+        }
+        selected
+    }
+
+    /// Rewrites bundles leaving out unused chunks and returns the number of rewritten bundles
+    fn rewrite_bundles(
+        &mut self,
+        usage: &HashMap<u32, BundleAnalysis>,
+        bundles: &[u32],
+        max_time: Option<i64>,
+    ) -> Result<usize, RepositoryError> {
+        let start = Instant::now();
+        let mut journal = VacuumJournal::default();
+        let mut rewritten = HashSet::new();
+        let mut rewritten_data = 0;
+        let mut deleted = 0;
+        for id in ProgressIter::new(tr!("rewriting bundles"), bundles.len(), bundles.iter()) {
+            if let Some(max_time) = max_time {
+                if start.elapsed().as_secs() as i64 >= max_time {
+                    break;
+                }
+            }
+            let bundle = &usage[id];
+            let bundle_id = self.bundle_map.get(*id).unwrap();
+            journal.rewrite.push(bundle_id.clone());
+            try!(self.save_vacuum_journal(&journal));
+            let chunks = try!(self.bundles.get_chunk_list(&bundle_id));
+            let mode = bundle.info.mode;
+            for (chunk, &(hash, _len)) in chunks.into_iter().enumerate() {
+                if !bundle.chunk_usage.get(chunk) {
+                    try!(self.index.delete(&hash));
+                    continue;
+                }
+                let data = try!(self.bundles.get_chunk(&bundle_id, chunk));
+                try!(self.put_chunk_override(mode, hash, &data));
+            }
+            rewritten.insert(*id);
+            rewritten_data += bundle.used_raw_size;
+            // Keep the repository consistent after every full new bundle
+            if rewritten_data >= self.config.bundle_size {
+                deleted += rewritten.len();
+                try!(self.finish_vacuum_step(&mut rewritten, &mut journal));
+                rewritten_data = 0;
+            }
+        }
+        deleted += rewritten.len();
+        try!(self.finish_vacuum_step(&mut rewritten, &mut journal));
+        if deleted < bundles.len() {
+            tr_info!(
+                "Time limit reached, {} bundles left for the next run",
+                bundles.len() - deleted
+            );
+        }
+        Ok(deleted)
+    }
+
     pub fn vacuum(
         &mut self,
         ratio: f32,
         combine: bool,
+        limits: &VacuumLimits,
         force: bool,
     ) -> Result<(), RepositoryError> {
         try!(self.flush());
         tr_info!("Locking repository");
         try!(self.write_mode());
         let _lock = try!(self.lock(true));
-        // analyze_usage will set the dirty flag
+        // get_usage will set the dirty flag
         tr_info!("Analyzing chunk usage");
-        let usage = try!(self.analyze_usage());
+        let usage = try!(self.get_usage());
         let mut data_total = 0;
         let mut data_used = 0;
         for bundle in usage.values() {
@@ -38,16 +292,18 @@ impl Repository {
             to_file_size(data_total as u64),
             data_used as f32 / data_total as f32 * 100.0
         );
-        let mut rewrite_bundles = HashSet::new();
-        let mut reclaim_space = 0;
-        let mut rewrite_data = 0;
-        for (id, bundle) in &usage {
-            if bundle.get_usage_ratio() <= ratio {
-                rewrite_bundles.insert(*id);
-                reclaim_space += bundle.get_unused_size();
-                rewrite_data += bundle.get_used_size();
-            }
-        }
+        let mut candidates: Vec<u32> = usage
+            .iter()
+            .filter(|&(_, bundle)| bundle.get_usage_ratio() <= ratio)
+            .map(|(id, _)| *id)
+            .collect();
+        // Prefer the bundles that free the most space per rewritten byte
+        candidates.sort_by(|a, b| {
+            usage[b]
+                .get_reclaim_priority()
+                .partial_cmp(&usage[a].get_reclaim_priority())
+                .unwrap_or(Ordering::Equal)
+        });
         if combine {
             let mut small_meta = vec![];
             let mut small_data = vec![];
@@ -59,17 +315,23 @@ impl Repository {
                     }
                 }
             }
-            if small_meta.len() >= 2 {
-                for bundle in small_meta {
-                    rewrite_bundles.insert(bundle);
-                }
-            }
-            if small_data.len() >= 2 {
-                for bundle in small_data {
-                    rewrite_bundles.insert(bundle);
+            for small in &[small_meta, small_data] {
+                if small.len() >= 2 {
+                    for id in small {
+                        if !candidates.contains(id) {
+                            candidates.push(*id);
+                        }
+                    }
                 }
             }
         }
+        let rewrite_bundles = Self::apply_vacuum_limits(&usage, candidates, limits);
+        let mut reclaim_space = 0;
+        let mut rewrite_data = 0;
+        for id in &rewrite_bundles {
+            reclaim_space += usage[id].get_unused_size();
+            rewrite_data += usage[id].get_used_size();
+        }
         tr_info!(
             "Reclaiming about {} by rewriting {} bundles ({})",
             to_file_size(reclaim_space as u64),
@@ -80,44 +342,86 @@ impl Repository {
             self.dirty = false;
             return Ok(());
         }
-        for id in ProgressIter::new(
-            tr!("rewriting bundles"),
-            rewrite_bundles.len(),
-            rewrite_bundles.iter()
-        )
-        {
-            let bundle = &usage[id];
-            let bundle_id = self.bundle_map.get(*id).unwrap();
-            let chunks = try!(self.bundles.get_chunk_list(&bundle_id));
-            let mode = usage[id].info.mode;
-            for (chunk, &(hash, _len)) in chunks.into_iter().enumerate() {
-                if !bundle.chunk_usage.get(chunk) {
-                    try!(self.index.delete(&hash));
-                    continue;
-                }
-                let data = try!(self.bundles.get_chunk(&bundle_id, chunk));
-                try!(self.put_chunk_override(mode, hash, &data));
+        let deleted = try!(self.rewrite_bundles(&usage, &rewrite_bundles, limits.time));
+        tr_info!("Deleted {} bundles", deleted);
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn rewrite(
+        &mut self,
+        selection: &RewriteSelection,
+        limits: &VacuumLimits,
+        force: bool,
+    ) -> Result<(), RepositoryError> {
+        try!(self.flush());
+        tr_info!("Locking repository");
+        try!(self.write_mode());
+        let _lock = try!(self.lock(true));
+        if selection.rehash {
+            let backups = try!(self.get_backups_to_rehash());
+            tr_info!(
+                "Rehashing {} backups with {}",
+                backups.len(),
+                self.config.hash.name()
+            );
+            if force && !backups.is_empty() {
+                try!(self.rehash_backups(&backups));
             }
         }
-        try!(self.flush());
-        tr_info!("Checking index");
-        for (hash, location) in self.index.iter() {
-            let loc_bundle = location.bundle;
-            let loc_chunk = location.chunk;
-            if rewrite_bundles.contains(&loc_bundle) {
-                tr_panic!(
-                    "Removed bundle is still referenced in index: hash:{}, bundle:{}, chunk:{}",
-                    hash,
-                    loc_bundle,
-                    loc_chunk
-                );
+        // get_usage will set the dirty flag
+        tr_info!("Analyzing chunk usage");
+        let usage = try!(self.get_usage());
+        let mut candidates: Vec<u32> = Vec::new();
+        let mut readers_cache = HashMap::new();
+        for (id, bundle) in &usage {
+            let readers = match bundle.info.encryption {
+                Some(ref encryption) => try!(self.get_readers(encryption, &mut readers_cache)),
+                None => vec![],
+            };
+            if selection.matches(&bundle.info, &readers, &self.config) {
+                candidates.push(*id);
             }
         }
-        tr_info!("Deleting {} bundles", rewrite_bundles.len());
-        for id in rewrite_bundles {
-            try!(self.delete_bundle(id));
+        // Oldest bundles first
+        candidates.sort_by_key(|id| usage[id].info.timestamp);
+        let rewrite_bundles = Self::apply_vacuum_limits(&usage, candidates, limits);
+        let mut groups = BTreeMap::new();
+        for id in &rewrite_bundles {
+            let info = &usage[id].info;
+            let compression = info.compression.as_ref().map(|c| c.to_string()).unwrap_or_else(
+                || tr!("none").to_string()
+            );
+            let encryption = info.encryption.as_ref().map(|e| to_hex(&e.1[..])).unwrap_or_else(
+                || tr!("none").to_string()
+            );
+            let group = groups.entry((compression, encryption)).or_insert((0, 0, 0));
+            group.0 += 1;
+            group.1 += info.encoded_size;
+            group.2 += usage[id].get_used_size();
         }
-        try!(self.save_bundle_map());
+        for (&(ref compression, ref encryption), &(count, size, used)) in &groups {
+            tr_info!(
+                "Compression {}, encryption {}: {} bundles, {} ({} used)",
+                compression,
+                encryption,
+                count,
+                to_file_size(size as u64),
+                to_file_size(used as u64)
+            );
+        }
+        let rewrite_data: usize = rewrite_bundles.iter().map(|id| usage[id].get_used_size()).sum();
+        tr_info!(
+            "Rewriting {} bundles ({}) with the current settings",
+            rewrite_bundles.len(),
+            to_file_size(rewrite_data as u64)
+        );
+        if !force {
+            self.dirty = false;
+            return Ok(());
+        }
+        let rewritten = try!(self.rewrite_bundles(&usage, &rewrite_bundles, limits.time));
+        tr_info!("Rewrote {} bundles", rewritten);
         self.dirty = false;
         Ok(())
     }
@@ -41,6 +41,58 @@ pub fn to_duration(dur: f32) -> String {
     format!("{}:{:02}:{:04.1}", hours, mins, secs)
 }
 
+const TIME_SPAN_UNITS: [(char, i64); 6] = [
+    ('y', 365 * 24 * 3600),
+    ('w', 7 * 24 * 3600),
+    ('d', 24 * 3600),
+    ('h', 3600),
+    ('m', 60),
+    ('s', 1)
+];
+
+pub fn parse_time_span(val: &str) -> Option<i64> {
+    let mut total = 0;
+    let mut num = None;
+    for c in val.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            num = num.unwrap_or(0i64).checked_mul(10).and_then(|n| n.checked_add(i64::from(digit)));
+            if num.is_none() {
+                return None;
+            }
+            continue;
+        }
+        let factor = match TIME_SPAN_UNITS.iter().find(|&&(unit, _)| unit == c) {
+            Some(&(_, factor)) => factor,
+            None => return None,
+        };
+        total = match num.take().and_then(|num| num.checked_mul(factor)) {
+            Some(value) => match total.checked_add(value) {
+                Some(total) => total,
+                None => return None,
+            },
+            None => return None,
+        };
+    }
+    if num.is_some() || val.is_empty() {
+        return None;
+    }
+    Some(total)
+}
+
+pub fn to_time_span(mut secs: i64) -> String {
+    if secs == 0 {
+        return "0s".to_string();
+    }
+    let mut span = String::new();
+    for &(unit, factor) in &TIME_SPAN_UNITS {
+        if secs >= factor {
+            span.push_str(&format!("{}{}", secs / factor, unit));
+            secs %= factor;
+        }
+    }
+    span
+}
+
 
 pub struct ProgressIter<T> {
     inner: T,
@@ -131,5 +183,29 @@ mod tests {
         assert_eq!("2:02:02.2", to_duration(7322.2));
     }
 
+    #[test]
+    fn test_parse_time_span() {
+        assert_eq!(Some(30), parse_time_span("30s"));
+        assert_eq!(Some(30 * 24 * 3600), parse_time_span("30d"));
+        assert_eq!(Some(36 * 3600), parse_time_span("1d12h"));
+        assert_eq!(Some(2 * 7 * 24 * 3600), parse_time_span("2w"));
+        assert_eq!(None, parse_time_span(""));
+        assert_eq!(None, parse_time_span("30"));
+        assert_eq!(None, parse_time_span("d"));
+        assert_eq!(None, parse_time_span("5x"));
+        assert_eq!(None, parse_time_span("9223372036854775808s"));
+        assert_eq!(None, parse_time_span("300000000000y"));
+        assert_eq!(None, parse_time_span("200000000000y200000000000y"));
+    }
+
+    #[test]
+    fn test_to_time_span() {
+        assert_eq!("0s", to_time_span(0));
+        assert_eq!("30d", to_time_span(30 * 24 * 3600));
+        assert_eq!("1d12h", to_time_span(36 * 3600));
+        assert_eq!("1h1m1s", to_time_span(3661));
+        assert_eq!(Some(3661), parse_time_span(&to_time_span(3661)));
+    }
+
 
 }
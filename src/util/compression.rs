@@ -2,8 +2,11 @@ use std::ptr;
 use std::ffi::{CStr, CString};
 use std::io::{self, Write};
 use std::str::FromStr;
+use std::cmp::min;
 
 use squash::*;
+use zstd::stream::write::{Encoder as ZstdEncoder, Decoder as ZstdDecoder};
+use zstd::dict;
 
 
 quick_error!{
@@ -13,6 +16,10 @@ quick_error!{
             description(tr!("Unsupported codec"))
             display("{}", tr_format!("Unsupported codec: {}", name))
         }
+        InvalidLevel(name: String) {
+            description(tr!("Invalid compression level"))
+            display("{}", tr_format!("Invalid compression level: {}", name))
+        }
         InitializeCodec {
             description(tr!("Failed to initialize codec"))
         }
@@ -26,6 +33,20 @@ quick_error!{
             description(tr!("Operation failed"))
             display("{}", tr_format!("Operation failed: {}", reason))
         }
+        UnsupportedDictionary(name: String) {
+            description(tr!("Codec does not support dictionaries"))
+            display("{}", tr_format!("Codec does not support dictionaries: {}", name))
+        }
+        Training(err: io::Error) {
+            cause(err)
+            description(tr!("Failed to train dictionary"))
+            display("{}", tr_format!("Failed to train dictionary\n\tcaused by: {}", err))
+        }
+        Codec(err: io::Error) {
+            cause(err)
+            description(tr!("Codec failed"))
+            display("{}", tr_format!("Codec failed\n\tcaused by: {}", err))
+        }
         Output(err: io::Error) {
             from()
             cause(err)
@@ -34,25 +55,89 @@ quick_error!{
     }
 }
 
+/// Chunks with a higher byte entropy (in bits per byte) are considered incompressible
+const INCOMPRESSIBLE_ENTROPY: f32 = 7.9;
+
+/// Smaller chunks are never considered incompressible as their entropy can not be estimated well
+const MIN_ENTROPY_SAMPLE: usize = 1024;
+
+/// Size of the sample that is compressed to confirm that a chunk is incompressible
+const TRIAL_COMPRESSION_SAMPLE: usize = 16 * 1024;
+
+/// Samples that compress to a larger fraction of their size are considered incompressible
+const TRIAL_COMPRESSION_RATIO: f32 = 0.97;
+
+
+/// Trains a zstd dictionary of at most `max_size` bytes on the given samples
+///
+/// Dictionaries help when many small and similar pieces of data are compressed, as the common
+/// structure does not have to be learned from scratch in every stream.
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>, CompressionError> {
+    dict::from_samples(samples, max_size).map_err(CompressionError::Training)
+}
+
+
+/// Estimates the entropy of the data in bits per byte from the byte frequencies
+pub fn byte_entropy(data: &[u8]) -> f32 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+    let len = data.len() as f32;
+    counts.iter().filter(|&&count| count > 0).fold(0.0, |entropy, &count| {
+        let p = count as f32 / len;
+        entropy - p * p.log2()
+    })
+}
+
+/// Checks whether compressing the data would most likely not save any space
+///
+/// Already compressed or encrypted data (e.g. JPEG images, videos or archives) looks random and
+/// has a byte entropy close to 8 bits. As the byte entropy ignores the order of the bytes, data
+/// like tables or repeating patterns can have a high entropy too, so a sample of the data is
+/// compressed with a fast method to confirm the estimation.
+pub fn is_incompressible(data: &[u8]) -> bool {
+    if data.len() < MIN_ENTROPY_SAMPLE || byte_entropy(data) < INCOMPRESSIBLE_ENTROPY {
+        return false;
+    }
+    let sample = &data[..min(data.len(), TRIAL_COMPRESSION_SAMPLE)];
+    let trial = Compression {
+        method: CompressionMethod::Zstd,
+        level: 1
+    };
+    match trial.compress(sample) {
+        Ok(compressed) => compressed.len() as f32 >= sample.len() as f32 * TRIAL_COMPRESSION_RATIO,
+        Err(_) => false,
+    }
+}
+
+
 #[derive(Clone, Debug, Copy, Eq, PartialEq, Hash)]
 pub enum CompressionMethod {
     Deflate, // Standardized
     Brotli, // Good speed and ratio
     Lzma, // Very good ratio, slow
-    Lz4 // Very fast, low ratio
+    Lz4, // Very fast, low ratio
+    Zstd, // Fast, good ratio, wide range of levels
+    ZstdLong // Zstd with long distance matching
 }
 serde_impl!(CompressionMethod(u8) {
     Deflate => 0,
     Brotli => 1,
     Lzma => 2,
-    Lz4 => 3
+    Lz4 => 3,
+    Zstd => 4,
+    ZstdLong => 5
 });
 
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Compression {
     method: CompressionMethod,
-    level: u8
+    level: i8
 }
 impl Default for Compression {
     fn default() -> Self {
@@ -64,7 +149,7 @@ impl Default for Compression {
 }
 serde_impl!(Compression(u64) {
     method: CompressionMethod => 0,
-    level: u8 => 1
+    level: i8 => 1
 });
 
 
@@ -76,7 +161,7 @@ impl Compression {
 
     pub fn from_string(name: &str) -> Result<Self, CompressionError> {
         let (name, level) = if let Some(pos) = name.find('/') {
-            let level = try!(u8::from_str(&name[pos + 1..]).map_err(|_| {
+            let level = try!(i8::from_str(&name[pos + 1..]).map_err(|_| {
                 CompressionError::UnsupportedCodec(name.to_string())
             }));
             let name = &name[..pos];
@@ -89,8 +174,19 @@ impl Compression {
             "brotli" => CompressionMethod::Brotli,
             "lzma" | "lzma2" | "xz" => CompressionMethod::Lzma,
             "lz4" => CompressionMethod::Lz4,
+            "zstd" => CompressionMethod::Zstd,
+            "zstd-long" => CompressionMethod::ZstdLong,
             _ => return Err(CompressionError::UnsupportedCodec(name.to_string())),
         };
+        // Only zstd has negative levels, they trade compression ratio for speed
+        let valid = if method.is_zstd() {
+            level >= -100 && level <= 22
+        } else {
+            level >= 0
+        };
+        if !valid {
+            return Err(CompressionError::InvalidLevel(format!("{}/{}", name, level)));
+        }
         Ok(Compression {
             method,
             level
@@ -103,6 +199,8 @@ impl Compression {
             CompressionMethod::Brotli => "brotli",
             CompressionMethod::Lzma => "lzma",
             CompressionMethod::Lz4 => "lz4",
+            CompressionMethod::Zstd => "zstd",
+            CompressionMethod::ZstdLong => "zstd-long",
         }
     }
 
@@ -116,7 +214,7 @@ impl Compression {
     }
 
     #[inline]
-    pub fn level(&self) -> u8 {
+    pub fn level(&self) -> i8 {
         self.level
     }
 
@@ -147,7 +245,21 @@ impl Compression {
         })
     }
 
+    fn process_all(
+        mut stream: CompressionStream,
+        data: &[u8],
+        size: usize,
+    ) -> Result<Vec<u8>, CompressionError> {
+        let mut buf = Vec::with_capacity(size);
+        try!(stream.process(data, &mut buf));
+        try!(stream.finish(&mut buf));
+        Ok(buf)
+    }
+
     pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        if self.method.is_zstd() {
+            return Self::process_all(try!(self.compress_stream()), data, data.len() / 2 + 500);
+        }
         let codec = try!(self.codec());
         let options = try!(self.options());
         let mut size = data.len() * 2 + 500;
@@ -177,6 +289,9 @@ impl Compression {
     }
 
     pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        if self.method.is_zstd() {
+            return Self::process_all(try!(self.decompress_stream()), data, data.len() * 4);
+        }
         let codec = try!(self.codec());
         let mut size =
             unsafe { squash_codec_get_uncompressed_size(codec, data.len(), data.as_ptr()) };
@@ -202,6 +317,12 @@ impl Compression {
     }
 
     pub fn compress_stream(&self) -> Result<CompressionStream, CompressionError> {
+        if self.method.is_zstd() {
+            let encoder = try!(ZstdEncoder::new(Vec::new(), i32::from(self.level)).map_err(
+                |_| CompressionError::InitializeStream
+            ));
+            return self.zstd_compress_stream(encoder);
+        }
         let codec = try!(self.codec());
         let options = try!(self.options());
         let stream =
@@ -209,31 +330,112 @@ impl Compression {
         if stream.is_null() {
             return Err(CompressionError::InitializeStream);
         }
-        Ok(CompressionStream::new(stream))
+        Ok(CompressionStream::new(StreamBackend::Squash(SquashStreamHandle(stream))))
+    }
+
+    /// Creates a compression stream that uses a dictionary trained with `train_dictionary`
+    ///
+    /// Only zstd supports dictionaries. The same dictionary is needed for decompression.
+    pub fn compress_stream_with_dictionary(
+        &self,
+        dictionary: &[u8],
+    ) -> Result<CompressionStream, CompressionError> {
+        if !self.method.is_zstd() {
+            return Err(CompressionError::UnsupportedDictionary(self.to_string()));
+        }
+        let encoder = try!(
+            ZstdEncoder::with_dictionary(Vec::new(), i32::from(self.level), dictionary)
+                .map_err(|_| CompressionError::InitializeStream)
+        );
+        self.zstd_compress_stream(encoder)
+    }
+
+    fn zstd_compress_stream(
+        &self,
+        mut encoder: ZstdEncoder<Vec<u8>>,
+    ) -> Result<CompressionStream, CompressionError> {
+        if self.method == CompressionMethod::ZstdLong {
+            try!(encoder.long_distance_matching(true).map_err(|_| {
+                CompressionError::InitializeOptions
+            }));
+        }
+        Ok(CompressionStream::new(StreamBackend::ZstdCompress(encoder)))
     }
 
     pub fn decompress_stream(&self) -> Result<CompressionStream, CompressionError> {
+        if self.method.is_zstd() {
+            let decoder = try!(ZstdDecoder::new(Vec::new()).map_err(|_| {
+                CompressionError::InitializeStream
+            }));
+            return Ok(CompressionStream::new(StreamBackend::ZstdDecompress(decoder)));
+        }
         let codec = try!(self.codec());
         let stream =
             unsafe { squash_stream_new(codec, SQUASH_STREAM_DECOMPRESS, ptr::null::<()>()) };
         if stream.is_null() {
             return Err(CompressionError::InitializeStream);
         }
-        Ok(CompressionStream::new(stream))
+        Ok(CompressionStream::new(StreamBackend::Squash(SquashStreamHandle(stream))))
+    }
+
+    #[inline]
+    pub fn supports_dictionary(&self) -> bool {
+        self.method.is_zstd()
+    }
+
+    pub fn decompress_stream_with_dictionary(
+        &self,
+        dictionary: &[u8],
+    ) -> Result<CompressionStream, CompressionError> {
+        if !self.method.is_zstd() {
+            return Err(CompressionError::UnsupportedDictionary(self.to_string()));
+        }
+        let decoder = try!(ZstdDecoder::with_dictionary(Vec::new(), dictionary).map_err(|_| {
+            CompressionError::InitializeStream
+        }));
+        Ok(CompressionStream::new(StreamBackend::ZstdDecompress(decoder)))
+    }
+}
+
+impl CompressionMethod {
+    #[inline]
+    fn is_zstd(&self) -> bool {
+        *self == CompressionMethod::Zstd || *self == CompressionMethod::ZstdLong
     }
 }
 
 
+struct SquashStreamHandle(*mut SquashStream);
+
+impl Drop for SquashStreamHandle {
+    fn drop(&mut self) {
+        unsafe {
+            //squash_object_unref(self.0 as *mut ::std::os::raw::c_void);
+            use libc;
+            squash_object_unref(self.0 as *mut libc::c_void);
+        }
+    }
+}
+
+
+enum StreamBackend {
+    Squash(SquashStreamHandle),
+    // Zstd is not used via squash to support negative levels and long distance matching
+    ZstdCompress(ZstdEncoder<Vec<u8>>),
+    ZstdDecompress(ZstdDecoder<Vec<u8>>)
+}
+
+
 pub struct CompressionStream {
-    stream: *mut SquashStream,
+    backend: StreamBackend,
     buffer: [u8; 16 * 1024]
 }
 
 impl CompressionStream {
     #[inline]
-    fn new(stream: *mut SquashStream) -> Self {
+    fn new(backend: StreamBackend) -> Self {
         CompressionStream {
-            stream,
+            backend,
             buffer: [0; 16 * 1024]
         }
     }
@@ -243,54 +445,70 @@ impl CompressionStream {
         input: &[u8],
         output: &mut W,
     ) -> Result<(), CompressionError> {
-        let stream = unsafe { &mut (*self.stream) };
-        stream.next_in = input.as_ptr();
-        stream.avail_in = input.len();
-        loop {
-            stream.next_out = self.buffer.as_mut_ptr();
-            stream.avail_out = self.buffer.len();
-            let res = unsafe { squash_stream_process(stream) };
-            if res < 0 {
-                return Err(Compression::error(res));
+        match self.backend {
+            StreamBackend::Squash(ref handle) => {
+                let stream = unsafe { &mut (*handle.0) };
+                stream.next_in = input.as_ptr();
+                stream.avail_in = input.len();
+                loop {
+                    stream.next_out = self.buffer.as_mut_ptr();
+                    stream.avail_out = self.buffer.len();
+                    let res = unsafe { squash_stream_process(stream) };
+                    if res < 0 {
+                        return Err(Compression::error(res));
+                    }
+                    let output_size = self.buffer.len() - stream.avail_out;
+                    try!(output.write_all(&self.buffer[..output_size]));
+                    if res != SQUASH_PROCESSING {
+                        break;
+                    }
+                }
             }
-            let output_size = self.buffer.len() - stream.avail_out;
-            try!(output.write_all(&self.buffer[..output_size]));
-            if res != SQUASH_PROCESSING {
-                break;
+            StreamBackend::ZstdCompress(ref mut encoder) => {
+                try!(encoder.write_all(input).map_err(CompressionError::Codec));
+                try!(output.write_all(encoder.get_ref()));
+                encoder.get_mut().clear();
+            }
+            StreamBackend::ZstdDecompress(ref mut decoder) => {
+                try!(decoder.write_all(input).map_err(CompressionError::Codec));
+                try!(output.write_all(decoder.get_ref()));
+                decoder.get_mut().clear();
             }
         }
         Ok(())
     }
 
     pub fn finish<W: Write>(mut self, output: &mut W) -> Result<(), CompressionError> {
-        let stream = unsafe { &mut (*self.stream) };
-        loop {
-            stream.next_out = self.buffer.as_mut_ptr();
-            stream.avail_out = self.buffer.len();
-            let res = unsafe { squash_stream_finish(stream) };
-            if res < 0 {
-                return Err(Compression::error(res));
+        match self.backend {
+            StreamBackend::Squash(ref handle) => {
+                let stream = unsafe { &mut (*handle.0) };
+                loop {
+                    stream.next_out = self.buffer.as_mut_ptr();
+                    stream.avail_out = self.buffer.len();
+                    let res = unsafe { squash_stream_finish(stream) };
+                    if res < 0 {
+                        return Err(Compression::error(res));
+                    }
+                    let output_size = self.buffer.len() - stream.avail_out;
+                    try!(output.write_all(&self.buffer[..output_size]));
+                    if res != SQUASH_PROCESSING {
+                        break;
+                    }
+                }
+            }
+            StreamBackend::ZstdCompress(encoder) => {
+                let rest = try!(encoder.finish().map_err(CompressionError::Codec));
+                try!(output.write_all(&rest));
             }
-            let output_size = self.buffer.len() - stream.avail_out;
-            try!(output.write_all(&self.buffer[..output_size]));
-            if res != SQUASH_PROCESSING {
-                break;
+            StreamBackend::ZstdDecompress(mut decoder) => {
+                try!(decoder.flush().map_err(CompressionError::Codec));
+                try!(output.write_all(&decoder.into_inner()));
             }
         }
         Ok(())
     }
 }
 
-impl Drop for CompressionStream {
-    fn drop(&mut self) {
-        unsafe {
-            //squash_object_unref(self.stream as *mut ::std::os::raw::c_void);
-            use libc;
-            squash_object_unref(self.stream as *mut libc::c_void);
-        }
-    }
-}
-
 
 mod tests {
 
@@ -315,6 +533,15 @@ mod tests {
         assert_eq!(("lzma", 3), (method.name(), method.level()));
         let method = Compression::from_string("lz4/1").unwrap();
         assert_eq!(("lz4", 1), (method.name(), method.level()));
+        let method = Compression::from_string("zstd/19").unwrap();
+        assert_eq!(("zstd", 19), (method.name(), method.level()));
+        let method = Compression::from_string("zstd/-5").unwrap();
+        assert_eq!(("zstd", -5), (method.name(), method.level()));
+        let method = Compression::from_string("zstd-long/3").unwrap();
+        assert_eq!(("zstd-long", 3), (method.name(), method.level()));
+        assert!(Compression::from_string("zstd/23").is_err());
+        assert!(Compression::from_string("brotli/-1").is_err());
+        assert!(Compression::from_string("foo/1").is_err());
     }
 
     #[test]
@@ -327,6 +554,10 @@ mod tests {
             "deflate/1",
             Compression::from_string("gzip/1").unwrap().to_string()
         );
+        assert_eq!(
+            "zstd/-3",
+            Compression::from_string("zstd/-3").unwrap().to_string()
+        );
     }
 
     #[allow(dead_code, needless_range_loop)]
@@ -338,8 +569,28 @@ mod tests {
         input
     }
 
+    #[test]
+    fn test_byte_entropy() {
+        assert_eq!(byte_entropy(&[]), 0.0);
+        assert_eq!(byte_entropy(&[42; 1000]), 0.0);
+        assert!((byte_entropy(&[0, 1, 0, 1]) - 1.0).abs() < 0.001);
+        let all: Vec<u8> = (0..256 * 16).map(|i| i as u8).collect();
+        assert!((byte_entropy(&all) - 8.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_is_incompressible() {
+        use sodiumoxide::randombytes::randombytes;
+        assert!(is_incompressible(&randombytes(64 * 1024)));
+        assert!(!is_incompressible(&randombytes(100)));
+        assert!(!is_incompressible(&test_data(64 * 1024)));
+        assert!(!is_incompressible(&[0; 64 * 1024]));
+        let ramp: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        assert!(!is_incompressible(&ramp));
+    }
+
     #[allow(dead_code)]
-    fn test_compression(method: &str, min_lvl: u8, max_lvl: u8) {
+    fn test_compression(method: &str, min_lvl: i8, max_lvl: i8) {
         let input = test_data(16 * 1024);
         for i in min_lvl..max_lvl + 1 {
             let method = Compression::from_string(&format!("{}/{}", method, i)).unwrap();
@@ -373,8 +624,18 @@ mod tests {
         test_compression("lz4", 1, 11)
     }
 
+    #[test]
+    fn test_compression_zstd() {
+        test_compression("zstd", -5, 19)
+    }
+
+    #[test]
+    fn test_compression_zstd_long() {
+        test_compression("zstd-long", 1, 9)
+    }
+
     #[allow(dead_code)]
-    fn test_stream_compression(method: &str, min_lvl: u8, max_lvl: u8) {
+    fn test_stream_compression(method: &str, min_lvl: i8, max_lvl: i8) {
         let input = test_data(512 * 1024);
         for i in min_lvl..max_lvl + 1 {
             let method = Compression::from_string(&format!("{}/{}", method, i)).unwrap();
@@ -416,6 +677,51 @@ mod tests {
         test_stream_compression("lz4", 1, 11)
     }
 
+    #[test]
+    fn test_stream_compression_zstd() {
+        test_stream_compression("zstd", -5, 19)
+    }
+
+    #[test]
+    fn test_stream_compression_zstd_long() {
+        test_stream_compression("zstd-long", 1, 9)
+    }
+
+    #[allow(dead_code)]
+    fn dictionary_samples() -> Vec<Vec<u8>> {
+        (0..1000)
+            .map(|i| format!("{{name: \"file{}.txt\", mode: 420, size: {}}}", i, i * 37).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_compression_dictionary() {
+        let samples = dictionary_samples();
+        let dictionary = train_dictionary(&samples, 4 * 1024).unwrap();
+        assert!(!dictionary.is_empty());
+        assert!(dictionary.len() <= 4 * 1024);
+        let method = Compression::from_string("zstd/3").unwrap();
+        let input = &samples[42];
+        let mut compressor = method.compress_stream_with_dictionary(&dictionary).unwrap();
+        let mut compressed = vec![];
+        compressor.process(input, &mut compressed).unwrap();
+        compressor.finish(&mut compressed).unwrap();
+        let mut decompressor = method.decompress_stream_with_dictionary(&dictionary).unwrap();
+        let mut decompressed = vec![];
+        decompressor.process(&compressed, &mut decompressed).unwrap();
+        decompressor.finish(&mut decompressed).unwrap();
+        assert_eq!(input, &decompressed);
+        assert!(compressed.len() < method.compress(input).unwrap().len());
+    }
+
+    #[test]
+    fn test_dictionary_unsupported() {
+        let method = Compression::from_string("brotli/3").unwrap();
+        assert!(!method.supports_dictionary());
+        assert!(method.compress_stream_with_dictionary(&[0; 16]).is_err());
+        assert!(method.decompress_stream_with_dictionary(&[0; 16]).is_err());
+    }
+
 }
 
 
@@ -870,4 +1176,55 @@ mod benches {
         bench_stream_decompression(b, Compression::from_string("lz4/11").unwrap())
     }
 
+
+    #[bench]
+    fn bench_zstd_fast5_compress(b: &mut Bencher) {
+        bench_stream_compression(b, Compression::from_string("zstd/-5").unwrap())
+    }
+
+    #[bench]
+    fn bench_zstd_1_compress(b: &mut Bencher) {
+        bench_stream_compression(b, Compression::from_string("zstd/1").unwrap())
+    }
+
+    #[bench]
+    fn bench_zstd_3_compress(b: &mut Bencher) {
+        bench_stream_compression(b, Compression::from_string("zstd/3").unwrap())
+    }
+
+    #[bench]
+    fn bench_zstd_9_compress(b: &mut Bencher) {
+        bench_stream_compression(b, Compression::from_string("zstd/9").unwrap())
+    }
+
+    #[bench]
+    fn bench_zstd_19_compress(b: &mut Bencher) {
+        bench_stream_compression(b, Compression::from_string("zstd/19").unwrap())
+    }
+
+    #[bench]
+    fn bench_zstd_fast5_decompress(b: &mut Bencher) {
+        bench_stream_decompression(b, Compression::from_string("zstd/-5").unwrap())
+    }
+
+    #[bench]
+    fn bench_zstd_1_decompress(b: &mut Bencher) {
+        bench_stream_decompression(b, Compression::from_string("zstd/1").unwrap())
+    }
+
+    #[bench]
+    fn bench_zstd_3_decompress(b: &mut Bencher) {
+        bench_stream_decompression(b, Compression::from_string("zstd/3").unwrap())
+    }
+
+    #[bench]
+    fn bench_zstd_9_decompress(b: &mut Bencher) {
+        bench_stream_decompression(b, Compression::from_string("zstd/9").unwrap())
+    }
+
+    #[bench]
+    fn bench_zstd_19_decompress(b: &mut Bencher) {
+        bench_stream_decompression(b, Compression::from_string("zstd/19").unwrap())
+    }
+
 }
@@ -1,8 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use std::io;
 use std::fs::{self, File};
 use std::sync::{Once, ONCE_INIT};
+use std::cell::Cell;
 
 use serde_yaml;
 use serde_bytes::ByteBuf;
@@ -10,9 +11,15 @@ use serde_bytes::ByteBuf;
 use libsodium_sys;
 use sodiumoxide;
 use sodiumoxide::crypto::sealedbox;
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::randombytes::randombytes;
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::sign;
+use byteorder::{BigEndian, ByteOrder};
 pub use sodiumoxide::crypto::box_::{SecretKey, PublicKey};
+pub use sodiumoxide::crypto::sign::{PublicKey as SigningPublicKey,
+                                    SecretKey as SigningSecretKey};
 
 use util::*;
 
@@ -35,10 +42,37 @@ quick_error!{
             description(tr!("Missing key"))
             display("{}", tr_format!("Missing key: {}", to_hex(&key[..])))
         }
+        WrongPassphrase {
+            description(tr!("Wrong passphrase"))
+        }
+        Passphrase(err: io::Error) {
+            cause(err)
+            description(tr!("Failed to read passphrase"))
+            display("{}", tr_format!("Failed to read passphrase: {}", err))
+        }
+        Unsigned {
+            description(tr!("Data is not signed"))
+        }
+        UntrustedSignature {
+            description(tr!("Signature can not be verified with a trusted key"))
+        }
+        BadSignature {
+            description(tr!("Invalid signature"))
+        }
+        MissingDataKey(id: Vec<u8>) {
+            description(tr!("Missing data key"))
+            display("{}", tr_format!("Missing data key: {}", to_hex(id)))
+        }
         Operation(reason: &'static str) {
             description(tr!("Operation failed"))
             display("{}", tr_format!("Operation failed: {}", reason))
         }
+        Shamir(err: ShamirError) {
+            from()
+            cause(err)
+            description(tr!("Secret sharing error"))
+            display("{}", tr_format!("Secret sharing error: {}", err))
+        }
         Io(err: io::Error) {
             from()
             cause(err)
@@ -58,16 +92,19 @@ quick_error!{
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 #[allow(unknown_lints, non_camel_case_types)]
 pub enum EncryptionMethod {
-    Sodium
+    Sodium,
+    SecretBox
 }
 serde_impl!(EncryptionMethod(u64) {
-    Sodium => 0
+    Sodium => 0,
+    SecretBox => 1
 });
 
 impl EncryptionMethod {
     pub fn from_string(val: &str) -> Result<Self, &'static str> {
         match val {
             "sodium" => Ok(EncryptionMethod::Sodium),
+            "secretbox" => Ok(EncryptionMethod::SecretBox),
             _ => Err(tr!("Unsupported encryption method")),
         }
     }
@@ -75,29 +112,43 @@ impl EncryptionMethod {
     pub fn to_string(&self) -> String {
         match *self {
             EncryptionMethod::Sodium => "sodium".to_string(),
+            EncryptionMethod::SecretBox => "secretbox".to_string(),
         }
     }
 }
 
 
+/// Encryption method and key
+///
+/// For `Sodium` the key is the public key that the data is sealed to, for `SecretBox` it is the
+/// id of the data key.
 pub type Encryption = (EncryptionMethod, ByteBuf);
 
 
+/// Stored key pair
+///
+/// If `salt` is set, the secret key is encrypted with a key derived from a passphrase and `nonce`.
 pub struct KeyfileYaml {
     public: String,
-    secret: String
+    secret: String,
+    salt: String,
+    nonce: String
 }
 impl Default for KeyfileYaml {
     fn default() -> Self {
         KeyfileYaml {
             public: "".to_string(),
-            secret: "".to_string()
+            secret: "".to_string(),
+            salt: "".to_string(),
+            nonce: "".to_string()
         }
     }
 }
 serde_impl!(KeyfileYaml(String) {
     public: String => "public",
-    secret: String => "secret"
+    secret: String => "secret",
+    salt: String => "salt",
+    nonce: String => "nonce"
 });
 
 impl KeyfileYaml {
@@ -111,12 +162,219 @@ impl KeyfileYaml {
         try!(serde_yaml::to_writer(&mut f, &self));
         Ok(())
     }
+
+    #[inline]
+    pub fn is_protected(&self) -> bool {
+        !self.salt.is_empty()
+    }
+
+    pub fn get_public(&self) -> Result<PublicKey, EncryptionError> {
+        let public = try!(parse_hex(&self.public).map_err(|_| EncryptionError::InvalidKey));
+        PublicKey::from_slice(&public).ok_or(EncryptionError::InvalidKey)
+    }
+
+    /// Returns the secret key, decrypting it with the passphrase if the keyfile is protected
+    pub fn get_secret(&self, passphrase: Option<&str>) -> Result<SecretKey, EncryptionError> {
+        let secret = try!(open_secret(&self.secret, &self.salt, &self.nonce, passphrase));
+        SecretKey::from_slice(&secret).ok_or(EncryptionError::InvalidKey)
+    }
+
+    /// Creates a keyfile, the secret key is encrypted if a passphrase is given
+    pub fn new(public: &PublicKey, secret: &SecretKey, passphrase: Option<&str>) -> Self {
+        let (secret, salt, nonce) = seal_secret(&secret[..], passphrase);
+        KeyfileYaml {
+            public: to_hex(&public[..]),
+            secret,
+            salt,
+            nonce
+        }
+    }
+}
+
+/// Encodes a secret as hex, encrypted with a key derived from the passphrase if one is given
+///
+/// Returns the secret, the salt and the nonce, the latter two are empty without a passphrase.
+fn seal_secret(secret: &[u8], passphrase: Option<&str>) -> (String, String, String) {
+    if let Some(passphrase) = passphrase {
+        sodium_init();
+        let salt = pwhash::gen_salt();
+        let nonce = secretbox::gen_nonce();
+        let key = passphrase_key(passphrase, &salt);
+        (
+            to_hex(&secretbox::seal(secret, &nonce, &key)),
+            to_hex(&salt[..]),
+            to_hex(&nonce[..])
+        )
+    } else {
+        (to_hex(secret), "".to_string(), "".to_string())
+    }
+}
+
+/// Decodes a secret created by `seal_secret`
+fn open_secret(
+    secret: &str,
+    salt: &str,
+    nonce: &str,
+    passphrase: Option<&str>,
+) -> Result<Vec<u8>, EncryptionError> {
+    let secret = try!(parse_hex(secret).map_err(|_| EncryptionError::InvalidKey));
+    if salt.is_empty() {
+        return Ok(secret);
+    }
+    let passphrase = try!(passphrase.ok_or(EncryptionError::WrongPassphrase));
+    let salt = try!(parse_hex(salt).map_err(|_| EncryptionError::InvalidKey));
+    let salt = try!(pwhash::Salt::from_slice(&salt).ok_or(EncryptionError::InvalidKey));
+    let nonce = try!(parse_hex(nonce).map_err(|_| EncryptionError::InvalidKey));
+    let nonce = try!(secretbox::Nonce::from_slice(&nonce).ok_or(
+        EncryptionError::InvalidKey
+    ));
+    let key = passphrase_key(passphrase, &salt);
+    secretbox::open(&secret, &nonce, &key).map_err(|_| EncryptionError::WrongPassphrase)
+}
+
+fn passphrase_key(passphrase: &str, salt: &pwhash::Salt) -> secretbox::Key {
+    let mut key = [0u8; secretbox::KEYBYTES];
+    pwhash::derive_key(
+        &mut key,
+        passphrase.as_bytes(),
+        salt,
+        pwhash::OPSLIMIT_INTERACTIVE,
+        pwhash::MEMLIMIT_INTERACTIVE
+    ).unwrap();
+    secretbox::Key(key)
+}
+
+
+/// Symmetric data key, sealed to the public keys of all recipients
+pub struct DataKeyYaml {
+    id: String,
+    wrapped: BTreeMap<String, String>
+}
+impl Default for DataKeyYaml {
+    fn default() -> Self {
+        DataKeyYaml {
+            id: "".to_string(),
+            wrapped: BTreeMap::new()
+        }
+    }
+}
+serde_impl!(DataKeyYaml(String) {
+    id: String => "id",
+    wrapped: BTreeMap<String, String> => "wrapped"
+});
+
+impl DataKeyYaml {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EncryptionError> {
+        let f = try!(File::open(path));
+        Ok(try!(serde_yaml::from_reader(f)))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), EncryptionError> {
+        let mut f = try!(File::create(path));
+        try!(serde_yaml::to_writer(&mut f, &self));
+        Ok(())
+    }
+
+    pub fn get_id(&self) -> Result<Vec<u8>, EncryptionError> {
+        parse_hex(&self.id).map_err(|_| EncryptionError::InvalidKey)
+    }
+
+    pub fn get_recipients(&self) -> Result<Vec<PublicKey>, EncryptionError> {
+        let mut recipients = vec![];
+        for public in self.wrapped.keys() {
+            let public = try!(parse_hex(public).map_err(|_| EncryptionError::InvalidKey));
+            recipients.push(try!(PublicKey::from_slice(&public).ok_or(
+                EncryptionError::InvalidKey
+            )));
+        }
+        Ok(recipients)
+    }
+}
+
+
+/// Ed25519 key pair used to sign backup files and bundles
+pub struct SigningKeyYaml {
+    public: String,
+    secret: String,
+    salt: String,
+    nonce: String
+}
+impl Default for SigningKeyYaml {
+    fn default() -> Self {
+        SigningKeyYaml {
+            public: "".to_string(),
+            secret: "".to_string(),
+            salt: "".to_string(),
+            nonce: "".to_string()
+        }
+    }
+}
+serde_impl!(SigningKeyYaml(String) {
+    public: String => "public",
+    secret: String => "secret",
+    salt: String => "salt",
+    nonce: String => "nonce"
+});
+
+impl SigningKeyYaml {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EncryptionError> {
+        let f = try!(File::open(path));
+        Ok(try!(serde_yaml::from_reader(f)))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), EncryptionError> {
+        let mut f = try!(File::create(path));
+        try!(serde_yaml::to_writer(&mut f, &self));
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_protected(&self) -> bool {
+        !self.salt.is_empty()
+    }
+
+    pub fn get_public(&self) -> Result<SigningPublicKey, EncryptionError> {
+        let public = try!(parse_hex(&self.public).map_err(|_| EncryptionError::InvalidKey));
+        SigningPublicKey::from_slice(&public).ok_or(EncryptionError::InvalidKey)
+    }
+
+    /// Returns the secret key, decrypting it with the passphrase if the keyfile is protected
+    pub fn get_secret(&self, passphrase: Option<&str>) -> Result<SigningSecretKey, EncryptionError> {
+        let secret = try!(open_secret(&self.secret, &self.salt, &self.nonce, passphrase));
+        SigningSecretKey::from_slice(&secret).ok_or(EncryptionError::InvalidKey)
+    }
+
+    /// Creates a keyfile, the secret key is encrypted like secret keys if a passphrase is given
+    pub fn new(
+        public: &SigningPublicKey,
+        secret: &SigningSecretKey,
+        passphrase: Option<&str>,
+    ) -> Self {
+        let (secret, salt, nonce) = seal_secret(&secret[..], passphrase);
+        SigningKeyYaml {
+            public: to_hex(&public[..]),
+            secret,
+            salt,
+            nonce
+        }
+    }
 }
 
 
 pub struct Crypto {
     path: Option<PathBuf>,
-    keys: HashMap<PublicKey, SecretKey>
+    keys: HashMap<PublicKey, SecretKey>,
+    locked_keys: HashMap<PublicKey, KeyfileYaml>,
+    passphrase: Option<String>,
+    data_keys: HashMap<Vec<u8>, secretbox::Key>,
+    wrapped_data_keys: HashMap<Vec<u8>, DataKeyYaml>,
+    signing_key: Option<(SigningPublicKey, SigningSecretKey)>,
+    locked_signing_key: Option<SigningKeyYaml>,
+    signing_key_path: Option<PathBuf>,
+    trusted_keys: Vec<SigningPublicKey>,
+    strict_signatures: bool,
+    unchecked_warned: Cell<bool>,
+    hash_key_id: Option<Vec<u8>>
 }
 
 impl Crypto {
@@ -125,7 +383,18 @@ impl Crypto {
         sodium_init();
         Crypto {
             path: None,
-            keys: HashMap::new()
+            keys: HashMap::new(),
+            locked_keys: HashMap::new(),
+            passphrase: None,
+            data_keys: HashMap::new(),
+            wrapped_data_keys: HashMap::new(),
+            signing_key: None,
+            locked_signing_key: None,
+            signing_key_path: None,
+            trusted_keys: vec![],
+            strict_signatures: false,
+            unchecked_warned: Cell::new(false),
+            hash_key_id: None
         }
     }
 
@@ -133,26 +402,32 @@ impl Crypto {
         sodium_init();
         let path = path.as_ref().to_owned();
         let mut keys: HashMap<PublicKey, SecretKey> = HashMap::default();
+        let mut locked_keys: HashMap<PublicKey, KeyfileYaml> = HashMap::default();
         for entry in try!(fs::read_dir(&path)) {
             let entry = try!(entry);
             let keyfile = try!(KeyfileYaml::load(entry.path()));
-            let public = try!(parse_hex(&keyfile.public).map_err(
-                |_| EncryptionError::InvalidKey
-            ));
-            let public = try!(PublicKey::from_slice(&public).ok_or(
-                EncryptionError::InvalidKey
-            ));
-            let secret = try!(parse_hex(&keyfile.secret).map_err(
-                |_| EncryptionError::InvalidKey
-            ));
-            let secret = try!(SecretKey::from_slice(&secret).ok_or(
-                EncryptionError::InvalidKey
-            ));
-            keys.insert(public, secret);
+            let public = try!(keyfile.get_public());
+            // Protected keys are only unlocked when they are actually needed
+            if keyfile.is_protected() {
+                locked_keys.insert(public, keyfile);
+            } else {
+                keys.insert(public, try!(keyfile.get_secret(None)));
+            }
         }
         Ok(Crypto {
             path: Some(path),
-            keys
+            keys,
+            locked_keys,
+            passphrase: None,
+            data_keys: HashMap::new(),
+            wrapped_data_keys: HashMap::new(),
+            signing_key: None,
+            locked_signing_key: None,
+            signing_key_path: None,
+            trusted_keys: vec![],
+            strict_signatures: false,
+            unchecked_warned: Cell::new(false),
+            hash_key_id: None
         })
     }
 
@@ -174,30 +449,28 @@ impl Crypto {
         Self::load_keypair_from_file_data(&try!(KeyfileYaml::load(path)))
     }
 
+    /// Reads the key pair, asking for the passphrase if the keyfile is protected
     pub fn load_keypair_from_file_data(
         keyfile: &KeyfileYaml,
     ) -> Result<(PublicKey, SecretKey), EncryptionError> {
-        let public = try!(parse_hex(&keyfile.public).map_err(
-            |_| EncryptionError::InvalidKey
-        ));
-        let public = try!(PublicKey::from_slice(&public).ok_or(
-            EncryptionError::InvalidKey
-        ));
-        let secret = try!(parse_hex(&keyfile.secret).map_err(
-            |_| EncryptionError::InvalidKey
-        ));
-        let secret = try!(SecretKey::from_slice(&secret).ok_or(
-            EncryptionError::InvalidKey
-        ));
+        let public = try!(keyfile.get_public());
+        let secret = if keyfile.is_protected() {
+            let passphrase = try!(read_passphrase(tr!("Passphrase: ")).map_err(
+                EncryptionError::Passphrase
+            ));
+            try!(keyfile.get_secret(Some(&passphrase)).map_err(|err| {
+                forget_passphrase();
+                err
+            }))
+        } else {
+            try!(keyfile.get_secret(None))
+        };
         Ok((public, secret))
     }
 
     #[inline]
     pub fn save_keypair_to_file_data(public: &PublicKey, secret: &SecretKey) -> KeyfileYaml {
-        KeyfileYaml {
-            public: to_hex(&public[..]),
-            secret: to_hex(&secret[..])
-        }
+        KeyfileYaml::new(public, secret, None)
     }
 
     #[inline]
@@ -209,55 +482,431 @@ impl Crypto {
         Self::save_keypair_to_file_data(public, secret).save(path)
     }
 
-    #[inline]
+    /// Stores the key pair, protected with the passphrase of the existing keys if they have one
     pub fn register_secret_key(
         &mut self,
         public: PublicKey,
         secret: SecretKey,
     ) -> Result<(), EncryptionError> {
+        if self.path.is_some() {
+            try!(self.ensure_passphrase());
+        }
         if let Some(ref path) = self.path {
             let path = path.join(to_hex(&public[..]) + ".yaml");
-            try!(Self::save_keypair_to_file(&public, &secret, path));
+            // New keys are protected like the existing keys
+            let passphrase = self.passphrase.as_ref().map(|p| p as &str);
+            try!(KeyfileYaml::new(&public, &secret, passphrase).save(path));
         }
+        self.locked_keys.remove(&public);
         self.keys.insert(public, secret);
         Ok(())
     }
 
+    pub fn remove_secret_key(&mut self, public: &PublicKey) -> Result<(), EncryptionError> {
+        if let Some(ref path) = self.path {
+            // Keyfiles can have any name, so all of them have to be checked
+            for entry in try!(fs::read_dir(path)) {
+                let path = try!(entry).path();
+                if try!(try!(KeyfileYaml::load(&path)).get_public()) == *public {
+                    try!(fs::remove_file(path));
+                }
+            }
+        }
+        self.keys.remove(public);
+        self.locked_keys.remove(public);
+        Ok(())
+    }
+
+    /// Sets, changes or removes the passphrase of all stored secret keys
+    pub fn change_passphrase(&mut self, passphrase: Option<&str>) -> Result<usize, EncryptionError> {
+        try!(self.unlock_keys());
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(0),
+        };
+        let mut count = 0;
+        for entry in try!(fs::read_dir(&path)) {
+            let file = try!(entry).path();
+            let public = try!(try!(KeyfileYaml::load(&file)).get_public());
+            let secret = try!(self.get_secret_key(&public)).clone();
+            // Write the new keyfile first so that the key is never lost, the temporary file is
+            // placed next to the key folder as every file in there is loaded as a keyfile
+            let tmp = path.with_file_name(format!(
+                "keys-{}.tmp",
+                file.file_name().unwrap().to_string_lossy()
+            ));
+            try!(KeyfileYaml::new(&public, &secret, passphrase).save(&tmp));
+            try!(fs::rename(&tmp, &file));
+            count += 1;
+        }
+        if let (Some(&(ref public, ref secret)), Some(ref file)) =
+            (self.signing_key.as_ref(), self.signing_key_path.as_ref())
+        {
+            let tmp = file.with_extension("tmp");
+            try!(SigningKeyYaml::new(public, secret, passphrase).save(&tmp));
+            try!(fs::rename(&tmp, file));
+            count += 1;
+        }
+        self.passphrase = passphrase.map(|p| p.to_string());
+        Ok(count)
+    }
+
+    /// Returns the secret key for the public key, unlocking it if needed
+    #[inline]
+    pub fn export_secret_key(&mut self, public: &PublicKey) -> Result<SecretKey, EncryptionError> {
+        self.get_secret_key(public).map(|secret| secret.clone())
+    }
+
+    /// Short fingerprint of a public key to identify it
+    pub fn fingerprint(public: &PublicKey) -> String {
+        let hash = HashMethod::Blake2.hash(&public[..]);
+        let hex = format!("{:016x}", hash.high);
+        let groups: Vec<&str> = (0..4).map(|i| &hex[i * 4..i * 4 + 4]).collect();
+        groups.join(":")
+    }
+
+    /// Decrypts all protected secret keys and the protected signing key
+    pub fn unlock_keys(&mut self) -> Result<(), EncryptionError> {
+        let locked: Vec<PublicKey> = self.locked_keys.keys().cloned().collect();
+        for public in locked {
+            try!(self.get_secret_key(&public));
+        }
+        self.unlock_signing_key()
+    }
+
+    /// Asks for the passphrase of the stored keys if they are protected
+    ///
+    /// New keys have to be protected like the existing keys, so the passphrase is needed first.
+    fn ensure_passphrase(&mut self) -> Result<(), EncryptionError> {
+        if self.passphrase.is_none() &&
+            (!self.locked_keys.is_empty() || self.locked_signing_key.is_some())
+        {
+            try!(self.unlock_keys());
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn public_keys(&self) -> Vec<PublicKey> {
+        self.keys.keys().chain(self.locked_keys.keys()).cloned().collect()
+    }
+
     #[inline]
     pub fn contains_secret_key(&mut self, public: &PublicKey) -> bool {
-        self.keys.contains_key(public)
+        self.keys.contains_key(public) || self.locked_keys.contains_key(public)
     }
 
-    fn get_secret_key(&self, public: &PublicKey) -> Result<&SecretKey, EncryptionError> {
+    fn get_passphrase(&mut self) -> Result<String, EncryptionError> {
+        if let Some(ref passphrase) = self.passphrase {
+            return Ok(passphrase.clone());
+        }
+        let passphrase = try!(read_passphrase(tr!("Passphrase for secret keys: ")).map_err(
+            EncryptionError::Passphrase
+        ));
+        self.passphrase = Some(passphrase.clone());
+        Ok(passphrase)
+    }
+
+    fn get_secret_key(&mut self, public: &PublicKey) -> Result<&SecretKey, EncryptionError> {
+        if !self.keys.contains_key(public) {
+            if let Some(keyfile) = self.locked_keys.remove(public) {
+                let passphrase = try!(self.get_passphrase());
+                match keyfile.get_secret(Some(&passphrase)) {
+                    Ok(secret) => {
+                        self.keys.insert(*public, secret);
+                    }
+                    Err(err) => {
+                        // Allow a retry with another passphrase
+                        self.passphrase = None;
+                        forget_passphrase();
+                        self.locked_keys.insert(*public, keyfile);
+                        return Err(err);
+                    }
+                }
+            }
+        }
         self.keys.get(public).ok_or_else(
             || EncryptionError::MissingKey(*public)
         )
     }
 
+    /// Generates a new data key and its id
+    pub fn gen_data_key() -> (Vec<u8>, secretbox::Key) {
+        sodium_init();
+        (randombytes(16), secretbox::gen_key())
+    }
+
+    pub fn wrap_data_key(
+        id: &[u8],
+        key: &secretbox::Key,
+        recipients: &[PublicKey],
+    ) -> DataKeyYaml {
+        DataKeyYaml {
+            id: to_hex(id),
+            wrapped: recipients
+                .iter()
+                .map(|public| {
+                    (to_hex(&public[..]), to_hex(&sealedbox::seal(&key[..], public)))
+                })
+                .collect()
+        }
+    }
+
+    /// Opens a data key with one of the stored secret keys, returns `None` if no key fits
+    pub fn unwrap_data_key(
+        &mut self,
+        data_key: &DataKeyYaml,
+    ) -> Result<Option<(Vec<u8>, secretbox::Key)>, EncryptionError> {
+        let id = try!(data_key.get_id());
+        for (public, sealed) in &data_key.wrapped {
+            let public = try!(parse_hex(public).map_err(|_| EncryptionError::InvalidKey));
+            let public = try!(PublicKey::from_slice(&public).ok_or(
+                EncryptionError::InvalidKey
+            ));
+            if self.contains_secret_key(&public) {
+                let secret = try!(self.get_secret_key(&public));
+                let sealed = try!(parse_hex(sealed).map_err(|_| EncryptionError::InvalidKey));
+                let key = try!(sealedbox::open(&sealed, &public, secret).map_err(|_| {
+                    EncryptionError::Operation(tr!("Decryption failed"))
+                }));
+                let key = try!(secretbox::Key::from_slice(&key).ok_or(
+                    EncryptionError::InvalidKey
+                ));
+                return Ok(Some((id, key)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Loads all data keys from the given folder, they are opened when they are first used
+    pub fn load_data_keys<P: AsRef<Path>>(&mut self, path: P) -> Result<(), EncryptionError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(());
+        }
+        for entry in try!(fs::read_dir(path)) {
+            let path = try!(entry).path();
+            // Leftover temporary files of interrupted rewraps are ignored
+            if path.extension() != Some("yaml".as_ref()) {
+                continue;
+            }
+            let data_key = try!(DataKeyYaml::load(&path));
+            self.wrapped_data_keys.insert(try!(data_key.get_id()), data_key);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn add_data_key(&mut self, id: Vec<u8>, key: secretbox::Key) {
+        self.data_keys.insert(id, key);
+    }
+
+    fn get_data_key(&mut self, id: &[u8]) -> Result<&secretbox::Key, EncryptionError> {
+        if !self.data_keys.contains_key(id) {
+            if let Some(data_key) = self.wrapped_data_keys.remove(id) {
+                match self.unwrap_data_key(&data_key) {
+                    Ok(Some((id, key))) => {
+                        self.data_keys.insert(id, key);
+                    }
+                    Ok(None) => (),
+                    Err(err) => {
+                        self.wrapped_data_keys.insert(id.to_vec(), data_key);
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        self.data_keys.get(id).ok_or_else(
+            || EncryptionError::MissingDataKey(id.to_vec())
+        )
+    }
+
+    /// Sets the id of the data key that is used as key for keyed hash methods
+    #[inline]
+    pub fn set_hash_key_id(&mut self, id: Option<Vec<u8>>) {
+        self.hash_key_id = id;
+    }
+
+    /// Returns the key for keyed hash methods or an empty key if none is set
+    pub fn get_hash_key(&mut self) -> Result<Vec<u8>, EncryptionError> {
+        let id = match self.hash_key_id {
+            Some(ref id) => id.clone(),
+            None => return Ok(vec![]),
+        };
+        Ok(try!(self.get_data_key(&id))[..].to_vec())
+    }
+
+    #[inline]
+    pub fn gen_signing_keypair() -> (SigningPublicKey, SigningSecretKey) {
+        sodium_init();
+        sign::gen_keypair()
+    }
+
+    /// Loads the signing key pair, a protected secret key is only decrypted when it is needed
+    pub fn load_signing_key<P: AsRef<Path>>(&mut self, path: P) -> Result<(), EncryptionError> {
+        let path = path.as_ref();
+        let keyfile = try!(SigningKeyYaml::load(path));
+        if keyfile.is_protected() {
+            self.signing_key = None;
+            self.locked_signing_key = Some(keyfile);
+        } else {
+            self.signing_key = Some((try!(keyfile.get_public()), try!(keyfile.get_secret(None))));
+            self.locked_signing_key = None;
+        }
+        self.signing_key_path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Stores the signing key pair and uses it, protected with the passphrase of the stored keys
+    pub fn register_signing_key<P: AsRef<Path>>(
+        &mut self,
+        public: SigningPublicKey,
+        secret: SigningSecretKey,
+        path: P,
+    ) -> Result<(), EncryptionError> {
+        let path = path.as_ref();
+        try!(self.ensure_passphrase());
+        {
+            let passphrase = self.passphrase.as_ref().map(|p| p as &str);
+            try!(SigningKeyYaml::new(&public, &secret, passphrase).save(path));
+        }
+        self.signing_key = Some((public, secret));
+        self.locked_signing_key = None;
+        self.signing_key_path = Some(path.to_path_buf());
+        Ok(())
+    }
+
     #[inline]
-    pub fn encrypt(&self, enc: &Encryption, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
-        let &(ref method, ref public) = enc;
-        let public = try!(PublicKey::from_slice(public).ok_or(
-            EncryptionError::InvalidKey
+    pub fn set_signing_key(&mut self, public: SigningPublicKey, secret: SigningSecretKey) {
+        self.signing_key = Some((public, secret));
+    }
+
+    #[inline]
+    pub fn get_signing_key(&self) -> Option<SigningPublicKey> {
+        self.signing_key.as_ref().map(|&(public, _)| public).or_else(|| {
+            self.locked_signing_key.as_ref().and_then(|keyfile| keyfile.get_public().ok())
+        })
+    }
+
+    fn unlock_signing_key(&mut self) -> Result<(), EncryptionError> {
+        let keyfile = match self.locked_signing_key.take() {
+            Some(keyfile) => keyfile,
+            None => return Ok(()),
+        };
+        let res = self.get_passphrase().and_then(|passphrase| {
+            Ok((try!(keyfile.get_public()), try!(keyfile.get_secret(Some(&passphrase)))))
+        });
+        match res {
+            Ok(key) => {
+                self.signing_key = Some(key);
+                Ok(())
+            }
+            Err(err) => {
+                // Allow a retry with another passphrase
+                self.passphrase = None;
+                forget_passphrase();
+                self.locked_signing_key = Some(keyfile);
+                Err(err)
+            }
+        }
+    }
+
+    /// Sets the keys that signatures are checked against
+    ///
+    /// In strict mode, data without a signature of one of those keys is refused.
+    #[inline]
+    pub fn set_trusted_keys(&mut self, keys: Vec<SigningPublicKey>, strict: bool) {
+        self.trusted_keys = keys;
+        self.strict_signatures = strict;
+    }
+
+    /// Creates a detached signature if a signing key is set
+    pub fn sign(&mut self, data: &[u8]) -> Result<Option<ByteBuf>, EncryptionError> {
+        try!(self.unlock_signing_key());
+        Ok(self.signing_key.as_ref().map(|&(_, ref secret)| {
+            ByteBuf::from(&sign::sign_detached(data, secret)[..])
+        }))
+    }
+
+    /// Checks the signature of the data against the trusted keys
+    ///
+    /// A bad signature is always an error, missing or unverifiable signatures only in strict mode.
+    /// Without trusted keys, signatures can not be checked at all in non-strict mode, so this
+    /// gives no protection.
+    pub fn verify(&self, data: &[u8], signature: Option<&[u8]>) -> Result<(), EncryptionError> {
+        let signature = match signature {
+            Some(signature) => signature,
+            None if self.strict_signatures => return Err(EncryptionError::Unsigned),
+            None => return Ok(()),
+        };
+        let signature = try!(sign::Signature::from_slice(signature).ok_or(
+            EncryptionError::BadSignature
         ));
+        if self.trusted_keys.is_empty() {
+            if self.strict_signatures {
+                return Err(EncryptionError::UntrustedSignature);
+            }
+            if !self.unchecked_warned.get() {
+                tr_warn!("No signing keys are trusted, signatures are not checked");
+                self.unchecked_warned.set(true);
+            }
+            return Ok(());
+        }
+        if self.trusted_keys.iter().any(|key| {
+            sign::verify_detached(&signature, data, key)
+        })
+        {
+            Ok(())
+        } else if self.strict_signatures {
+            Err(EncryptionError::UntrustedSignature)
+        } else {
+            Err(EncryptionError::BadSignature)
+        }
+    }
+
+    #[inline]
+    pub fn encrypt(&mut self, enc: &Encryption, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let &(ref method, ref key) = enc;
         match *method {
-            EncryptionMethod::Sodium => Ok(sealedbox::seal(data, &public)),
+            EncryptionMethod::Sodium => {
+                let public = try!(PublicKey::from_slice(key).ok_or(
+                    EncryptionError::InvalidKey
+                ));
+                Ok(sealedbox::seal(data, &public))
+            }
+            EncryptionMethod::SecretBox => {
+                let key = try!(self.get_data_key(key));
+                let nonce = secretbox::gen_nonce();
+                let mut encrypted = nonce[..].to_vec();
+                encrypted.extend_from_slice(&secretbox::seal(data, &nonce, key));
+                Ok(encrypted)
+            }
         }
     }
 
     #[inline]
-    pub fn decrypt(&self, enc: &Encryption, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
-        let &(ref method, ref public) = enc;
-        let public = try!(PublicKey::from_slice(public).ok_or(
-            EncryptionError::InvalidKey
-        ));
-        let secret = try!(self.get_secret_key(&public));
+    pub fn decrypt(&mut self, enc: &Encryption, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let &(ref method, ref key) = enc;
         match *method {
             EncryptionMethod::Sodium => {
+                let public = try!(PublicKey::from_slice(key).ok_or(
+                    EncryptionError::InvalidKey
+                ));
+                let secret = try!(self.get_secret_key(&public));
                 sealedbox::open(data, &public, secret).map_err(|_| {
                     EncryptionError::Operation(tr!("Decryption failed"))
                 })
             }
+            EncryptionMethod::SecretBox => {
+                let key = try!(self.get_data_key(key));
+                if data.len() < secretbox::NONCEBYTES {
+                    return Err(EncryptionError::Operation(tr!("Decryption failed")));
+                }
+                let nonce = secretbox::Nonce::from_slice(&data[..secretbox::NONCEBYTES]).unwrap();
+                secretbox::open(&data[secretbox::NONCEBYTES..], &nonce, key).map_err(|_| {
+                    EncryptionError::Operation(tr!("Decryption failed"))
+                })
+            }
         }
     }
 
@@ -267,6 +916,41 @@ impl Crypto {
         box_::gen_keypair()
     }
 
+    /// Derives the public key that belongs to a secret key
+    pub fn public_from_secret(secret: &SecretKey) -> PublicKey {
+        sodium_init();
+        let mut pk = [0u8; 32];
+        if unsafe { libsodium_sys::crypto_scalarmult_base(&mut pk, &secret.0) } != 0 {
+            tr_panic!("Libsodium failed");
+        }
+        PublicKey::from_slice(&pk).unwrap()
+    }
+
+    /// Splits a secret key into shares, `threshold` of them are needed to restore it
+    pub fn split_secret_key(
+        public: &PublicKey,
+        secret: &SecretKey,
+        threshold: u8,
+        count: u8,
+    ) -> Result<Vec<SecretShare>, EncryptionError> {
+        // The start of the public key identifies shares that belong together
+        let id = BigEndian::read_u32(&public[..4]);
+        Ok(try!(split_secret(&secret[..], id, threshold, count)))
+    }
+
+    /// Restores a key pair from the shares created by `split_secret_key`
+    pub fn combine_secret_key(
+        shares: &[SecretShare],
+    ) -> Result<(PublicKey, SecretKey), EncryptionError> {
+        let data = try!(combine_shares(shares));
+        let secret = try!(SecretKey::from_slice(&data).ok_or(EncryptionError::InvalidKey));
+        let public = Self::public_from_secret(&secret);
+        if BigEndian::read_u32(&public[..4]) != shares[0].id {
+            return Err(EncryptionError::InvalidKey);
+        }
+        Ok((public, secret))
+    }
+
     pub fn keypair_from_password(password: &str) -> (PublicKey, SecretKey) {
         let salt = pwhash::Salt::from_slice(b"the_great_zvault_password_salt_1").unwrap();
         let mut key = [0u8; pwhash::HASHEDPASSWORDBYTES];
@@ -302,6 +986,27 @@ mod tests {
     use super::*;
 
 
+    #[test]
+    fn test_fingerprint() {
+        let (pk, _) = Crypto::gen_keypair();
+        let (pk2, _) = Crypto::gen_keypair();
+        let fingerprint = Crypto::fingerprint(&pk);
+        assert_eq!(fingerprint.len(), 19);
+        assert_eq!(fingerprint, Crypto::fingerprint(&pk));
+        assert!(fingerprint != Crypto::fingerprint(&pk2));
+    }
+
+    #[test]
+    fn test_split_secret_key() {
+        let (pk, sk) = Crypto::gen_keypair();
+        assert_eq!(Crypto::public_from_secret(&sk), pk);
+        let shares = Crypto::split_secret_key(&pk, &sk, 2, 3).unwrap();
+        let (pk2, sk2) = Crypto::combine_secret_key(&shares[1..]).unwrap();
+        assert_eq!(pk, pk2);
+        assert_eq!(sk, sk2);
+        assert!(Crypto::combine_secret_key(&shares[..1]).is_err());
+    }
+
     #[test]
     fn test_gen_keypair() {
         let key1 = Crypto::gen_keypair();
@@ -325,6 +1030,8 @@ mod tests {
         assert!(!crypto.contains_secret_key(&pk));
         crypto.add_secret_key(pk, sk);
         assert!(crypto.contains_secret_key(&pk));
+        assert!(crypto.remove_secret_key(&pk).is_ok());
+        assert!(!crypto.contains_secret_key(&pk));
     }
 
     #[test]
@@ -338,6 +1045,29 @@ mod tests {
         assert_eq!(sk, sk2);
     }
 
+    #[test]
+    fn test_protected_keyfile() {
+        let (pk, sk) = Crypto::gen_keypair();
+        let data = KeyfileYaml::new(&pk, &sk, Some("passphrase"));
+        assert!(data.is_protected());
+        assert_eq!(pk, data.get_public().unwrap());
+        assert_eq!(sk, data.get_secret(Some("passphrase")).unwrap());
+        assert!(data.get_secret(Some("wrong")).is_err());
+        assert!(data.get_secret(None).is_err());
+        let data = KeyfileYaml::new(&pk, &sk, None);
+        assert!(!data.is_protected());
+        assert_eq!(sk, data.get_secret(None).unwrap());
+        let (pk, sk) = Crypto::gen_signing_keypair();
+        let data = SigningKeyYaml::new(&pk, &sk, Some("passphrase"));
+        assert!(data.is_protected());
+        assert_eq!(pk, data.get_public().unwrap());
+        assert_eq!(sk, data.get_secret(Some("passphrase")).unwrap());
+        assert!(data.get_secret(Some("wrong")).is_err());
+        let data = SigningKeyYaml::new(&pk, &sk, None);
+        assert!(!data.is_protected());
+        assert_eq!(sk, data.get_secret(None).unwrap());
+    }
+
     #[test]
     fn test_encrypt_decrpyt() {
         let mut crypto = Crypto::dummy();
@@ -375,6 +1105,57 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_wrap_unwrap_data_key() {
+        let mut crypto = Crypto::dummy();
+        let (pk, sk) = Crypto::gen_keypair();
+        let (pk2, _sk2) = Crypto::gen_keypair();
+        let (id, key) = Crypto::gen_data_key();
+        let data_key = Crypto::wrap_data_key(&id, &key, &[pk, pk2]);
+        assert_eq!(data_key.get_recipients().unwrap().len(), 2);
+        assert!(crypto.unwrap_data_key(&data_key).unwrap().is_none());
+        crypto.add_secret_key(pk, sk);
+        let (id2, key2) = crypto.unwrap_data_key(&data_key).unwrap().unwrap();
+        assert_eq!(id, id2);
+        assert_eq!(key, key2);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_data_key() {
+        let mut crypto = Crypto::dummy();
+        let (id, key) = Crypto::gen_data_key();
+        let encryption = (EncryptionMethod::SecretBox, ByteBuf::from(&id[..]));
+        let cleartext = b"test123";
+        assert!(crypto.encrypt(&encryption, cleartext).is_err());
+        crypto.add_data_key(id, key);
+        let mut ciphertext = crypto.encrypt(&encryption, cleartext).unwrap();
+        assert!(&ciphertext != cleartext);
+        assert_eq!(&cleartext[..] as &[u8], &crypto.decrypt(&encryption, &ciphertext).unwrap() as &[u8]);
+        let last = ciphertext.len() - 1;
+        ciphertext[last] ^= 53;
+        assert!(crypto.decrypt(&encryption, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let mut crypto = Crypto::dummy();
+        let data = b"test123";
+        assert!(crypto.sign(data).unwrap().is_none());
+        assert!(crypto.verify(data, None).is_ok());
+        let (pk, sk) = Crypto::gen_signing_keypair();
+        crypto.set_signing_key(pk, sk);
+        let signature = crypto.sign(data).unwrap().unwrap();
+        crypto.set_trusted_keys(vec![pk], false);
+        assert!(crypto.verify(data, Some(&signature)).is_ok());
+        assert!(crypto.verify(b"test124", Some(&signature)).is_err());
+        assert!(crypto.verify(data, None).is_ok());
+        crypto.set_trusted_keys(vec![pk], true);
+        assert!(crypto.verify(data, None).is_err());
+        let (pk2, _sk2) = Crypto::gen_signing_keypair();
+        crypto.set_trusted_keys(vec![pk2], true);
+        assert!(crypto.verify(data, Some(&signature)).is_err());
+    }
+
     #[test]
     fn test_modified_ciphertext() {
         let mut crypto = Crypto::dummy();
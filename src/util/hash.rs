@@ -4,7 +4,9 @@ use serde_bytes::{ByteBuf, Bytes};
 
 use murmurhash3::murmurhash3_x64_128;
 use blake2::blake2b::blake2b;
-use byteorder::{LittleEndian, ByteOrder, WriteBytesExt, ReadBytesExt};
+use blake3;
+use sha2::{Sha256, Digest};
+use byteorder::{LittleEndian, BigEndian, ByteOrder, WriteBytesExt, ReadBytesExt};
 
 use std::mem;
 use std::fmt;
@@ -50,6 +52,15 @@ impl Hash {
         })
     }
 
+    /// Takes the first 128 bits of a longer hash value
+    #[inline]
+    fn truncate(hash: &[u8]) -> Self {
+        Hash {
+            high: BigEndian::read_u64(&hash[..8]),
+            low: BigEndian::read_u64(&hash[8..16])
+        }
+    }
+
     #[inline]
     pub fn from_string(val: &str) -> Result<Self, ()> {
         let high = try!(u64::from_str_radix(&val[..16], 16).map_err(|_| ()));
@@ -105,23 +116,46 @@ impl<'a> Deserialize<'a> for Hash {
 }
 
 
+/// Number of alternative hashes that are tried to store a chunk whose hash collides
+pub const MAX_HASH_ATTEMPTS: u8 = 16;
+
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum HashMethod {
     Blake2,
-    Murmur3
+    Murmur3,
+    Blake2Keyed,
+    Blake3,
+    Sha256
 }
 serde_impl!(HashMethod(u64) {
     Blake2 => 1,
-    Murmur3 => 2
+    Murmur3 => 2,
+    Blake2Keyed => 3,
+    Blake3 => 4,
+    Sha256 => 5
 });
 
 
 impl HashMethod {
+    /// Hashes the data without a key, keyed methods must use `hash_keyed` instead
     #[inline]
     pub fn hash(&self, data: &[u8]) -> Hash {
+        assert!(!self.is_keyed(), "keyed hash method used without a key");
+        self.hash_keyed(&[], data)
+    }
+
+    /// Hashes the data, the key is only used by keyed methods
+    #[inline]
+    pub fn hash_keyed(&self, key: &[u8], data: &[u8]) -> Hash {
         match *self {
-            HashMethod::Blake2 => {
-                let hash = blake2b(16, &[], data);
+            HashMethod::Blake2 | HashMethod::Blake2Keyed => {
+                let key = if *self == HashMethod::Blake2Keyed {
+                    key
+                } else {
+                    &[]
+                };
+                let hash = blake2b(16, key, data);
                 let hash =
                     unsafe { &*mem::transmute::<_, *const (u64, u64)>(hash.as_bytes().as_ptr()) };
                 Hash {
@@ -133,14 +167,36 @@ impl HashMethod {
                 let (a, b) = murmurhash3_x64_128(data, 0);
                 Hash { high: a, low: b }
             }
+            HashMethod::Blake3 => Hash::truncate(blake3::hash(data).as_bytes()),
+            HashMethod::Sha256 => Hash::truncate(&Sha256::digest(data)),
         }
     }
 
+    /// Alternative hash to store a chunk under if its regular hash collides with another chunk
+    ///
+    /// The alternative hash only depends on the data and the attempt (starting at 1) so that
+    /// identical chunks still get deduplicated.
+    pub fn hash_attempt(&self, key: &[u8], data: &[u8], attempt: u8) -> Hash {
+        let mut buf = Vec::with_capacity(data.len() + 1);
+        buf.extend_from_slice(data);
+        buf.push(attempt);
+        self.hash_keyed(key, &buf)
+    }
+
+    /// Checks whether the hash is the regular or an alternative hash of the data
+    pub fn matches(&self, key: &[u8], data: &[u8], hash: &Hash) -> bool {
+        self.hash_keyed(key, data) == *hash ||
+            (1..MAX_HASH_ATTEMPTS + 1).any(|attempt| self.hash_attempt(key, data, attempt) == *hash)
+    }
+
     #[inline]
     pub fn from(name: &str) -> Result<Self, &'static str> {
         match name {
             "blake2" => Ok(HashMethod::Blake2),
             "murmur3" => Ok(HashMethod::Murmur3),
+            "blake2-keyed" => Ok(HashMethod::Blake2Keyed),
+            "blake3" => Ok(HashMethod::Blake3),
+            "sha256" => Ok(HashMethod::Sha256),
             _ => Err(tr!("Unsupported hash method")),
         }
     }
@@ -150,8 +206,16 @@ impl HashMethod {
         match *self {
             HashMethod::Blake2 => "blake2",
             HashMethod::Murmur3 => "murmur3",
+            HashMethod::Blake2Keyed => "blake2-keyed",
+            HashMethod::Blake3 => "blake3",
+            HashMethod::Sha256 => "sha256",
         }
     }
+
+    #[inline]
+    pub fn is_keyed(&self) -> bool {
+        *self == HashMethod::Blake2Keyed
+    }
 }
 
 
@@ -166,6 +230,9 @@ mod tests {
     fn test_parse() {
         assert_eq!(HashMethod::from("blake2"), Ok(HashMethod::Blake2));
         assert_eq!(HashMethod::from("murmur3"), Ok(HashMethod::Murmur3));
+        assert_eq!(HashMethod::from("blake2-keyed"), Ok(HashMethod::Blake2Keyed));
+        assert_eq!(HashMethod::from("blake3"), Ok(HashMethod::Blake3));
+        assert_eq!(HashMethod::from("sha256"), Ok(HashMethod::Sha256));
         assert!(HashMethod::from("foo").is_err());
     }
 
@@ -173,6 +240,33 @@ mod tests {
     fn test_to_str() {
         assert_eq!(HashMethod::Blake2.name(), "blake2");
         assert_eq!(HashMethod::Murmur3.name(), "murmur3");
+        assert_eq!(HashMethod::Blake2Keyed.name(), "blake2-keyed");
+        assert_eq!(HashMethod::Blake3.name(), "blake3");
+        assert_eq!(HashMethod::Sha256.name(), "sha256");
+    }
+
+    #[test]
+    fn test_blake2_keyed() {
+        let key = [1u8; 32];
+        let hash = HashMethod::Blake2Keyed.hash_keyed(&key, b"abc");
+        assert!(hash != HashMethod::Blake2.hash(b"abc"));
+        assert_eq!(hash, HashMethod::Blake2Keyed.hash_keyed(&key, b"abc"));
+        assert!(hash != HashMethod::Blake2Keyed.hash_keyed(&[2u8; 32], b"abc"));
+        assert_eq!(HashMethod::Blake2.hash_keyed(&key, b"abc"), HashMethod::Blake2.hash(b"abc"));
+    }
+
+    #[test]
+    fn test_hash_attempt() {
+        let hash = HashMethod::Murmur3.hash(b"abc");
+        let alt = HashMethod::Murmur3.hash_attempt(&[], b"abc", 1);
+        assert!(alt != hash);
+        assert!(alt != HashMethod::Murmur3.hash_attempt(&[], b"abc", 2));
+        assert_eq!(alt, HashMethod::Murmur3.hash_attempt(&[], b"abc", 1));
+        assert!(HashMethod::Murmur3.matches(&[], b"abc", &hash));
+        assert!(HashMethod::Murmur3.matches(&[], b"abc", &alt));
+        let last = HashMethod::Murmur3.hash_attempt(&[], b"abc", MAX_HASH_ATTEMPTS);
+        assert!(HashMethod::Murmur3.matches(&[], b"abc", &last));
+        assert!(!HashMethod::Murmur3.matches(&[], b"abd", &hash));
     }
 
     #[test]
@@ -186,6 +280,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_blake3() {
+        assert_eq!(
+            HashMethod::Blake3.hash(b"abc"),
+            Hash {
+                high: 0x6437b3ac38465133,
+                low: 0xffb63b75273a8db5
+            }
+        );
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            HashMethod::Sha256.hash(b"abc"),
+            Hash {
+                high: 0xba7816bf8f01cfea,
+                low: 0x414140de5dae2223
+            }
+        );
+    }
+
     #[test]
     fn test_murmur3() {
         assert_eq!(
@@ -226,6 +342,20 @@ mod benches {
         b.iter(|| HashMethod::Blake2.hash(&data));
     }
 
+    #[bench]
+    fn bench_blake3(b: &mut Bencher) {
+        let data = test_data(16 * 1024);
+        b.bytes = data.len() as u64;
+        b.iter(|| HashMethod::Blake3.hash(&data));
+    }
+
+    #[bench]
+    fn bench_sha256(b: &mut Bencher) {
+        let data = test_data(16 * 1024);
+        b.bytes = data.len() as u64;
+        b.iter(|| HashMethod::Sha256.hash(&data));
+    }
+
     #[bench]
     fn bench_murmur3(b: &mut Bencher) {
         let data = test_data(16 * 1024);
@@ -10,6 +10,8 @@ mod hostname;
 mod fs;
 mod lock;
 mod statistics;
+mod passphrase;
+mod shamir;
 pub mod msgpack;
 
 pub use self::fs::*;
@@ -23,4 +25,6 @@ pub use self::hex::*;
 pub use self::cli::*;
 pub use self::hostname::*;
 pub use self::lock::*;
-pub use self::statistics::*;
\ No newline at end of file
+pub use self::statistics::*;
+pub use self::passphrase::*;
+pub use self::shamir::*;
\ No newline at end of file
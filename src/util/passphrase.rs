@@ -0,0 +1,105 @@
+use libc;
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::sync::Mutex;
+
+
+pub static PASSPHRASE_ENV: &'static str = "ZVAULT_PASSPHRASE";
+pub static PASSPHRASE_FD_ENV: &'static str = "ZVAULT_PASSPHRASE_FD";
+pub static NEW_PASSPHRASE_ENV: &'static str = "ZVAULT_NEW_PASSPHRASE";
+
+lazy_static! {
+    static ref FD_PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+
+fn read_line<R: BufRead>(mut reader: R) -> Result<String, io::Error> {
+    let mut line = String::new();
+    try!(reader.read_line(&mut line));
+    while line.ends_with('\n') || line.ends_with('\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+/// Reads the passphrase from the file descriptor once and returns the same passphrase afterwards
+///
+/// The file descriptor belongs to the caller, so it is not closed.
+fn read_from_fd(fd: libc::c_int) -> Result<String, io::Error> {
+    let mut cached = FD_PASSPHRASE.lock().unwrap();
+    if let Some(ref passphrase) = *cached {
+        return Ok(passphrase.clone());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    let res = read_line(BufReader::new(&file));
+    file.into_raw_fd();
+    let passphrase = try!(res);
+    *cached = Some(passphrase.clone());
+    Ok(passphrase)
+}
+
+/// Drops a cached passphrase from the file descriptor after it turned out to be wrong
+///
+/// The next call of `read_passphrase` then reads the file descriptor again.
+pub fn forget_passphrase() {
+    *FD_PASSPHRASE.lock().unwrap() = None;
+}
+
+fn prompt_tty(prompt: &str) -> Result<String, io::Error> {
+    let mut tty = try!(OpenOptions::new().read(true).write(true).open("/dev/tty"));
+    try!(tty.write_all(prompt.as_bytes()));
+    try!(tty.flush());
+    let fd = tty.as_raw_fd();
+    let mut term: libc::termios = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut term) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let old = term;
+    term.c_lflag &= !libc::ECHO;
+    term.c_lflag |= libc::ECHONL;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let res = read_line(BufReader::new(&tty));
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &old) };
+    res
+}
+
+/// Reads a passphrase from the environment, a file descriptor or the terminal
+///
+/// The variable `ZVAULT_PASSPHRASE` contains the passphrase itself, `ZVAULT_PASSPHRASE_FD` the
+/// number of a file descriptor to read the passphrase from. Otherwise the user is asked.
+pub fn read_passphrase(prompt: &str) -> Result<String, io::Error> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if let Ok(fd) = env::var(PASSPHRASE_FD_ENV) {
+        return match fd.parse::<libc::c_int>() {
+            Ok(fd) => read_from_fd(fd),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                tr!("Invalid file descriptor")
+            )),
+        };
+    }
+    prompt_tty(prompt)
+}
+
+/// Reads a new passphrase from `ZVAULT_NEW_PASSPHRASE` or asks the user for it twice
+pub fn read_new_passphrase() -> Result<String, io::Error> {
+    if let Ok(passphrase) = env::var(NEW_PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = try!(prompt_tty(tr!("New passphrase: ")));
+    let repeated = try!(prompt_tty(tr!("Repeat passphrase: ")));
+    if passphrase != repeated {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            tr!("Passphrases do not match")
+        ));
+    }
+    Ok(passphrase)
+}
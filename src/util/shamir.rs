@@ -0,0 +1,265 @@
+use prelude::*;
+
+use sodiumoxide::randombytes::randombytes;
+
+use std::fmt;
+
+
+static SHARE_PREFIX: &'static str = "ZVAULT-SHARE";
+
+
+quick_error!{
+    #[derive(Debug, PartialEq)]
+    pub enum ShamirError {
+        InvalidThreshold(threshold: u8, count: u8) {
+            description(tr!("Invalid threshold"))
+            display("{}", tr_format!("Invalid threshold: {} of {} shares", threshold, count))
+        }
+        NotEnoughShares(needed: u8, given: usize) {
+            description(tr!("Not enough shares"))
+            display("{}", tr_format!("Not enough shares: {} needed but only {} given", needed, given))
+        }
+        DuplicateShare(index: u8) {
+            description(tr!("Duplicate share"))
+            display("{}", tr_format!("Share {} has been given more than once", index))
+        }
+        Mismatch {
+            description(tr!("Shares do not belong together"))
+        }
+        InvalidFormat {
+            description(tr!("Invalid share format"))
+        }
+        WrongChecksum {
+            description(tr!("Wrong checksum, the share contains a typing error"))
+        }
+    }
+}
+
+
+// Arithmetic in GF(2^8) with the reduction polynomial x^8 + x^4 + x^3 + x + 1
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut res = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            res ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    res
+}
+
+fn gf_inv(a: u8) -> u8 {
+    // a^254 is the inverse of a since a^255 = 1
+    let mut res = 1;
+    for _ in 0..254 {
+        res = gf_mul(res, a);
+    }
+    res
+}
+
+
+/// A share of a secret, `threshold` shares with distinct indices restore the secret
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecretShare {
+    pub id: u32,
+    pub threshold: u8,
+    pub index: u8,
+    pub data: Vec<u8>
+}
+
+impl SecretShare {
+    fn checksum(text: &str) -> u8 {
+        HashMethod::Blake2.hash(text.as_bytes()).low as u8
+    }
+
+    /// Parses a share in the text format, whitespace is ignored
+    pub fn parse(text: &str) -> Result<Self, ShamirError> {
+        let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+        let text = text.to_uppercase();
+        let pos = try!(text.rfind(':').ok_or(ShamirError::InvalidFormat));
+        let checksum = try!(parse_hex(&text[pos + 1..]).map_err(|_| ShamirError::InvalidFormat));
+        if checksum.len() != 1 {
+            return Err(ShamirError::InvalidFormat);
+        }
+        if checksum[0] != Self::checksum(&text[..pos]) {
+            return Err(ShamirError::WrongChecksum);
+        }
+        let parts: Vec<&str> = text[..pos].split(':').collect();
+        if parts.len() != 5 || parts[0] != SHARE_PREFIX {
+            return Err(ShamirError::InvalidFormat);
+        }
+        let id = try!(u32::from_str_radix(parts[1], 16).map_err(|_| ShamirError::InvalidFormat));
+        let threshold = try!(parts[2].parse().map_err(|_| ShamirError::InvalidFormat));
+        let index = try!(parts[3].parse().map_err(|_| ShamirError::InvalidFormat));
+        let data = try!(parse_hex(parts[4]).map_err(|_| ShamirError::InvalidFormat));
+        if index == 0 {
+            return Err(ShamirError::InvalidFormat);
+        }
+        Ok(SecretShare {
+            id,
+            threshold,
+            index,
+            data
+        })
+    }
+}
+
+impl fmt::Display for SecretShare {
+    /// Formats the share using only characters of the QR code alphanumeric mode
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let text = format!(
+            "{}:{:08X}:{}:{}:{}",
+            SHARE_PREFIX,
+            self.id,
+            self.threshold,
+            self.index,
+            to_hex(&self.data).to_uppercase()
+        );
+        write!(fmt, "{}:{:02X}", text, Self::checksum(&text))
+    }
+}
+
+
+/// Splits the secret into `count` shares so that any `threshold` of them restore it
+pub fn split_secret(
+    secret: &[u8],
+    id: u32,
+    threshold: u8,
+    count: u8,
+) -> Result<Vec<SecretShare>, ShamirError> {
+    if threshold == 0 || threshold > count {
+        return Err(ShamirError::InvalidThreshold(threshold, count));
+    }
+    let mut shares: Vec<SecretShare> = (1..count as u16 + 1)
+        .map(|index| {
+            SecretShare {
+                id,
+                threshold,
+                index: index as u8,
+                data: Vec::with_capacity(secret.len())
+            }
+        })
+        .collect();
+    for &byte in secret {
+        // Random polynomial of degree threshold-1 with the secret byte as constant term
+        let mut coefficients = randombytes(threshold as usize - 1);
+        coefficients.insert(0, byte);
+        for share in &mut shares {
+            let mut value = 0;
+            for &coefficient in coefficients.iter().rev() {
+                value = gf_mul(value, share.index) ^ coefficient;
+            }
+            share.data.push(value);
+        }
+    }
+    Ok(shares)
+}
+
+/// Restores the secret from at least `threshold` shares
+pub fn combine_shares(shares: &[SecretShare]) -> Result<Vec<u8>, ShamirError> {
+    let first = match shares.first() {
+        Some(share) => share,
+        None => return Err(ShamirError::NotEnoughShares(1, 0)),
+    };
+    for (i, share) in shares.iter().enumerate() {
+        if share.id != first.id || share.threshold != first.threshold ||
+            share.data.len() != first.data.len()
+        {
+            return Err(ShamirError::Mismatch);
+        }
+        if shares[..i].iter().any(|s| s.index == share.index) {
+            return Err(ShamirError::DuplicateShare(share.index));
+        }
+    }
+    if shares.len() < first.threshold as usize {
+        return Err(ShamirError::NotEnoughShares(first.threshold, shares.len()));
+    }
+    let shares = &shares[..first.threshold as usize];
+    // Lagrange interpolation at x = 0, the basis values do not depend on the byte
+    let basis: Vec<u8> = shares
+        .iter()
+        .map(|share| {
+            let mut num = 1;
+            let mut den = 1;
+            for other in shares {
+                if other.index != share.index {
+                    num = gf_mul(num, other.index);
+                    den = gf_mul(den, other.index ^ share.index);
+                }
+            }
+            gf_mul(num, gf_inv(den))
+        })
+        .collect();
+    Ok(
+        (0..first.data.len())
+            .map(|pos| {
+                shares.iter().zip(&basis).fold(0, |acc, (share, &factor)| {
+                    acc ^ gf_mul(share.data[pos], factor)
+                })
+            })
+            .collect()
+    )
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_gf_inverse() {
+        for a in 1..256 {
+            assert_eq!(gf_mul(a as u8, gf_inv(a as u8)), 1);
+        }
+    }
+
+    #[test]
+    fn test_split_combine() {
+        let secret = b"the secret key of the repository".to_vec();
+        let shares = split_secret(&secret, 42, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+        assert_eq!(combine_shares(&shares).unwrap(), secret);
+        assert_eq!(combine_shares(&shares[2..]).unwrap(), secret);
+        let picked = vec![shares[4].clone(), shares[0].clone(), shares[2].clone()];
+        assert_eq!(combine_shares(&picked).unwrap(), secret);
+        assert!(combine_shares(&shares[..2]).unwrap_err() == ShamirError::NotEnoughShares(3, 2));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(split_secret(b"secret", 1, 0, 3).is_err());
+        assert!(split_secret(b"secret", 1, 4, 3).is_err());
+        let shares = split_secret(b"secret", 1, 2, 3).unwrap();
+        let others = split_secret(b"secret", 2, 2, 3).unwrap();
+        let mixed = vec![shares[0].clone(), others[1].clone()];
+        assert_eq!(combine_shares(&mixed).unwrap_err(), ShamirError::Mismatch);
+        let duplicate = vec![shares[0].clone(), shares[0].clone()];
+        assert_eq!(combine_shares(&duplicate).unwrap_err(), ShamirError::DuplicateShare(1));
+    }
+
+    #[test]
+    fn test_format() {
+        let shares = split_secret(b"secret", 0xabcd, 2, 3).unwrap();
+        for share in &shares {
+            let text = share.to_string();
+            assert!(text.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == ':' || c == '-'));
+            assert_eq!(&SecretShare::parse(&text).unwrap(), share);
+            let spaced = format!("{}\n  {}", &text[..20], &text[20..]);
+            assert_eq!(&SecretShare::parse(&spaced).unwrap(), share);
+        }
+        let text = shares[0].to_string();
+        let pos = text.len() - 5;
+        let broken = format!("{}{}{}", &text[..pos], if &text[pos..pos + 1] == "0" { "1" } else { "0" }, &text[pos + 1..]);
+        assert_eq!(SecretShare::parse(&broken).unwrap_err(), ShamirError::WrongChecksum);
+        assert_eq!(SecretShare::parse("garbage").unwrap_err(), ShamirError::InvalidFormat);
+    }
+
+}